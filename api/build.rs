@@ -0,0 +1,24 @@
+use std::path::Path;
+use std::process::Command;
+
+/// Embed the commit the API is built from, given by `GIT_COMMIT` in the Docker builds.
+fn main() {
+    println!("cargo:rerun-if-env-changed=GIT_COMMIT");
+    if Path::new("../.git/logs/HEAD").exists() {
+        println!("cargo:rerun-if-changed=../.git/logs/HEAD");
+    }
+
+    let commit = std::env::var("GIT_COMMIT").ok().or_else(|| {
+        Command::new("git")
+            .args(["rev-parse", "--short", "HEAD"])
+            .output()
+            .ok()
+            .filter(|output| output.status.success())
+            .and_then(|output| String::from_utf8(output.stdout).ok())
+            .map(|commit| commit.trim().to_owned())
+    });
+    println!(
+        "cargo:rustc-env=GIT_COMMIT={}",
+        commit.unwrap_or_else(|| "unknown".to_owned())
+    );
+}
@@ -0,0 +1,3 @@
+-- The removed geometry properties were null or not used by their geometry type,
+-- so there is nothing to restore.
+SELECT 1;
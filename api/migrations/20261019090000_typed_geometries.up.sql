@@ -0,0 +1,25 @@
+-- Rewrite stored geometries to the shape produced by the typed geometry model:
+-- null properties are dropped, as well as properties not belonging to the geometry type.
+UPDATE projects
+SET project = jsonb_set(
+        project,
+        '{geometries}',
+        (
+            SELECT COALESCE(jsonb_agg(
+                CASE
+                    -- Hiding the volume of a borehole keeps its depth and diameter, so they are kept
+                    WHEN geometry->>'type' = 'point'
+                        THEN geometry - ARRAY['area', 'perimeter', 'sidesLength', 'numberOfSegments',
+                                              'showSlicingBox', 'volumeHeightLimits']
+                    WHEN geometry->>'type' = 'line'
+                        THEN geometry - ARRAY['pointSymbol', 'clampPoint', 'showSlicingBox',
+                                              'swissforagesId', 'depth', 'diameter']
+                    ELSE geometry - ARRAY['pointSymbol', 'clampPoint', 'swissforagesId', 'depth', 'diameter']
+                END
+                ORDER BY ordinality
+            ), '[]'::jsonb)
+            FROM jsonb_array_elements(project->'geometries') WITH ORDINALITY AS g(raw, ordinality),
+                 LATERAL jsonb_strip_nulls(raw) AS geometry
+        )
+)
+WHERE jsonb_typeof(project->'geometries') = 'array';
@@ -0,0 +1 @@
+DROP TABLE geometry_extents;
@@ -0,0 +1,9 @@
+-- LV95 bounding boxes of project geometries, derived on save to allow spatial queries.
+CREATE TABLE geometry_extents (
+    project_id uuid NOT NULL REFERENCES projects (id) ON DELETE CASCADE,
+    geometry_index integer NOT NULL,
+    extent box NOT NULL,
+    PRIMARY KEY (project_id, geometry_index)
+);
+
+CREATE INDEX geometry_extents_extent_idx ON geometry_extents USING gist (extent);
@@ -0,0 +1,2 @@
+DROP INDEX projects_tags_idx;
+DROP TABLE curated_tags;
@@ -0,0 +1,7 @@
+-- Tags suggested to every user, maintained by the administrators.
+CREATE TABLE curated_tags (
+    name text PRIMARY KEY,
+    description text
+);
+
+CREATE INDEX projects_tags_idx ON projects USING gin ((project->'tags'));
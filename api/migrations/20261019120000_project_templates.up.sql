@@ -0,0 +1,5 @@
+-- Projects marked by the administrators as templates for new projects.
+CREATE TABLE project_templates (
+    project_id uuid PRIMARY KEY REFERENCES projects (id) ON DELETE CASCADE,
+    created timestamptz NOT NULL DEFAULT now()
+);
@@ -0,0 +1,15 @@
+-- S3 operations recorded together with the project changes requiring them,
+-- executed by a background worker with retries.
+CREATE TABLE asset_operations (
+    id bigserial PRIMARY KEY,
+    kind text NOT NULL CHECK (kind IN ('save', 'copy', 'delete')),
+    key text NOT NULL,
+    source_key text,
+    status text NOT NULL DEFAULT 'pending' CHECK (status IN ('pending', 'failed')),
+    attempts integer NOT NULL DEFAULT 0,
+    last_error text,
+    run_after timestamptz NOT NULL DEFAULT now(),
+    created timestamptz NOT NULL DEFAULT now()
+);
+
+CREATE INDEX asset_operations_pending_idx ON asset_operations (run_after) WHERE status = 'pending';
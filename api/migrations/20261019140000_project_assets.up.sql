@@ -0,0 +1,14 @@
+-- Saved assets referenced by each project. Assets are content-addressed and may be
+-- shared by several projects, they are deleted once no project references them.
+CREATE TABLE project_assets (
+    project_id uuid NOT NULL REFERENCES projects (id) ON DELETE CASCADE,
+    key text NOT NULL,
+    PRIMARY KEY (project_id, key)
+);
+
+CREATE INDEX project_assets_key_idx ON project_assets (key);
+
+INSERT INTO project_assets (project_id, key)
+SELECT DISTINCT id, asset->>'key'
+FROM projects, jsonb_array_elements(COALESCE(project->'assets', '[]')) AS asset
+WHERE asset->>'key' IS NOT NULL;
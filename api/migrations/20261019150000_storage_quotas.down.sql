@@ -0,0 +1,2 @@
+DROP TABLE storage_quotas;
+DROP TABLE asset_sizes;
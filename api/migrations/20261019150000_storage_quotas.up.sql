@@ -0,0 +1,15 @@
+-- Size in bytes of the saved assets, recorded when they are saved.
+CREATE TABLE asset_sizes (
+    key text PRIMARY KEY,
+    size bigint NOT NULL
+);
+
+-- Storage quotas overriding the default quota of a user.
+CREATE TABLE storage_quotas (
+    email text PRIMARY KEY,
+    quota bigint NOT NULL CHECK (quota >= 0)
+);
+
+-- Saving an already saved asset only records its size
+INSERT INTO asset_operations (kind, key)
+SELECT DISTINCT 'save', key FROM project_assets;
@@ -0,0 +1,8 @@
+-- Virus scan results of uploaded assets. Assets without a scan were uploaded
+-- before scanning was introduced or without a configured scanner.
+CREATE TABLE asset_scans (
+    key text PRIMARY KEY,
+    status text NOT NULL CHECK (status IN ('clean', 'infected', 'not_scanned')),
+    signature text,
+    scanned timestamptz NOT NULL DEFAULT now()
+);
@@ -0,0 +1,5 @@
+-- Deletion of the renditions of a project image no longer used by any project
+ALTER TABLE asset_operations DROP CONSTRAINT asset_operations_kind_check;
+ALTER TABLE asset_operations
+    ADD CONSTRAINT asset_operations_kind_check
+    CHECK (kind IN ('save', 'copy', 'delete', 'delete_image'));
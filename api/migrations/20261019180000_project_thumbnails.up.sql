@@ -0,0 +1,5 @@
+-- Rendering of the cached thumbnail of a project after its geometries changed
+ALTER TABLE asset_operations DROP CONSTRAINT asset_operations_kind_check;
+ALTER TABLE asset_operations
+    ADD CONSTRAINT asset_operations_kind_check
+    CHECK (kind IN ('save', 'copy', 'delete', 'delete_image', 'thumbnail'));
@@ -0,0 +1,11 @@
+-- Short codes redirecting to viewer permalinks
+CREATE TABLE links (
+    code text PRIMARY KEY,
+    url text NOT NULL,
+    created timestamptz NOT NULL DEFAULT now(),
+    expires timestamptz,
+    clicks bigint NOT NULL DEFAULT 0,
+    last_clicked timestamptz
+);
+
+CREATE INDEX links_url_idx ON links (url) WHERE expires IS NULL;
@@ -0,0 +1,7 @@
+-- Former layer ids referenced by stored permalinks, with the id replacing them.
+-- A null layer marks a retired layer, removed from the permalinks.
+CREATE TABLE layer_aliases (
+    alias text PRIMARY KEY,
+    layer text CHECK (layer <> alias),
+    created timestamptz NOT NULL DEFAULT now()
+);
@@ -0,0 +1,6 @@
+DELETE FROM asset_operations WHERE kind = 'expire_upload';
+ALTER TABLE asset_operations DROP CONSTRAINT asset_operations_kind_check;
+ALTER TABLE asset_operations
+    ADD CONSTRAINT asset_operations_kind_check
+    CHECK (kind IN ('save', 'copy', 'delete', 'delete_image', 'thumbnail'));
+DROP TABLE pending_uploads;
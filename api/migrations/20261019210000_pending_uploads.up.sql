@@ -0,0 +1,16 @@
+-- Uploaded assets not referenced by a project yet, counted in the storage quota of their
+-- uploader until they are saved or expire.
+CREATE TABLE pending_uploads (
+    email text NOT NULL,
+    key text NOT NULL,
+    size bigint NOT NULL,
+    uploaded timestamptz NOT NULL DEFAULT now(),
+    PRIMARY KEY (email, key)
+);
+
+CREATE INDEX pending_uploads_key_idx ON pending_uploads (key);
+
+ALTER TABLE asset_operations DROP CONSTRAINT asset_operations_kind_check;
+ALTER TABLE asset_operations
+    ADD CONSTRAINT asset_operations_kind_check
+    CHECK (kind IN ('save', 'copy', 'delete', 'delete_image', 'thumbnail', 'expire_upload'));
@@ -0,0 +1,11 @@
+-- Projects saved before their geometry extents were recorded, indexed once at startup.
+CREATE TABLE geometry_extents_backfill (
+    project_id uuid PRIMARY KEY REFERENCES projects (id) ON DELETE CASCADE
+);
+
+INSERT INTO geometry_extents_backfill (project_id)
+SELECT id
+FROM projects
+WHERE
+    jsonb_array_length(COALESCE(project->'geometries', '[]')) > 0 AND
+    NOT EXISTS (SELECT 1 FROM geometry_extents WHERE project_id = projects.id);
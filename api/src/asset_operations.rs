@@ -0,0 +1,499 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Context;
+use aws_sdk_s3::Client;
+use sha2::{Digest, Sha256};
+use sqlx::{PgConnection, PgPool};
+use tokio::sync::Notify;
+use tokio_util::sync::CancellationToken;
+use uuid::Uuid;
+
+use crate::geometry::Geometry;
+use crate::images::{image_object_key, ImageSize};
+use crate::quotas::{record_asset_size, PENDING_UPLOAD_EXPIRY};
+use crate::thumbnails::{render_thumbnail, thumbnail_key};
+
+/// Interval at which pending operations are retried without notification
+const POLL_INTERVAL: Duration = Duration::from_secs(30);
+/// Number of operations claimed at once
+const BATCH_SIZE: i64 = 10;
+/// Number of attempts after which an operation is marked as failed
+const MAX_ATTEMPTS: i32 = 12;
+/// Upper bound of the delay between two attempts
+const MAX_BACKOFF: Duration = Duration::from_secs(60 * 60);
+/// Time after which an operation claimed by a worker is attempted again without result
+const CLAIM_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+/// Failure of an operation that cannot succeed when retried, marking it as failed right away.
+#[derive(thiserror::Error, Debug)]
+#[error("{0}")]
+struct Permanent(&'static str);
+
+/// An S3 operation recorded in the same transaction as the project change requiring it.
+///
+/// Operations are idempotent, so that a failed or interrupted attempt can be retried.
+/// Saved assets are content-addressed and shared by the projects referencing them,
+/// see [`set_asset_references`].
+#[derive(Clone, Debug, PartialEq)]
+pub enum AssetOperation {
+    /// Move an uploaded asset from `assets/temp/` to `assets/saved/` and record its size
+    Save { key: String },
+    /// Copy a saved asset to a new key, only recorded before assets were shared between projects
+    Copy { source: String, target: String },
+    /// Delete a saved asset, unless it is still referenced by a project
+    Delete { key: String },
+    /// Delete the renditions of a project image, unless a project still uses it
+    DeleteImage { key: String },
+    /// Render the cached thumbnail of a project, or delete it if the project was deleted
+    RenderThumbnail { project_id: Uuid },
+    /// Forget an expired upload and delete its temporary object, unless a project references it
+    ExpireUpload { key: String },
+}
+
+impl AssetOperation {
+    fn kind(&self) -> &'static str {
+        match self {
+            Self::Save { .. } => "save",
+            Self::Copy { .. } => "copy",
+            Self::Delete { .. } => "delete",
+            Self::DeleteImage { .. } => "delete_image",
+            Self::RenderThumbnail { .. } => "thumbnail",
+            Self::ExpireUpload { .. } => "expire_upload",
+        }
+    }
+
+    fn from_row(kind: &str, key: String, source_key: Option<String>) -> anyhow::Result<Self> {
+        Ok(match kind {
+            "save" => Self::Save { key },
+            "copy" => Self::Copy {
+                source: source_key.context("Copy operation without source key")?,
+                target: key,
+            },
+            "delete" => Self::Delete { key },
+            "delete_image" => Self::DeleteImage { key },
+            "thumbnail" => Self::RenderThumbnail {
+                project_id: key.parse().context("Invalid project id")?,
+            },
+            "expire_upload" => Self::ExpireUpload { key },
+            _ => anyhow::bail!("Unknown asset operation `{kind}`"),
+        })
+    }
+
+    /// Record the operation, to be executed once the transaction is committed.
+    pub async fn enqueue(&self, conn: &mut PgConnection) -> sqlx::Result<()> {
+        self.enqueue_after(conn, Duration::ZERO).await
+    }
+
+    /// Record the operation, to be executed once `delay` has elapsed after the transaction.
+    pub async fn enqueue_after(
+        &self,
+        conn: &mut PgConnection,
+        delay: Duration,
+    ) -> sqlx::Result<()> {
+        let (key, source_key) = match self {
+            Self::Save { key }
+            | Self::Delete { key }
+            | Self::DeleteImage { key }
+            | Self::ExpireUpload { key } => (key.clone(), None),
+            Self::Copy { source, target } => (target.clone(), Some(source)),
+            Self::RenderThumbnail { project_id } => (project_id.to_string(), None),
+        };
+        sqlx::query!(
+            r#"
+            INSERT INTO asset_operations (kind, key, source_key, run_after)
+            VALUES ($1, $2, $3, now() + make_interval(secs => $4))
+            "#,
+            self.kind(),
+            key,
+            source_key,
+            delay.as_secs_f64()
+        )
+        .execute(conn)
+        .await?;
+        Ok(())
+    }
+}
+
+/// Take a transaction-level lock on an asset key, serializing reference changes and deletion.
+pub async fn lock_key(conn: &mut PgConnection, key: &str) -> sqlx::Result<()> {
+    sqlx::query!("SELECT pg_advisory_xact_lock(hashtext($1))", key)
+        .execute(conn)
+        .await?;
+    Ok(())
+}
+
+/// Replace the assets referenced by a project, returning the keys no longer referenced by it.
+pub async fn set_asset_references(
+    conn: &mut PgConnection,
+    project_id: Uuid,
+    keys: &[String],
+) -> sqlx::Result<Vec<String>> {
+    let removed = sqlx::query_scalar!(
+        "DELETE FROM project_assets WHERE project_id = $1 AND NOT key = ANY($2) RETURNING key",
+        project_id,
+        keys
+    )
+    .fetch_all(&mut *conn)
+    .await?;
+
+    for key in keys {
+        lock_key(conn, key).await?;
+        sqlx::query!(
+            "INSERT INTO project_assets (project_id, key) VALUES ($1, $2) ON CONFLICT DO NOTHING",
+            project_id,
+            key
+        )
+        .execute(&mut *conn)
+        .await?;
+    }
+    // Referenced uploads are counted with the assets of the project owner
+    sqlx::query!("DELETE FROM pending_uploads WHERE key = ANY($1)", keys)
+        .execute(&mut *conn)
+        .await?;
+
+    Ok(removed)
+}
+
+/// Content-addressed key of an uploaded asset.
+pub fn asset_key(content: &[u8]) -> String {
+    format!("{:x}.kml", Sha256::digest(content))
+}
+
+/// Delay before the next attempt of an operation that failed `attempts` times.
+fn backoff(attempts: i32) -> Duration {
+    Duration::from_secs(1 << attempts.clamp(0, 12)).min(MAX_BACKOFF)
+}
+
+/// Background worker executing the recorded asset operations.
+#[derive(Clone)]
+pub struct AssetWorker {
+    pool: PgPool,
+    client: Client,
+    bucket: String,
+    notify: Arc<Notify>,
+}
+
+impl AssetWorker {
+    pub fn new(pool: PgPool, client: Client, bucket: String) -> Self {
+        Self {
+            pool,
+            client,
+            bucket,
+            notify: Arc::new(Notify::new()),
+        }
+    }
+
+    /// Wake the worker up after operations have been committed.
+    pub fn notify(&self) {
+        self.notify.notify_one();
+    }
+
+    /// Process operations until the token is cancelled, finishing the current batch.
+    pub async fn run(self, token: CancellationToken) {
+        while !token.is_cancelled() {
+            match self.process_due().await {
+                Ok(0) => {}
+                Ok(_) => continue,
+                Err(e) => tracing::error!("Failed to process asset operations: {:?}", e),
+            }
+            tokio::select! {
+                _ = self.notify.notified() => {}
+                _ = tokio::time::sleep(POLL_INTERVAL) => {}
+                _ = token.cancelled() => {}
+            }
+        }
+        tracing::info!("Stopped the asset worker");
+    }
+
+    /// Process a batch of due operations, returning the number of operations attempted.
+    ///
+    /// The operations are claimed by postponing them, so that no transaction nor row lock is
+    /// held during the S3 calls. Operations whose result was not recorded, e.g. as the worker
+    /// was stopped, are attempted again once their claim expires.
+    async fn process_due(&self) -> anyhow::Result<usize> {
+        let mut rows = sqlx::query!(
+            r#"
+            UPDATE asset_operations
+            SET run_after = now() + make_interval(secs => $2)
+            WHERE id IN (
+                SELECT id
+                FROM asset_operations
+                WHERE status = 'pending' AND run_after <= now()
+                ORDER BY id
+                LIMIT $1
+                FOR UPDATE SKIP LOCKED
+            )
+            RETURNING id, kind, key, source_key, attempts
+            "#,
+            BATCH_SIZE,
+            CLAIM_TIMEOUT.as_secs_f64()
+        )
+        .fetch_all(&self.pool)
+        .await?;
+        rows.sort_by_key(|row| row.id);
+
+        for row in &rows {
+            let result = match AssetOperation::from_row(
+                &row.kind,
+                row.key.clone(),
+                row.source_key.clone(),
+            ) {
+                Ok(operation) => self.execute(&operation).await,
+                Err(e) => Err(e),
+            };
+
+            match result {
+                Ok(()) => {
+                    sqlx::query!("DELETE FROM asset_operations WHERE id = $1", row.id)
+                        .execute(&self.pool)
+                        .await?;
+                }
+                Err(e) => {
+                    let attempts = row.attempts + 1;
+                    tracing::warn!(
+                        "Asset operation {} ({} {}) failed, attempt {}: {:?}",
+                        row.id,
+                        row.kind,
+                        row.key,
+                        attempts,
+                        e
+                    );
+                    let status = if attempts >= MAX_ATTEMPTS || e.is::<Permanent>() {
+                        "failed"
+                    } else {
+                        "pending"
+                    };
+                    sqlx::query!(
+                        r#"
+                        UPDATE asset_operations
+                        SET
+                            attempts = $2,
+                            last_error = $3,
+                            run_after = now() + make_interval(secs => $4),
+                            status = $5
+                        WHERE id = $1
+                        "#,
+                        row.id,
+                        attempts,
+                        format!("{e:#}"),
+                        backoff(attempts).as_secs_f64(),
+                        status
+                    )
+                    .execute(&self.pool)
+                    .await?;
+                }
+            }
+        }
+
+        Ok(rows.len())
+    }
+
+    async fn execute(&self, operation: &AssetOperation) -> anyhow::Result<()> {
+        match operation {
+            AssetOperation::Save { key } => {
+                let temp_key = format!("assets/temp/{}", key);
+                let saved_key = format!("assets/saved/{}", key);
+                let size = match self.size(&saved_key).await? {
+                    Some(size) => size,
+                    None => {
+                        if self.size(&temp_key).await?.is_none() {
+                            return Err(Permanent("Uploaded object not found").into());
+                        }
+                        self.copy(&temp_key, &saved_key).await?;
+                        self.size(&saved_key)
+                            .await?
+                            .context("Saved object not found")?
+                    }
+                };
+                record_asset_size(&mut *self.pool.acquire().await?, key, size).await?;
+                self.delete(&temp_key).await
+            }
+            AssetOperation::Copy { source, target } => {
+                let source_key = format!("assets/saved/{}", source);
+                let target_key = format!("assets/saved/{}", target);
+                if self.size(&target_key).await?.is_some() {
+                    return Ok(());
+                }
+                if self.size(&source_key).await?.is_none() {
+                    return Err(Permanent("Source object not found").into());
+                }
+                self.copy(&source_key, &target_key).await
+            }
+            AssetOperation::Delete { key } => {
+                // The lock of the key is held until the deletion, so that no reference can be
+                // added between the check and the deletion
+                let mut tx = self.pool.begin().await?;
+                lock_key(&mut tx, key).await?;
+                let referenced = sqlx::query_scalar!(
+                    r#"SELECT EXISTS (SELECT 1 FROM project_assets WHERE key = $1) AS "exists!""#,
+                    key
+                )
+                .fetch_one(&mut *tx)
+                .await?;
+                if !referenced {
+                    self.delete(&format!("assets/saved/{}", key)).await?;
+                }
+                tx.commit().await?;
+                Ok(())
+            }
+            AssetOperation::DeleteImage { key } => {
+                let mut tx = self.pool.begin().await?;
+                lock_key(&mut tx, key).await?;
+                let used = sqlx::query_scalar!(
+                    r#"SELECT EXISTS (SELECT 1 FROM projects WHERE project->>'image' = $1) AS "exists!""#,
+                    key
+                )
+                .fetch_one(&mut *tx)
+                .await?;
+                if !used {
+                    for size in ImageSize::ALL {
+                        self.delete(&image_object_key(key, size)).await?;
+                    }
+                }
+                tx.commit().await?;
+                Ok(())
+            }
+            AssetOperation::ExpireUpload { key } => {
+                let mut tx = self.pool.begin().await?;
+                lock_key(&mut tx, key).await?;
+                sqlx::query!(
+                    r#"
+                    DELETE FROM pending_uploads
+                    WHERE key = $1 AND uploaded <= now() - make_interval(secs => $2)
+                    "#,
+                    key,
+                    PENDING_UPLOAD_EXPIRY.as_secs_f64()
+                )
+                .execute(&mut *tx)
+                .await?;
+                // A later upload of the same asset expires on its own, and referenced assets
+                // are moved by their save operation
+                let kept = sqlx::query_scalar!(
+                    r#"
+                    SELECT
+                        EXISTS (SELECT 1 FROM pending_uploads WHERE key = $1)
+                        OR EXISTS (SELECT 1 FROM project_assets WHERE key = $1) AS "exists!"
+                    "#,
+                    key
+                )
+                .fetch_one(&mut *tx)
+                .await?;
+                if !kept {
+                    self.delete(&format!("assets/temp/{}", key)).await?;
+                }
+                tx.commit().await?;
+                Ok(())
+            }
+            AssetOperation::RenderThumbnail { project_id } => {
+                let geometries = sqlx::query_scalar!(
+                    r#"
+                    SELECT COALESCE(project->'geometries', '[]') AS "geometries!: sqlx::types::Json<Vec<Geometry>>"
+                    FROM projects WHERE id = $1
+                    "#,
+                    project_id
+                )
+                .fetch_optional(&self.pool)
+                .await?;
+                let key = thumbnail_key(*project_id);
+                match geometries {
+                    Some(geometries) => {
+                        self.put(&key, "image/svg+xml", render_thumbnail(&geometries).into())
+                            .await
+                    }
+                    None => self.delete(&key).await,
+                }
+            }
+        }
+    }
+
+    /// Size of an object, `None` if it does not exist.
+    async fn size(&self, key: &str) -> anyhow::Result<Option<i64>> {
+        match self
+            .client
+            .head_object()
+            .bucket(&self.bucket)
+            .key(key)
+            .send()
+            .await
+        {
+            Ok(object) => Ok(Some(object.content_length().unwrap_or_default())),
+            Err(e) if e.as_service_error().is_some_and(|e| e.is_not_found()) => Ok(None),
+            Err(e) => Err(e).context("Failed to check object"),
+        }
+    }
+
+    async fn copy(&self, source_key: &str, target_key: &str) -> anyhow::Result<()> {
+        self.client
+            .copy_object()
+            .bucket(&self.bucket)
+            .copy_source(format!("{}/{}", &self.bucket, source_key))
+            .key(target_key)
+            .send()
+            .await
+            .context("Failed to copy object")?;
+        Ok(())
+    }
+
+    async fn put(&self, key: &str, content_type: &str, content: Vec<u8>) -> anyhow::Result<()> {
+        self.client
+            .put_object()
+            .bucket(&self.bucket)
+            .key(key)
+            .content_type(content_type)
+            .body(content.into())
+            .send()
+            .await
+            .context("Failed to put object")?;
+        Ok(())
+    }
+
+    /// Deleting a missing object succeeds, which keeps deletions idempotent.
+    async fn delete(&self, key: &str) -> anyhow::Result<()> {
+        self.client
+            .delete_object()
+            .bucket(&self.bucket)
+            .key(key)
+            .send()
+            .await
+            .context("Failed to delete object")?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff_grows_until_capped() {
+        assert_eq!(backoff(1), Duration::from_secs(2));
+        assert_eq!(backoff(5), Duration::from_secs(32));
+        assert_eq!(backoff(MAX_ATTEMPTS), MAX_BACKOFF);
+        assert_eq!(backoff(100), MAX_BACKOFF);
+    }
+
+    #[test]
+    fn operations_round_trip_through_rows() {
+        let copy = AssetOperation::Copy {
+            source: "a.kml".into(),
+            target: "b.kml".into(),
+        };
+        assert_eq!(
+            AssetOperation::from_row(copy.kind(), "b.kml".into(), Some("a.kml".into())).unwrap(),
+            copy
+        );
+        assert!(AssetOperation::from_row("copy", "b.kml".into(), None).is_err());
+        assert!(AssetOperation::from_row("thumbnail", "b.kml".into(), None).is_err());
+        assert!(AssetOperation::from_row("move", "b.kml".into(), None).is_err());
+    }
+
+    #[test]
+    fn asset_key_is_content_addressed() {
+        assert_ne!(asset_key(b"<kml/>"), asset_key(b"<kml></kml>"));
+        assert_eq!(
+            asset_key(b""),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855.kml"
+        );
+    }
+}
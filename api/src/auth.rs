@@ -1,18 +1,28 @@
 use anyhow::Context;
 use axum::extract::FromRequestParts;
+use axum::http::header::AUTHORIZATION;
 use axum::{async_trait, http::request::Parts};
 use axum_extra::headers::authorization::Bearer;
 use axum_extra::headers::Authorization;
 use axum_extra::TypedHeader;
+use chrono::{DateTime, Utc};
+use jsonwebtoken::errors::ErrorKind;
 use jsonwebtoken::jwk::{AlgorithmParameters, JwkSet, KeyAlgorithm};
 use jsonwebtoken::{Algorithm, DecodingKey, Validation};
 use once_cell::sync::OnceCell;
 use serde::{Deserialize, Serialize};
+use std::sync::RwLock;
+use std::time::Duration;
+use tokio_util::sync::CancellationToken;
+use utoipa::ToSchema;
 
 use crate::Error;
 
-/// JSON Web Key Set (JWKS)
-static JWKS: OnceCell<JwkSet> = OnceCell::new();
+/// JSON Web Key Set (JWKS), refreshed periodically
+static JWKS: RwLock<Option<KeySet>> = RwLock::new(None);
+
+/// Age after which the JSON Web Key Set is stale
+static JWKS_MAX_AGE: OnceCell<Duration> = OnceCell::new();
 
 /// Audience
 static AUD: OnceCell<String> = OnceCell::new();
@@ -20,8 +30,11 @@ static AUD: OnceCell<String> = OnceCell::new();
 /// Issuer
 static ISS: OnceCell<String> = OnceCell::new();
 
+/// Cognito group of the administrators
+static ADMIN_GROUP: OnceCell<String> = OnceCell::new();
+
 /// Configuration for AWS Cognito JWKS
-#[derive(clap::Parser, Serialize)]
+#[derive(clap::Parser, Clone, Debug, Serialize, ToSchema)]
 pub struct Auth {
     /// The cognito client id
     #[clap(long, env)]
@@ -35,17 +48,32 @@ pub struct Auth {
     /// The AWS region
     #[clap(long, env, default_value = "eu-west-1")]
     pub cognito_aws_region: String,
+    /// The cognito group granting administration rights
+    #[clap(long, env, default_value = "admin")]
+    #[serde(skip)]
+    pub cognito_admin_group: String,
+    /// Interval between the refreshes of the JSON Web Key Set, in seconds
+    #[clap(long, env, default_value_t = 3600)]
+    #[serde(skip)]
+    pub jwks_refresh_interval: u64,
+    /// Age after which the JSON Web Key Set is reported stale by the readiness probe, in seconds
+    #[clap(long, env, default_value_t = 86400)]
+    #[serde(skip)]
+    pub jwks_max_age: u64,
+}
+
+/// JSON Web Key Set and the time it was fetched
+struct KeySet {
+    keys: JwkSet,
+    fetched: DateTime<Utc>,
 }
 
 impl Auth {
     pub async fn initialize(&self) -> anyhow::Result<()> {
         // Fetch & set JSON Web Key Set
-        let url = format!(
-            "https://cognito-idp.{}.amazonaws.com/{}/.well-known/jwks.json",
-            self.cognito_aws_region, self.cognito_pool_id
-        );
-        let keyset = reqwest::get(url).await?.json().await?;
-        JWKS.get_or_init(|| keyset);
+        self.fetch_key_set().await?;
+        let max_age = Duration::from_secs(self.jwks_max_age);
+        JWKS_MAX_AGE.get_or_init(|| max_age);
 
         // Set auience
         let audience = self.cognito_client_id.clone();
@@ -58,16 +86,91 @@ impl Auth {
         );
         ISS.get_or_init(|| issuer);
 
+        // Set admin group
+        let admin_group = self.cognito_admin_group.clone();
+        ADMIN_GROUP.get_or_init(|| admin_group);
+
+        Ok(())
+    }
+
+    /// Refresh the JSON Web Key Set periodically until the token is cancelled, keeping the
+    /// previous one on failures.
+    pub async fn refresh_key_set(self, token: CancellationToken) {
+        let mut interval =
+            tokio::time::interval(Duration::from_secs(self.jwks_refresh_interval.max(1)));
+        // The first tick completes immediately, after the initial fetch
+        interval.tick().await;
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {}
+                _ = token.cancelled() => return,
+            }
+            if let Err(e) = self.fetch_key_set().await {
+                tracing::warn!("Failed to refresh the JSON Web Key Set: {:?}", e);
+            }
+        }
+    }
+
+    async fn fetch_key_set(&self) -> anyhow::Result<()> {
+        let url = format!(
+            "https://cognito-idp.{}.amazonaws.com/{}/.well-known/jwks.json",
+            self.cognito_aws_region, self.cognito_pool_id
+        );
+        let keys = reqwest::get(url).await?.error_for_status()?.json().await?;
+        *JWKS.write().expect("JWKS lock poisoned") = Some(KeySet {
+            keys,
+            fetched: Utc::now(),
+        });
         Ok(())
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+/// Number of keys of the JSON Web Key Set and the time they were fetched, failing when they
+/// are missing or stale.
+pub fn check_key_set() -> Result<(usize, DateTime<Utc>), &'static str> {
+    let jwks = JWKS.read().expect("JWKS lock poisoned");
+    let key_set = jwks.as_ref().ok_or("JSON Web Key Set not loaded")?;
+    let max_age = JWKS_MAX_AGE.get().copied().unwrap_or(Duration::MAX);
+    let age = (Utc::now() - key_set.fetched).to_std().unwrap_or_default();
+    if age > max_age {
+        return Err("JSON Web Key Set is stale");
+    }
+    Ok((key_set.keys.keys.len(), key_set.fetched))
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Claims {
     aud: String,
     exp: usize,
     iss: String,
     pub email: String,
+    #[serde(rename = "cognito:groups", default)]
+    pub groups: Vec<String>,
+}
+
+impl Claims {
+    /// Claims of the bearer token of a request, if valid.
+    ///
+    /// Rejected tokens are not counted, as the handler rejects them if it requires a user.
+    pub async fn authenticate(parts: &mut Parts) -> Option<Self> {
+        if let Some(claims) = parts.extensions.get::<Self>() {
+            return Some(claims.clone());
+        }
+        if !parts.headers.contains_key(AUTHORIZATION) {
+            return None;
+        }
+        let claims = Self::decode(parts).await.ok()?;
+        crate::telemetry::record_user(&claims.email);
+        parts.extensions.insert(claims.clone());
+        Some(claims)
+    }
+
+    /// Whether the user belongs to the administrators group.
+    pub fn is_admin(&self) -> bool {
+        ADMIN_GROUP
+            .get()
+            .is_some_and(|group| self.groups.contains(group))
+    }
 }
 
 #[async_trait]
@@ -77,35 +180,71 @@ where
 {
     type Rejection = Error;
 
-    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+    async fn from_request_parts(parts: &mut Parts, _: &S) -> Result<Self, Self::Rejection> {
+        // Validated before, e.g. by the rate limiter
+        if let Some(claims) = parts.extensions.get::<Self>() {
+            return Ok(claims.clone());
+        }
+        let claims = Self::decode(parts)
+            .await
+            .map_err(|Rejection(reason, error)| {
+                if let Some(reason) = reason {
+                    crate::metrics::jwt_validation_failure(reason);
+                }
+                error
+            })?;
+        crate::telemetry::record_user(&claims.email);
+        parts.extensions.insert(claims.clone());
+        Ok(claims)
+    }
+}
+
+impl Claims {
+    /// Validate the bearer token of a request.
+    async fn decode(parts: &mut Parts) -> Result<Self, Rejection> {
         // Extract the token from the authorization header
         let TypedHeader(Authorization(bearer)) =
-            TypedHeader::<Authorization<Bearer>>::from_request_parts(parts, state)
+            TypedHeader::<Authorization<Bearer>>::from_request_parts(parts, &())
                 .await
-                .map_err(|_| Error::Unauthorized)?;
+                .map_err(|_| reject("missing_token", Error::Unauthorized))?;
         let token = bearer.token();
 
         // Decode the user data
-        let header = jsonwebtoken::decode_header(token)
-            .map_err(|_| Error::Jwt("Failed to decode token header"))?;
-        let kid = header
-            .kid
-            .ok_or(Error::Jwt("Token is missing `kid` parameter"))?;
+        let header = jsonwebtoken::decode_header(token).map_err(|_| {
+            reject(
+                "invalid_header",
+                Error::Jwt("Failed to decode token header"),
+            )
+        })?;
+        let kid = header.kid.ok_or_else(|| {
+            reject(
+                "missing_kid",
+                Error::Jwt("Token is missing `kid` parameter"),
+            )
+        })?;
         let jwk = JWKS
-            .get()
-            .context("Once cell `JWKS` not initialized")?
+            .read()
+            .expect("JWKS lock poisoned")
+            .as_ref()
+            .context("JSON Web Key Set not initialized")?
+            .keys
             .find(&kid)
-            .ok_or(Error::Jwt("No matching key found in keyset"))?;
+            .cloned()
+            .ok_or_else(|| reject("unknown_key", Error::Jwt("No matching key found in keyset")))?;
 
         match jwk.algorithm {
             AlgorithmParameters::RSA(ref rsa) => {
-                let decoding_key = DecodingKey::from_rsa_components(&rsa.n, &rsa.e)
-                    .map_err(|_| Error::Jwt("Failed to create decoding key"))?;
+                let decoding_key =
+                    DecodingKey::from_rsa_components(&rsa.n, &rsa.e).map_err(|_| {
+                        reject("invalid_key", Error::Jwt("Failed to create decoding key"))
+                    })?;
 
-                let algorithm = jwk
-                    .common
-                    .key_algorithm
-                    .ok_or(Error::Jwt("JWK is missing `algorithm` parameter"))?;
+                let algorithm = jwk.common.key_algorithm.ok_or_else(|| {
+                    reject(
+                        "missing_algorithm",
+                        Error::Jwt("JWK is missing `algorithm` parameter"),
+                    )
+                })?;
 
                 let mut validation = Validation::new(key_algorithm_to_algorithm(algorithm));
                 validation.set_audience(&[AUD.get().context("Once cell `AUD` not initialized")?]);
@@ -113,19 +252,38 @@ where
 
                 let decoded_token =
                     jsonwebtoken::decode::<Claims>(token, &decoding_key, &validation).map_err(
-                        |_e| {
-                            // TODO: Better error handling
-                            // tracing::error!("{}", e);
-                            Error::Jwt("Failed to decode token")
+                        |e| {
+                            let reason = match e.kind() {
+                                ErrorKind::ExpiredSignature => "expired",
+                                ErrorKind::ImmatureSignature => "immature",
+                                ErrorKind::InvalidAudience => "invalid_audience",
+                                ErrorKind::InvalidIssuer => "invalid_issuer",
+                                ErrorKind::InvalidSignature => "invalid_signature",
+                                _ => "invalid_token",
+                            };
+                            reject(reason, Error::Jwt("Failed to decode token"))
                         },
                     )?;
                 Ok(decoded_token.claims)
             }
-            _ => Err(Error::Jwt("Unreachable!")),
+            _ => Err(reject("unsupported_key", Error::Jwt("Unreachable!"))),
         }
     }
 }
 
+/// Token rejected, for a reason counted by the metrics unless it is an internal error.
+struct Rejection(Option<&'static str>, Error);
+
+impl From<anyhow::Error> for Rejection {
+    fn from(error: anyhow::Error) -> Self {
+        Self(None, error.into())
+    }
+}
+
+fn reject(reason: &'static str, error: Error) -> Rejection {
+    Rejection(Some(reason), error)
+}
+
 fn key_algorithm_to_algorithm(key_algorithm: KeyAlgorithm) -> Algorithm {
     match key_algorithm {
         KeyAlgorithm::RS256 => Algorithm::RS256,
@@ -0,0 +1,33 @@
+//! Report the project views referencing renamed, retired or unknown layers.
+//!
+//! Run with `--apply` to rewrite the permalinks using the layer aliases.
+
+use clap::Parser;
+
+#[derive(Parser)]
+struct Args {
+    /// Rewrite the permalinks instead of only reporting them
+    #[clap(long)]
+    apply: bool,
+    /// Comma separated layer ids of the current layer tree
+    #[clap(long, value_delimiter = ',')]
+    known_layers: Option<Vec<String>>,
+    #[clap(flatten)]
+    config: api::Config,
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    dotenv::dotenv().ok();
+    let args = api::load_config(std::env::args_os(), |args: &Args| &args.config);
+
+    let pool = args.config.database.setup().await;
+    let check = api::PermalinkCheck {
+        apply: args.apply,
+        known_layers: args.known_layers.map(|layers| layers.into_iter().collect()),
+    };
+    let report = api::check_permalinks(&pool, &check).await?;
+
+    println!("{}", serde_json::to_string_pretty(&report)?);
+    Ok(())
+}
@@ -1,8 +1,32 @@
-use crate::{auth::Auth, database::Database};
+use clap::error::ErrorKind;
+use clap::parser::ValueSource;
+use clap::{ArgMatches, Command, Parser};
 use serde::Serialize;
+use std::collections::HashSet;
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+use tracing_subscriber::EnvFilter;
+use utoipa::ToSchema;
+
+use crate::cors::Cors;
+use crate::deprecation::Deprecation;
+use crate::links::Links;
+use crate::quotas::Quotas;
+use crate::rate_limit::RateLimits;
+use crate::s3::S3;
+use crate::scanning::Scanning;
+use crate::telemetry::{Telemetry, REDACTED};
+use crate::{auth::Auth, database::Database};
 
-#[derive(clap::Parser)]
+/// Configuration of the API, loaded once at startup by [`load_config`].
+#[derive(clap::Parser, Clone, Debug)]
 pub struct Config {
+    /// TOML file of settings, overridden by the environment and the command line
+    #[clap(long, env)]
+    pub config_file: Option<PathBuf>,
+    /// Print the effective configuration, with the secrets redacted, and exit
+    #[clap(long)]
+    pub print_config: bool,
     #[clap(flatten)]
     pub database: Database,
     #[clap(long, env)]
@@ -11,14 +35,394 @@ pub struct Config {
     pub auth: Auth,
     #[clap(long, env)]
     pub env: String,
+    /// The Cesium ion access token of the viewer
+    #[clap(long, env)]
+    pub ion_default_access_token: String,
+    /// Time given to the in-flight requests and the background tasks to finish after a
+    /// termination signal, in seconds
+    #[clap(long, env, default_value_t = 25)]
+    pub shutdown_timeout: u64,
+    #[clap(flatten)]
+    pub s3: S3,
+    #[clap(flatten)]
+    pub quotas: Quotas,
+    #[clap(flatten)]
+    pub scanning: Scanning,
+    #[clap(flatten)]
+    pub links: Links,
+    #[clap(flatten)]
+    pub cors: Cors,
+    #[clap(flatten)]
+    pub rate_limits: RateLimits,
+    #[clap(flatten)]
+    pub deprecation: Deprecation,
+    #[clap(flatten)]
+    pub telemetry: Telemetry,
 }
 
-#[derive(clap::Parser, Serialize)]
+/// Configuration of the viewer.
+#[derive(Clone, Serialize, ToSchema)]
 pub struct ClientConfig {
-    #[clap(long, env)]
     pub env: String,
-    #[clap(long, env)]
     pub ion_default_access_token: String,
-    #[clap(flatten)]
     pub auth: Auth,
 }
+
+impl Config {
+    /// Load the configuration from the arguments of the process.
+    pub fn load() -> Self {
+        Self::load_from(std::env::args_os())
+    }
+
+    /// Load the configuration from the given arguments.
+    pub fn load_from<I, T>(args: I) -> Self
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<OsString>,
+    {
+        load_config(args, |config: &Self| config)
+    }
+
+    pub fn client_config(&self) -> ClientConfig {
+        ClientConfig {
+            env: self.env.clone(),
+            ion_default_access_token: self.ion_default_access_token.clone(),
+            auth: self.auth.clone(),
+        }
+    }
+
+    /// Check the settings which clap accepts but the API cannot run with.
+    pub fn validate(&self) -> Result<(), Vec<String>> {
+        let checks = [
+            (self.app_port != 0, "APP_PORT must not be 0"),
+            (
+                self.database.pg_max_connections > 0,
+                "PG_MAX_CONNECTIONS must be at least 1",
+            ),
+            (
+                !self.s3.projects_s3_bucket.is_empty(),
+                "PROJECTS_S3_BUCKET must not be empty",
+            ),
+            (
+                self.s3.s3_endpoint.is_none()
+                    || !(self.s3.aws_access_key_id.is_empty()
+                        || self.s3.aws_secret_access_key.is_empty()),
+                "AWS_ACCESS_KEY_ID and AWS_SECRET_ACCESS_KEY are required with S3_ENDPOINT",
+            ),
+            (
+                !(self.auth.cognito_client_id.is_empty() || self.auth.cognito_pool_id.is_empty()),
+                "COGNITO_CLIENT_ID and COGNITO_POOL_ID must not be empty",
+            ),
+            (
+                self.auth.jwks_refresh_interval > 0,
+                "JWKS_REFRESH_INTERVAL must be at least 1 second",
+            ),
+            (
+                self.auth.jwks_max_age >= self.auth.jwks_refresh_interval,
+                "JWKS_MAX_AGE must not be shorter than JWKS_REFRESH_INTERVAL",
+            ),
+            (
+                self.quotas.default_storage_quota >= 0,
+                "DEFAULT_STORAGE_QUOTA must not be negative",
+            ),
+            (
+                EnvFilter::try_new(&self.telemetry.otel_traces_filter).is_ok(),
+                "OTEL_TRACES_FILTER is not a valid filter",
+            ),
+        ];
+
+        let errors: Vec<String> = checks
+            .into_iter()
+            .filter(|(valid, _)| !valid)
+            .map(|(_, message)| message.to_owned())
+            .collect();
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+}
+
+/// Parse the command line `P` containing the [`Config`], exiting with the list of the missing
+/// or invalid settings.
+///
+/// The settings of the TOML file are set in the environment unless already there, as `.env` is,
+/// so the command line takes precedence over the environment, and the environment over the file.
+/// With `--print-config`, the effective settings are printed and the process exits.
+pub fn load_config<P, I, T>(args: I, config: impl Fn(&P) -> &Config) -> P
+where
+    P: Parser,
+    I: IntoIterator<Item = T>,
+    T: Into<OsString>,
+{
+    let args: Vec<OsString> = args.into_iter().map(Into::into).collect();
+    let mut command = P::command();
+
+    let from_file = match config_file(&args) {
+        Some(path) => {
+            let applied = apply_file(&command, &path)
+                .unwrap_or_else(|e| command.error(ErrorKind::Io, e).exit());
+            // The arguments capture the environment when built
+            command = P::command();
+            applied
+        }
+        None => HashSet::new(),
+    };
+
+    let matches = command
+        .try_get_matches_from_mut(&args)
+        .unwrap_or_else(|e| e.exit());
+    let parsed = P::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+
+    if config(&parsed).print_config {
+        print!("{}", effective_config(&command, &matches, &from_file));
+        std::process::exit(0);
+    }
+    if let Err(errors) = config(&parsed).validate() {
+        let errors: Vec<String> = errors.iter().map(|e| format!("  - {e}")).collect();
+        command
+            .error(
+                ErrorKind::ValueValidation,
+                format!("invalid configuration:\n{}", errors.join("\n")),
+            )
+            .exit();
+    }
+    parsed
+}
+
+/// Path of the TOML file, given with `--config-file` or in `CONFIG_FILE`.
+fn config_file(args: &[OsString]) -> Option<PathBuf> {
+    let mut args = args.iter().skip(1).map(|arg| arg.to_string_lossy());
+    while let Some(arg) = args.next() {
+        if arg == "--config-file" {
+            return args.next().map(|path| PathBuf::from(path.as_ref()));
+        }
+        if let Some(path) = arg.strip_prefix("--config-file=") {
+            return Some(PathBuf::from(path));
+        }
+    }
+    std::env::var_os("CONFIG_FILE").map(PathBuf::from)
+}
+
+/// Set the variables of a TOML file in the environment, unless already set.
+///
+/// Returns the names of the variables which were set.
+fn apply_file(command: &Command, path: &Path) -> Result<HashSet<String>, String> {
+    let content = std::fs::read_to_string(path)
+        .map_err(|e| format!("failed to read {}: {e}", path.display()))?;
+    let table: toml::Table = content
+        .parse()
+        .map_err(|e| format!("failed to parse {}: {e}", path.display()))?;
+    let settings = file_settings(table);
+
+    let known: HashSet<String> = command
+        .get_arguments()
+        .filter_map(|arg| arg.get_env())
+        .map(|env| env.to_string_lossy().into_owned())
+        .collect();
+    let unknown: Vec<String> = settings
+        .iter()
+        .filter(|(name, _)| !known.contains(name))
+        .map(|(name, _)| name.to_lowercase())
+        .collect();
+    if !unknown.is_empty() {
+        return Err(format!(
+            "unknown settings in {}: {}",
+            path.display(),
+            unknown.join(", ")
+        ));
+    }
+
+    let mut applied = HashSet::new();
+    for (name, value) in settings {
+        if std::env::var_os(&name).is_none() {
+            std::env::set_var(&name, value);
+            applied.insert(name);
+        }
+    }
+    Ok(applied)
+}
+
+/// Variables of a TOML file, named after its keys in upper case.
+///
+/// Tables only group the settings, e.g. `[database]`, and arrays are joined with commas.
+fn file_settings(table: toml::Table) -> Vec<(String, String)> {
+    let mut settings = Vec::new();
+    for (key, value) in table {
+        let value = match value {
+            toml::Value::Table(table) => {
+                settings.extend(file_settings(table));
+                continue;
+            }
+            toml::Value::Array(values) => values
+                .into_iter()
+                .map(toml_string)
+                .collect::<Vec<_>>()
+                .join(","),
+            value => toml_string(value),
+        };
+        settings.push((key.to_uppercase(), value));
+    }
+    settings
+}
+
+fn toml_string(value: toml::Value) -> String {
+    match value {
+        toml::Value::String(s) => s,
+        value => value.to_string(),
+    }
+}
+
+/// The effective settings as a TOML file, with their source and the secrets redacted.
+fn effective_config(
+    command: &Command,
+    matches: &ArgMatches,
+    from_file: &HashSet<String>,
+) -> String {
+    let mut output = String::new();
+    for arg in command.get_arguments() {
+        let (Some(env), id) = (arg.get_env(), arg.get_id().as_str()) else {
+            continue;
+        };
+        let name = env.to_string_lossy();
+        let key = name.to_lowercase();
+        let Some(values) = matches.get_raw(id) else {
+            output.push_str(&format!("# {key} is not set\n"));
+            continue;
+        };
+
+        let value = if arg.is_hide_env_values_set() {
+            REDACTED.to_owned()
+        } else {
+            let values: Vec<_> = values.map(|v| v.to_string_lossy()).collect();
+            values.join(",")
+        };
+        let source = match matches.value_source(id) {
+            Some(ValueSource::CommandLine) => "command line",
+            Some(ValueSource::EnvVariable) if from_file.contains(name.as_ref()) => "file",
+            Some(ValueSource::EnvVariable) => "environment",
+            _ => "default",
+        };
+        output.push_str(&format!(
+            "{key} = {} # {source}\n",
+            toml::Value::String(value)
+        ));
+    }
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::{Arg, CommandFactory};
+
+    const ARGS: &[&str] = &[
+        "api",
+        "--pguser=www-data",
+        "--pgpassword=p4ssw0rd",
+        "--pghost=localhost",
+        "--pgport=5432",
+        "--pgdatabase=swissgeol",
+        "--pg-ssl-mode=disable",
+        "--app-port=3000",
+        "--cognito-client-id=client",
+        "--cognito-pool-id=pool",
+        "--cognito-identity-pool-id=identity",
+        "--env=test",
+        "--ion-default-access-token=token",
+        "--s3-bucket=userdata",
+        "--projects-s3-bucket=projects",
+    ];
+
+    #[test]
+    fn file_settings_are_named_after_the_variables() {
+        let table = r#"
+            app_port = 3000
+            [database]
+            pghost = "db"
+            [links]
+            public_api_url = "https://api.swissgeol.ch"
+            [cors]
+            cors_origins = ["https://viewer.swissgeol.ch", "https://*.swissgeol.ch"]
+        "#
+        .parse()
+        .unwrap();
+
+        let mut settings = file_settings(table);
+        settings.sort();
+        assert_eq!(
+            settings,
+            [
+                ("APP_PORT", "3000"),
+                (
+                    "CORS_ORIGINS",
+                    "https://viewer.swissgeol.ch,https://*.swissgeol.ch"
+                ),
+                ("PGHOST", "db"),
+                ("PUBLIC_API_URL", "https://api.swissgeol.ch"),
+            ]
+            .map(|(name, value)| (name.to_owned(), value.to_owned()))
+        );
+    }
+
+    #[test]
+    fn file_settings_do_not_override_the_environment() {
+        let command = Command::new("test")
+            .arg(Arg::new("first").long("first").env("CONFIG_TEST_FIRST"))
+            .arg(Arg::new("second").long("second").env("CONFIG_TEST_SECOND"));
+        let path = std::env::temp_dir().join(format!("config-{}.toml", std::process::id()));
+        std::fs::write(
+            &path,
+            "config_test_first = \"file\"\nconfig_test_second = 2\n",
+        )
+        .unwrap();
+        std::env::set_var("CONFIG_TEST_FIRST", "environment");
+
+        let applied = apply_file(&command, &path).unwrap();
+        assert_eq!(applied, HashSet::from(["CONFIG_TEST_SECOND".to_owned()]));
+        assert_eq!(std::env::var("CONFIG_TEST_FIRST").unwrap(), "environment");
+        assert_eq!(std::env::var("CONFIG_TEST_SECOND").unwrap(), "2");
+
+        std::fs::write(
+            &path,
+            "config_test_first = \"file\"\npgpasword = \"typo\"\n",
+        )
+        .unwrap();
+        let error = apply_file(&command, &path).unwrap_err();
+        assert_eq!(
+            error,
+            format!("unknown settings in {}: pgpasword", path.display())
+        );
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn effective_config_redacts_the_secrets() {
+        let command = Config::command();
+        let matches = command.clone().try_get_matches_from(ARGS).unwrap();
+
+        let output = effective_config(&command, &matches, &HashSet::new());
+        assert!(output.contains("pguser = \"www-data\" # command line\n"));
+        assert!(output.contains("pgpassword = \"<redacted>\" # command line\n"));
+        assert!(output.contains("default_storage_quota = \"104857600\" # default\n"));
+        assert!(output.contains("# clamav_socket is not set\n"));
+        assert!(!output.contains("p4ssw0rd"));
+    }
+
+    #[test]
+    fn validate_lists_every_invalid_setting() {
+        let mut config = Config::try_parse_from(ARGS).unwrap();
+        assert_eq!(config.validate(), Ok(()));
+
+        config.app_port = 0;
+        config.auth.jwks_max_age = 60;
+        assert_eq!(
+            config.validate(),
+            Err(vec![
+                "APP_PORT must not be 0".to_owned(),
+                "JWKS_MAX_AGE must not be shorter than JWKS_REFRESH_INTERVAL".to_owned(),
+            ])
+        );
+    }
+}
@@ -0,0 +1,320 @@
+use axum::http::{HeaderName, HeaderValue, Method};
+use hyper::header::{LINK, LOCATION};
+use std::str::FromStr;
+use std::time::Duration;
+use tower_http::cors::{AllowOrigin, CorsLayer};
+use url::Url;
+
+use crate::{deprecation, handlers, X_REQUEST_ID};
+
+/// Configuration of the Cross-Origin Resource Sharing, allowing the viewers to call the API.
+#[derive(clap::Parser, Clone, Debug)]
+pub struct Cors {
+    /// Comma separated origins allowed to call the API, e.g. `https://viewer.swissgeol.ch`.
+    /// A leading `*.` matches any subdomain, e.g. `https://*.review-viewer.swissgeol.ch`
+    #[clap(
+        long,
+        env,
+        value_delimiter = ',',
+        default_value = "http://localhost:8000,\
+            https://api.dev-viewer.swissgeol.ch,\
+            https://api.int-viewer.swissgeol.ch,\
+            https://api.swissgeol.ch,\
+            https://review-viewer.swissgeol.ch,\
+            https://dev-viewer.swissgeol.ch,\
+            https://int-viewer.swissgeol.ch,\
+            https://viewer.swissgeol.ch"
+    )]
+    pub cors_origins: Vec<OriginPattern>,
+    /// Comma separated methods allowed in cross-origin requests
+    #[clap(
+        long,
+        env,
+        value_delimiter = ',',
+        value_parser = parse_method,
+        default_value = "GET,POST,PUT,DELETE"
+    )]
+    pub cors_methods: Vec<Method>,
+    /// Comma separated headers allowed in cross-origin requests
+    #[clap(
+        long,
+        env,
+        value_delimiter = ',',
+        default_value = "authorization,accept,content-type,x-request-id"
+    )]
+    pub cors_headers: Vec<HeaderName>,
+    /// Time the browsers may cache the result of a preflight request, in seconds
+    #[clap(long, env, default_value_t = 3600)]
+    pub cors_max_age: u64,
+}
+
+/// Origin allowed to call the API, possibly with a wildcard subdomain.
+#[derive(Clone, Debug, PartialEq)]
+pub enum OriginPattern {
+    /// An origin, e.g. `https://viewer.swissgeol.ch`
+    Exact(String),
+    /// The subdomains of a domain, e.g. `https://*.swissgeol.ch`, not matching the domain itself
+    Subdomains {
+        /// Scheme and separator, e.g. `https://`
+        scheme: String,
+        /// Domain with its leading dot and the optional port, e.g. `.swissgeol.ch`
+        suffix: String,
+    },
+}
+
+impl FromStr for OriginPattern {
+    type Err = String;
+
+    fn from_str(pattern: &str) -> Result<Self, Self::Err> {
+        let (scheme, host) = pattern.split_once("://").ok_or_else(|| {
+            format!("{pattern} is not an origin, e.g. https://viewer.swissgeol.ch")
+        })?;
+        let (wildcard, host) = match host.strip_prefix("*.") {
+            Some(domain) => (true, domain),
+            None => (false, host),
+        };
+
+        // Browsers send origins serialized, without path nor trailing slash, in lower case
+        let origin = format!("{scheme}://{host}");
+        let url = Url::parse(&origin).map_err(|e| format!("{pattern} is not an origin: {e}"))?;
+        if !matches!(url.scheme(), "http" | "https") {
+            return Err(format!("{pattern} is not an http or https origin"));
+        }
+        if url.origin().ascii_serialization() != origin {
+            return Err(format!(
+                "{pattern} is not a serialized origin, e.g. without path, trailing slash nor \
+                 upper case letters"
+            ));
+        }
+        if host.contains('*') {
+            return Err(format!(
+                "{pattern} may only contain a wildcard as its first label, e.g. https://*.swissgeol.ch"
+            ));
+        }
+        // A wildcard directly below a top-level domain would allow any site of that domain
+        if wildcard && url.host_str().is_none_or(|domain| !domain.contains('.')) {
+            return Err(format!(
+                "{pattern} must have at least two labels after its wildcard, e.g. https://*.swissgeol.ch"
+            ));
+        }
+
+        Ok(if wildcard {
+            Self::Subdomains {
+                scheme: format!("{scheme}://"),
+                suffix: format!(".{host}"),
+            }
+        } else {
+            Self::Exact(origin)
+        })
+    }
+}
+
+/// Parse a method, in any case as the methods are usually upper case.
+/// The `*` wildcard is rejected, as it is not allowed with credentials.
+fn parse_method(method: &str) -> Result<Method, String> {
+    let method = method.trim();
+    if method == "*" {
+        return Err("* is not allowed with credentials, list the methods instead".to_owned());
+    }
+    Method::from_bytes(method.to_uppercase().as_bytes()).map_err(|e| format!("{method}: {e}"))
+}
+
+impl OriginPattern {
+    pub fn matches(&self, origin: &str) -> bool {
+        match self {
+            Self::Exact(exact) => origin == exact,
+            Self::Subdomains { scheme, suffix } => origin
+                .strip_prefix(scheme.as_str())
+                .and_then(|host| host.strip_suffix(suffix.as_str()))
+                .is_some_and(|subdomain| {
+                    !subdomain.is_empty()
+                        && subdomain.split('.').all(|label| {
+                            !label.is_empty()
+                                && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+                        })
+                }),
+        }
+    }
+}
+
+impl Cors {
+    pub fn layer(&self) -> CorsLayer {
+        let origins = self.cors_origins.clone();
+        CorsLayer::new()
+            .allow_credentials(true)
+            .allow_methods(self.cors_methods.clone())
+            .allow_origin(AllowOrigin::predicate(move |origin: &HeaderValue, _| {
+                origin
+                    .to_str()
+                    .is_ok_and(|origin| origins.iter().any(|pattern| pattern.matches(origin)))
+            }))
+            .allow_headers(self.cors_headers.clone())
+            .expose_headers([
+                LOCATION,
+                HeaderName::from_static(handlers::TOTAL_COUNT_HEADER),
+                HeaderName::from_static(handlers::NEXT_CURSOR_HEADER),
+                HeaderName::from_static(deprecation::DEPRECATION_HEADER),
+                HeaderName::from_static(deprecation::SUNSET_HEADER),
+                LINK,
+                X_REQUEST_ID,
+            ])
+            .max_age(Duration::from_secs(self.cors_max_age))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::Body;
+    use axum::http::{header, Request, StatusCode};
+    use axum::routing::get;
+    use axum::Router;
+    use clap::Parser;
+    use tower::ServiceExt;
+
+    fn preflight(origin: &str, method: &str) -> Request<Body> {
+        Request::builder()
+            .method(Method::OPTIONS)
+            .uri("/projects")
+            .header(header::ORIGIN, origin)
+            .header(header::ACCESS_CONTROL_REQUEST_METHOD, method)
+            .header(
+                header::ACCESS_CONTROL_REQUEST_HEADERS,
+                "authorization,content-type",
+            )
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    fn app(args: &[&str]) -> Router {
+        let cors = Cors::try_parse_from([&["api"], args].concat()).unwrap();
+        Router::new()
+            .route("/projects", get(|| async { "[]" }))
+            .layer(cors.layer())
+    }
+
+    #[test]
+    fn origin_patterns_are_validated() {
+        assert_eq!(
+            "https://viewer.swissgeol.ch".parse(),
+            Ok(OriginPattern::Exact(
+                "https://viewer.swissgeol.ch".to_owned()
+            ))
+        );
+        assert_eq!(
+            "http://*.viewer.localhost:8000".parse(),
+            Ok(OriginPattern::Subdomains {
+                scheme: "http://".to_owned(),
+                suffix: ".viewer.localhost:8000".to_owned()
+            })
+        );
+        for invalid in [
+            "viewer.swissgeol.ch",
+            "*",
+            "https://viewer.swissgeol.ch/",
+            "https://viewer.swissgeol.ch/path",
+            "https://Viewer.swissgeol.ch",
+            "https://*",
+            "https://review-*.swissgeol.ch",
+            "https://*.*.swissgeol.ch",
+            "https://*.ch",
+            "http://*.localhost:8000",
+            "ftp://viewer.swissgeol.ch",
+        ] {
+            assert!(invalid.parse::<OriginPattern>().is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn wildcards_match_subdomains_only() {
+        let pattern: OriginPattern = "https://*.review-viewer.swissgeol.ch".parse().unwrap();
+        assert!(pattern.matches("https://pr-123.review-viewer.swissgeol.ch"));
+        assert!(pattern.matches("https://a.b.review-viewer.swissgeol.ch"));
+        assert!(!pattern.matches("https://review-viewer.swissgeol.ch"));
+        assert!(!pattern.matches("http://pr-123.review-viewer.swissgeol.ch"));
+        assert!(!pattern.matches("https://pr-123.review-viewer.swissgeol.ch:8443"));
+        assert!(!pattern.matches("https://evil.ch/.review-viewer.swissgeol.ch"));
+        assert!(!pattern.matches("https://evilreview-viewer.swissgeol.ch"));
+    }
+
+    #[tokio::test]
+    async fn preflight_allows_the_configured_origins() {
+        let app = app(&[
+            "--cors-origins=https://viewer.swissgeol.ch,https://*.review-viewer.swissgeol.ch",
+            "--cors-max-age=600",
+        ]);
+
+        for origin in [
+            "https://viewer.swissgeol.ch",
+            "https://pr-42.review-viewer.swissgeol.ch",
+        ] {
+            let response = app.clone().oneshot(preflight(origin, "PUT")).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            let headers = response.headers();
+            assert_eq!(headers[header::ACCESS_CONTROL_ALLOW_ORIGIN], origin);
+            assert_eq!(headers[header::ACCESS_CONTROL_ALLOW_CREDENTIALS], "true");
+            assert_eq!(
+                headers[header::ACCESS_CONTROL_ALLOW_METHODS],
+                "GET,POST,PUT,DELETE"
+            );
+            assert_eq!(
+                headers[header::ACCESS_CONTROL_ALLOW_HEADERS],
+                "authorization,accept,content-type,x-request-id"
+            );
+            assert_eq!(headers[header::ACCESS_CONTROL_MAX_AGE], "600");
+        }
+    }
+
+    #[tokio::test]
+    async fn preflight_rejects_other_origins() {
+        let app = app(&["--cors-origins=https://*.review-viewer.swissgeol.ch"]);
+
+        for origin in [
+            "https://viewer.swissgeol.ch",
+            "https://review-viewer.swissgeol.ch",
+            "https://review-viewer.swissgeol.ch.evil.ch",
+        ] {
+            let response = app.clone().oneshot(preflight(origin, "GET")).await.unwrap();
+            assert!(!response
+                .headers()
+                .contains_key(header::ACCESS_CONTROL_ALLOW_ORIGIN));
+        }
+    }
+
+    #[tokio::test]
+    async fn simple_requests_expose_the_headers_of_the_api() {
+        let app = app(&["--cors-methods=GET"]);
+        let request = Request::builder()
+            .uri("/projects")
+            .header(header::ORIGIN, "http://localhost:8000")
+            .body(Body::empty())
+            .unwrap();
+
+        let response = app.oneshot(request).await.unwrap();
+        let headers = response.headers();
+        assert_eq!(
+            headers[header::ACCESS_CONTROL_ALLOW_ORIGIN],
+            "http://localhost:8000"
+        );
+        assert!(headers[header::ACCESS_CONTROL_EXPOSE_HEADERS]
+            .to_str()
+            .unwrap()
+            .contains("x-request-id"));
+    }
+
+    #[test]
+    fn invalid_settings_are_rejected() {
+        for args in [
+            ["api", "--cors-origins=https://viewer.swissgeol.ch/"],
+            ["api", "--cors-methods=GET,NOT A METHOD"],
+            ["api", "--cors-methods=*"],
+            ["api", "--cors-headers=authorization,not a header"],
+            ["api", "--cors-max-age=-1"],
+        ] {
+            assert!(Cors::try_parse_from(args).is_err(), "{args:?}");
+        }
+
+        let cors = Cors::try_parse_from(["api", "--cors-methods=get, post"]).unwrap();
+        assert_eq!(cors.cors_methods, [Method::GET, Method::POST]);
+    }
+}
@@ -3,7 +3,9 @@ use sqlx::{
     Connection, Executor, PgConnection, PgPool,
 };
 
-#[derive(clap::Parser)]
+use crate::telemetry::REDACTED;
+
+#[derive(clap::Parser, Clone)]
 pub struct Database {
     /// The database username
     #[clap(long, env)]
@@ -21,8 +23,25 @@ pub struct Database {
     #[clap(long, env)]
     pub pgdatabase: String,
     /// The database ssl mode
-    #[clap(env)]
+    #[clap(long, env)]
     pub pg_ssl_mode: PgSslMode,
+    /// The maximum number of connections of the pool
+    #[clap(long, env, default_value_t = 50)]
+    pub pg_max_connections: u32,
+}
+
+impl std::fmt::Debug for Database {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Database")
+            .field("pguser", &self.pguser)
+            .field("pgpassword", &REDACTED)
+            .field("pghost", &self.pghost)
+            .field("pgport", &self.pgport)
+            .field("pgdatabase", &self.pgdatabase)
+            .field("pg_ssl_mode", &self.pg_ssl_mode)
+            .field("pg_max_connections", &self.pg_max_connections)
+            .finish()
+    }
 }
 
 impl Database {
@@ -53,7 +72,7 @@ impl Database {
 
         // Create pool
         let pool = PgPoolOptions::new()
-            .max_connections(50)
+            .max_connections(self.pg_max_connections)
             .connect_with(options.database(name))
             .await
             .expect("Failed to connect to Postgres.");
@@ -68,3 +87,25 @@ impl Database {
         pool
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn debug_redacts_the_password() {
+        let database = Database {
+            pguser: "user".to_owned(),
+            pgpassword: "secret".to_owned(),
+            pghost: "localhost".to_owned(),
+            pgport: 5432,
+            pgdatabase: "swissgeol".to_owned(),
+            pg_ssl_mode: PgSslMode::Prefer,
+            pg_max_connections: 50,
+        };
+
+        let debug = format!("{database:?}");
+        assert!(debug.contains("localhost"));
+        assert!(!debug.contains("secret"));
+    }
+}
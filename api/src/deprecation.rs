@@ -0,0 +1,44 @@
+use axum::extract::State;
+use axum::http::{header, HeaderName, HeaderValue};
+use axum::response::Response;
+use chrono::{DateTime, Utc};
+
+/// `Deprecation` response header, see RFC 9745
+pub const DEPRECATION_HEADER: &str = "deprecation";
+/// `Sunset` response header, see RFC 8594
+pub const SUNSET_HEADER: &str = "sunset";
+
+/// Date the first version of the API was deprecated, as a structured field date
+const V1_DEPRECATION: &str = "@1792368000";
+
+/// Deprecation of the first version of the API, served under `/api`.
+#[derive(clap::Parser, Clone, Debug)]
+pub struct Deprecation {
+    /// Date after which the first version of the API may be removed, e.g. `2027-06-30T00:00:00Z`
+    #[clap(long, env)]
+    pub api_v1_sunset: Option<DateTime<Utc>>,
+}
+
+/// Announce the deprecation of the first version in its responses, linking to the documentation
+/// of its successor.
+pub async fn deprecate_v1(
+    State(deprecation): State<Deprecation>,
+    mut response: Response,
+) -> Response {
+    let headers = response.headers_mut();
+    headers.insert(
+        HeaderName::from_static(DEPRECATION_HEADER),
+        HeaderValue::from_static(V1_DEPRECATION),
+    );
+    headers.append(
+        header::LINK,
+        HeaderValue::from_static("</api/docs>; rel=\"deprecation\"; type=\"text/html\""),
+    );
+    if let Some(sunset) = deprecation.api_v1_sunset {
+        let date = sunset.format("%a, %d %b %Y %H:%M:%S GMT").to_string();
+        if let Ok(value) = HeaderValue::from_str(&date) {
+            headers.insert(HeaderName::from_static(SUNSET_HEADER), value);
+        }
+    }
+    response
+}
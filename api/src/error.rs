@@ -1,7 +1,9 @@
-use axum::http::{header::WWW_AUTHENTICATE, StatusCode};
+use axum::http::header::{RETRY_AFTER, WWW_AUTHENTICATE};
+use axum::http::StatusCode;
 use axum::response::{IntoResponse, Response};
 use axum::Json;
-use serde_json::json;
+use serde_json::{json, Value};
+use std::time::Duration;
 
 #[derive(thiserror::Error, Debug)]
 pub enum Error {
@@ -21,6 +23,10 @@ pub enum Error {
     #[error("request path not found")]
     NotFound,
 
+    /// Return `429 Too Many Requests`, with the time after which the client may retry.
+    #[error("too many requests")]
+    TooManyRequests(Duration),
+
     /// Automatically return `500 Internal Server Error` on a `sqlx::Error`.
     #[error("an error occurred with the database")]
     Sqlx(#[from] sqlx::Error),
@@ -40,10 +46,23 @@ impl Error {
             Self::Unauthorized => StatusCode::UNAUTHORIZED,
             Self::Forbidden | Self::Jwt(_) => StatusCode::FORBIDDEN,
             Self::NotFound => StatusCode::NOT_FOUND,
+            Self::TooManyRequests(_) => StatusCode::TOO_MANY_REQUESTS,
             Self::Sqlx(_) | Self::Anyhow(_) => StatusCode::INTERNAL_SERVER_ERROR,
             Self::Api(code, _) => *code,
         }
     }
+
+    /// Body of the response, with the id of the request to correlate it with the logs.
+    fn body(&self, message: String) -> Json<Value> {
+        let mut body = json!({
+          "status": self.status_code().as_u16(),
+          "message": message,
+        });
+        if let Some(request_id) = crate::telemetry::request_id() {
+            body["request_id"] = Value::String(request_id);
+        }
+        Json(body)
+    }
 }
 
 impl IntoResponse for Error {
@@ -56,10 +75,18 @@ impl IntoResponse for Error {
                     // for the `401 Unauthorized` response code:
                     // https://developer.mozilla.org/en-US/docs/Web/HTTP/Status/401
                     [(WWW_AUTHENTICATE, "Token")],
-                    Json(json!({
-                      "status": self.status_code().as_u16(),
-                      "message": self.to_string(),
-                    })),
+                    self.body(self.to_string()),
+                )
+                    .into_response();
+            }
+
+            Self::TooManyRequests(retry_after) => {
+                // Whole seconds, rounded up not to retry too early
+                let seconds = retry_after.as_secs_f64().ceil() as u64;
+                return (
+                    self.status_code(),
+                    [(RETRY_AFTER, seconds.max(1).to_string())],
+                    self.body(self.to_string()),
                 )
                     .into_response();
             }
@@ -88,11 +115,6 @@ impl IntoResponse for Error {
             _ => self.to_string(),
         };
 
-        let body = json!({
-          "status": self.status_code().as_u16(),
-          "message": message,
-        });
-
-        (self.status_code(), Json(body)).into_response()
+        (self.status_code(), self.body(message)).into_response()
     }
 }
@@ -0,0 +1,468 @@
+use serde::{Deserialize, Serialize};
+use utoipa::openapi::{schema::Schema, RefOr};
+use utoipa::{PartialSchema, ToSchema};
+
+/// A drawing stored with a project.
+///
+/// On the wire (and in the `projects.project` jsonb) geometries keep the flat
+/// shape used by the viewer, see [`GeometryRecord`]. Deserialization maps that
+/// shape onto the variant matching its `type`, dropping properties that do not
+/// belong to it.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(try_from = "GeometryRecord", into = "GeometryRecord")]
+pub enum Geometry {
+    Point(Point),
+    Line(Line),
+    Polygon(Polygon),
+    Rectangle(Polygon),
+    /// A point extruded to a cylinder, optionally linked to a swissforages borehole.
+    /// The viewer draws boreholes as points, so they are serialized with `"type": "point"`.
+    Borehole(Borehole),
+}
+
+/// Properties shared by every geometry type.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct GeometryProperties {
+    pub id: Option<String>,
+    pub name: Option<String>,
+    pub show: Option<bool>,
+    pub description: Option<String>,
+    pub image: Option<String>,
+    pub website: Option<String>,
+    pub color: Option<CesiumColor>,
+    pub editable: Option<bool>,
+    pub copyable: Option<bool>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Point {
+    pub properties: GeometryProperties,
+    pub position: Cartesian3,
+    pub point_symbol: Option<String>,
+    pub clamp_point: Option<bool>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Borehole {
+    pub properties: GeometryProperties,
+    pub position: Cartesian3,
+    pub point_symbol: Option<String>,
+    pub clamp_point: Option<bool>,
+    pub volume_showed: Option<bool>,
+    pub swissforages_id: Option<String>,
+    pub depth: Option<f64>,
+    pub diameter: Option<f64>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Line {
+    pub properties: GeometryProperties,
+    pub positions: Vec<Cartesian3>,
+    pub measurements: Measurements,
+    pub volume: Volume,
+}
+
+/// A polygon or rectangle.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Polygon {
+    pub properties: GeometryProperties,
+    pub positions: Vec<Cartesian3>,
+    pub measurements: Measurements,
+    pub volume: Volume,
+    pub show_slicing_box: Option<bool>,
+}
+
+/// Measurements computed by the viewer while drawing.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Measurements {
+    pub area: Option<String>,
+    pub perimeter: Option<String>,
+    pub sides_length: Option<Vec<f64>>,
+    pub number_of_segments: Option<u32>,
+}
+
+/// Extrusion of a line or area.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Volume {
+    pub volume_showed: Option<bool>,
+    pub volume_height_limits: Option<GeometryVolumeHeightLimits>,
+}
+
+/// Earth-centered, earth-fixed coordinates in meters.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, ToSchema)]
+pub struct Cartesian3 {
+    pub x: f64,
+    pub y: f64,
+    pub z: f64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, ToSchema)]
+pub struct CesiumColor {
+    pub red: f64,
+    pub green: f64,
+    pub blue: f64,
+    pub alpha: f64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct GeometryVolumeHeightLimits {
+    pub lower_limit: f64,
+    pub height: f64,
+}
+
+impl Geometry {
+    pub fn positions(&self) -> &[Cartesian3] {
+        match self {
+            Self::Point(Point { position, .. }) | Self::Borehole(Borehole { position, .. }) => {
+                std::slice::from_ref(position)
+            }
+            Self::Line(Line { positions, .. })
+            | Self::Polygon(Polygon { positions, .. })
+            | Self::Rectangle(Polygon { positions, .. }) => positions,
+        }
+    }
+
+    pub fn properties(&self) -> &GeometryProperties {
+        match self {
+            Self::Point(Point { properties, .. })
+            | Self::Borehole(Borehole { properties, .. })
+            | Self::Line(Line { properties, .. })
+            | Self::Polygon(Polygon { properties, .. })
+            | Self::Rectangle(Polygon { properties, .. }) => properties,
+        }
+    }
+}
+
+/// Flat representation of a [`Geometry`] as exchanged with the viewer.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, ToSchema)]
+#[serde(rename_all = "camelCase")]
+#[schema(description = "A drawing stored with a project")]
+struct GeometryRecord {
+    /// `point`, `line`, `polygon` or `rectangle`
+    #[serde(rename = "type")]
+    typ: String,
+    positions: Vec<Cartesian3>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    show: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    area: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    perimeter: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sides_length: Option<Vec<f64>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    number_of_segments: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    description: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    image: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    website: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    point_symbol: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    color: Option<CesiumColor>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    clamp_point: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    show_slicing_box: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    volume_showed: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    volume_height_limits: Option<GeometryVolumeHeightLimits>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    swissforages_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    depth: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    diameter: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    editable: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    copyable: Option<bool>,
+}
+
+impl GeometryRecord {
+    fn properties(&mut self) -> GeometryProperties {
+        GeometryProperties {
+            id: self.id.take(),
+            name: self.name.take(),
+            show: self.show.take(),
+            description: self.description.take(),
+            image: self.image.take(),
+            website: self.website.take(),
+            color: self.color.take(),
+            editable: self.editable.take(),
+            copyable: self.copyable.take(),
+        }
+    }
+
+    fn measurements(&mut self) -> Measurements {
+        Measurements {
+            area: self.area.take(),
+            perimeter: self.perimeter.take(),
+            sides_length: self.sides_length.take(),
+            number_of_segments: self.number_of_segments.take(),
+        }
+    }
+
+    fn volume(&mut self) -> Volume {
+        Volume {
+            volume_showed: self.volume_showed.take(),
+            volume_height_limits: self.volume_height_limits.take(),
+        }
+    }
+
+    fn polygon(&mut self) -> Polygon {
+        Polygon {
+            properties: self.properties(),
+            positions: std::mem::take(&mut self.positions),
+            measurements: self.measurements(),
+            volume: self.volume(),
+            show_slicing_box: self.show_slicing_box.take(),
+        }
+    }
+
+    fn with_properties(typ: &str, properties: GeometryProperties) -> Self {
+        let GeometryProperties {
+            id,
+            name,
+            show,
+            description,
+            image,
+            website,
+            color,
+            editable,
+            copyable,
+        } = properties;
+        Self {
+            typ: typ.to_owned(),
+            id,
+            name,
+            show,
+            description,
+            image,
+            website,
+            color,
+            editable,
+            copyable,
+            ..Default::default()
+        }
+    }
+
+    fn set_measurements(&mut self, measurements: Measurements) {
+        self.area = measurements.area;
+        self.perimeter = measurements.perimeter;
+        self.sides_length = measurements.sides_length;
+        self.number_of_segments = measurements.number_of_segments;
+    }
+
+    fn set_volume(&mut self, volume: Volume) {
+        self.volume_showed = volume.volume_showed;
+        self.volume_height_limits = volume.volume_height_limits;
+    }
+
+    fn from_polygon(typ: &str, polygon: Polygon) -> Self {
+        let mut record = Self::with_properties(typ, polygon.properties);
+        record.positions = polygon.positions;
+        record.set_measurements(polygon.measurements);
+        record.set_volume(polygon.volume);
+        record.show_slicing_box = polygon.show_slicing_box;
+        record
+    }
+}
+
+// Documented with the flat shape it is serialized to
+impl PartialSchema for Geometry {
+    fn schema() -> RefOr<Schema> {
+        GeometryRecord::schema()
+    }
+}
+
+impl ToSchema for Geometry {
+    fn schemas(schemas: &mut Vec<(String, RefOr<Schema>)>) {
+        GeometryRecord::schemas(schemas)
+    }
+}
+
+impl TryFrom<GeometryRecord> for Geometry {
+    type Error = String;
+
+    fn try_from(mut record: GeometryRecord) -> Result<Self, Self::Error> {
+        let geometry = match record.typ.as_str() {
+            "point" => {
+                let position = *record
+                    .positions
+                    .first()
+                    .ok_or("point geometry requires a position")?;
+                // Hiding the volume of a borehole keeps its depth and diameter, so only
+                // the toggle being on classifies a point without them as a borehole
+                if record.swissforages_id.is_some()
+                    || record.depth.is_some()
+                    || record.diameter.is_some()
+                    || record.volume_showed == Some(true)
+                {
+                    Self::Borehole(Borehole {
+                        properties: record.properties(),
+                        position,
+                        point_symbol: record.point_symbol,
+                        clamp_point: record.clamp_point,
+                        volume_showed: record.volume_showed,
+                        swissforages_id: record.swissforages_id,
+                        depth: record.depth,
+                        diameter: record.diameter,
+                    })
+                } else {
+                    Self::Point(Point {
+                        properties: record.properties(),
+                        position,
+                        point_symbol: record.point_symbol,
+                        clamp_point: record.clamp_point,
+                    })
+                }
+            }
+            "line" => Self::Line(Line {
+                properties: record.properties(),
+                positions: std::mem::take(&mut record.positions),
+                measurements: record.measurements(),
+                volume: record.volume(),
+            }),
+            "polygon" => Self::Polygon(record.polygon()),
+            "rectangle" => Self::Rectangle(record.polygon()),
+            other => return Err(format!("unknown geometry type `{other}`")),
+        };
+        Ok(geometry)
+    }
+}
+
+impl From<Geometry> for GeometryRecord {
+    fn from(geometry: Geometry) -> Self {
+        match geometry {
+            Geometry::Point(point) => Self {
+                positions: vec![point.position],
+                point_symbol: point.point_symbol,
+                clamp_point: point.clamp_point,
+                ..Self::with_properties("point", point.properties)
+            },
+            Geometry::Borehole(borehole) => Self {
+                positions: vec![borehole.position],
+                point_symbol: borehole.point_symbol,
+                clamp_point: borehole.clamp_point,
+                volume_showed: borehole.volume_showed,
+                swissforages_id: borehole.swissforages_id,
+                depth: borehole.depth,
+                diameter: borehole.diameter,
+                ..Self::with_properties("point", borehole.properties)
+            },
+            Geometry::Line(line) => {
+                let mut record = Self::with_properties("line", line.properties);
+                record.positions = line.positions;
+                record.set_measurements(line.measurements);
+                record.set_volume(line.volume);
+                record
+            }
+            Geometry::Polygon(polygon) => Self::from_polygon("polygon", polygon),
+            Geometry::Rectangle(rectangle) => Self::from_polygon("rectangle", rectangle),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn point_with_hidden_volume_keeps_its_depth_and_diameter() {
+        let geometry: Geometry = serde_json::from_value(json!({
+            "type": "point",
+            "positions": [{"x": 4325000.5, "y": 565000, "z": 4638000}],
+            "name": "Point 1",
+            "volumeShowed": false,
+            "volumeHeightLimits": {"lowerLimit": 0, "height": 100},
+            "diameter": 40,
+            "depth": 400,
+            "sidesLength": null,
+            "pointSymbol": "/images/i_point.svg",
+        }))
+        .unwrap();
+
+        let Geometry::Borehole(borehole) = &geometry else {
+            panic!("expected a borehole, got {geometry:?}");
+        };
+        assert_eq!(borehole.properties.name.as_deref(), Some("Point 1"));
+        assert_eq!(borehole.position.x, 4325000.5);
+        assert_eq!(
+            serde_json::to_value(&geometry).unwrap(),
+            json!({
+                "type": "point",
+                "positions": [{"x": 4325000.5, "y": 565000.0, "z": 4638000.0}],
+                "name": "Point 1",
+                "pointSymbol": "/images/i_point.svg",
+                "volumeShowed": false,
+                "depth": 400.0,
+                "diameter": 40.0,
+            })
+        );
+    }
+
+    #[test]
+    fn swissforages_point_is_borehole() {
+        let geometry: Geometry = serde_json::from_value(json!({
+            "type": "point",
+            "positions": [{"x": 1, "y": 2, "z": 3}],
+            "swissforagesId": "42",
+            "depth": 250,
+            "diameter": 40,
+        }))
+        .unwrap();
+
+        let Geometry::Borehole(borehole) = &geometry else {
+            panic!("expected a borehole, got {geometry:?}");
+        };
+        assert_eq!(borehole.depth, Some(250.0));
+        assert_eq!(serde_json::to_value(&geometry).unwrap()["type"], "point");
+    }
+
+    #[test]
+    fn rectangle_round_trips() {
+        let value = json!({
+            "type": "rectangle",
+            "positions": [
+                {"x": 1.0, "y": 2.0, "z": 3.0},
+                {"x": 4.0, "y": 5.0, "z": 6.0},
+                {"x": 7.0, "y": 8.0, "z": 9.0},
+            ],
+            "area": "1.250",
+            "sidesLength": [1.0, 1.25],
+            "numberOfSegments": 4,
+            "showSlicingBox": true,
+            "volumeShowed": true,
+            "volumeHeightLimits": {"lowerLimit": -100.0, "height": 200.0},
+            "color": {"red": 1.0, "green": 0.5, "blue": 0.0, "alpha": 1.0},
+        });
+        let geometry: Geometry = serde_json::from_value(value.clone()).unwrap();
+
+        assert!(matches!(geometry, Geometry::Rectangle(_)));
+        assert_eq!(serde_json::to_value(&geometry).unwrap(), value);
+    }
+
+    #[test]
+    fn rejects_unknown_type_and_empty_point() {
+        assert!(serde_json::from_value::<Geometry>(json!({
+            "type": "circle",
+            "positions": [],
+        }))
+        .is_err());
+        assert!(serde_json::from_value::<Geometry>(json!({
+            "type": "point",
+            "positions": [],
+        }))
+        .is_err());
+    }
+}
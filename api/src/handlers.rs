@@ -1,28 +1,45 @@
 use aws_sdk_s3::Client;
 use axum::{
-    extract::{Extension, Json, Multipart, Path},
-    http::StatusCode,
+    extract::{Extension, Json, Multipart, Path, Query},
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    response::{IntoResponse, Redirect, Response},
 };
 use chrono::{DateTime, Utc};
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize};
 use sqlx::{FromRow, PgPool};
 use uuid::Uuid;
 
+use crate::asset_operations::{asset_key, lock_key, AssetOperation, AssetWorker};
 use crate::auth::Claims;
+use crate::config::{ClientConfig, Config};
+use crate::geometry::Geometry;
+use crate::health::{self, Health, HealthReport};
+use crate::images::{image_object_key, is_managed_image, process_image, ImageSize};
+use crate::layer_aliases::{self, BrokenView, LayerAlias, LayerAliasTarget, PermalinkCheck};
+use crate::links::{generate_code, validate_url, CreateLink, CreatedLink, Link, Links};
+use crate::openapi::FileUpload;
+use crate::project_list::{self, ProjectListQuery};
+use crate::projects::Projects;
+use crate::quotas::{record_asset_size, record_pending_upload, Quotas, StorageQuota, StorageUsage};
+use crate::scanning::{record_scan, ScanResult, ScanStatus, Scanner};
+use crate::spatial::{save_geometry_extents, BboxQuery};
+use crate::tags::{normalize_tag, normalize_tags, CuratedTag, TagUsage};
+use crate::thumbnails::{render_thumbnail, thumbnail_key};
+use crate::views::ViewState;
 use crate::{Error, Result};
 use anyhow::Context;
 use axum_macros::debug_handler;
-use clap::Parser;
-use rand::{distributions::Alphanumeric, Rng};
-use serde_json::Number;
+use metrics_exporter_prometheus::PrometheusHandle;
 use std::collections::HashSet;
+use std::sync::Arc;
+use utoipa::{IntoParams, ToSchema};
 
-#[derive(Serialize, Deserialize, Clone, Debug, FromRow)]
-pub struct ProjectQuery {
-    project: sqlx::types::Json<Project>,
-}
+/// Number of projects matching the filters of `GET /api/projects`
+pub const TOTAL_COUNT_HEADER: &str = "x-total-count";
+/// Cursor of the next page of `GET /api/projects`
+pub const NEXT_CURSOR_HEADER: &str = "x-next-cursor";
 
-#[derive(Serialize, Deserialize, Clone, Debug, FromRow)]
+#[derive(Serialize, Deserialize, Clone, Debug, FromRow, ToSchema)]
 pub struct CreateProject {
     pub owner: Member,
     #[serde(default)]
@@ -39,9 +56,11 @@ pub struct CreateProject {
     pub assets: Vec<Asset>,
     #[serde(default)]
     pub geometries: Vec<Geometry>,
+    #[serde(default)]
+    pub tags: Vec<String>,
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug, FromRow)]
+#[derive(Serialize, Deserialize, Clone, Debug, FromRow, ToSchema)]
 pub struct Project {
     pub id: Uuid,
     pub title: String,
@@ -61,92 +80,196 @@ pub struct Project {
     pub editors: Vec<Member>,
     #[serde(default)]
     pub geometries: Vec<Geometry>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    /// The project this project was copied from
+    #[serde(
+        rename = "duplicatedFrom",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub duplicated_from: Option<Uuid>,
+}
+
+impl Project {
+    /// Whether the user with the given lowercase email may view the project.
+    pub fn is_viewable_by(&self, email: &str) -> bool {
+        self.is_editable_by(email) || self.viewers.iter().any(|m| m.email == email)
+    }
+
+    /// Whether the user with the given lowercase email may edit the project.
+    pub fn is_editable_by(&self, email: &str) -> bool {
+        self.owner.email == email || self.editors.iter().any(|m| m.email == email)
+    }
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug, FromRow)]
+#[derive(Serialize, Deserialize, Clone, Debug, FromRow, ToSchema)]
 pub struct View {
     pub id: String,
     pub title: String,
     pub permalink: String,
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug, FromRow)]
+/// A view with its position in the project and its parsed state.
+#[derive(Serialize, Debug, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct ViewResource {
+    pub id: String,
+    pub title: String,
+    pub permalink: String,
+    pub position: usize,
+    /// `None` for permalinks saved before views were validated that cannot be parsed
+    pub state: Option<ViewState>,
+}
+
+impl ViewResource {
+    pub fn new(position: usize, view: View) -> Self {
+        Self {
+            state: ViewState::parse(&view.permalink).ok(),
+            id: view.id,
+            title: view.title,
+            permalink: view.permalink,
+            position,
+        }
+    }
+}
+
+/// Content of a view, given either as a permalink or as a structured state.
+#[derive(Deserialize, Debug, ToSchema)]
+pub struct ViewInput {
+    pub title: String,
+    pub permalink: Option<String>,
+    pub state: Option<ViewState>,
+    /// Position of a new view, appended if omitted
+    pub position: Option<usize>,
+}
+
+impl ViewInput {
+    fn permalink(&self) -> Result<String> {
+        ViewState::normalize(self.permalink.as_deref(), self.state.as_ref())
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, FromRow, ToSchema)]
 #[serde(rename_all = "camelCase")]
 pub struct Asset {
     pub name: String,
     pub key: String,
     pub clamp_to_ground: Option<bool>,
+    /// Set from the scan of the uploaded file when the project is saved
+    #[serde(default)]
+    #[sqlx(skip)]
+    pub scan_status: ScanStatus,
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug, FromRow)]
+#[derive(Serialize, Deserialize, Clone, Debug, FromRow, ToSchema)]
 pub struct Member {
+    #[serde(deserialize_with = "deserialize_lowercase")]
     pub email: String,
     pub name: String,
     pub surname: String,
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug, FromRow)]
+/// Summary of a project usable as template.
+#[derive(Serialize, Clone, Debug, ToSchema)]
+pub struct ProjectTemplate {
+    pub id: Uuid,
+    pub title: String,
+    pub description: Option<String>,
+    pub image: Option<String>,
+    pub color: String,
+    pub tags: Vec<String>,
+}
+
+impl From<Project> for ProjectTemplate {
+    fn from(project: Project) -> Self {
+        Self {
+            id: project.id,
+            title: project.title,
+            description: project.description,
+            image: project.image,
+            color: project.color,
+            tags: project.tags,
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Debug, ToSchema)]
+pub struct CreateProjectFromTemplate {
+    pub owner: Member,
+    /// Defaults to the title of the template
+    pub title: Option<String>,
+}
+
+#[derive(Deserialize, Clone, Debug, ToSchema)]
 #[serde(rename_all = "camelCase")]
-pub struct Geometry {
-    #[serde(rename = "type")]
-    typ: String,
-    positions: Vec<Cartesian3>,
-    id: Option<String>,
-    name: Option<String>,
-    show: Option<bool>,
-    area: Option<String>,
-    perimeter: Option<String>,
-    sides_length: Option<Vec<Number>>,
-    number_of_segments: Option<Number>,
-    description: Option<String>,
-    image: Option<String>,
-    website: Option<String>,
-    point_symbol: Option<String>,
-    color: Option<CesiumColor>,
-    clamp_point: Option<bool>,
-    show_slicing_box: Option<bool>,
-    volume_showed: Option<bool>,
-    volume_height_limits: Option<GeometryVolumeHeightLimits>,
-    swissforages_id: Option<String>,
-    depth: Option<Number>,
-    diameter: Option<Number>,
-    editable: Option<bool>,
-    copyable: Option<bool>,
-}
-
-#[derive(Serialize, Deserialize, Clone, Debug, FromRow)]
-struct Cartesian3 {
-    x: Number,
-    y: Number,
-    z: Number,
-}
-
-#[derive(Serialize, Deserialize, Clone, Debug, FromRow)]
-struct CesiumColor {
-    red: Number,
-    green: Number,
-    blue: Number,
-    alpha: Number,
-}
-
-#[derive(Serialize, Deserialize, Clone, Debug, FromRow)]
+pub struct DuplicateProject {
+    pub owner: Member,
+    /// Defaults to the title of the source project
+    pub title: Option<String>,
+    /// Copy the viewers and editors of the source project
+    #[serde(default)]
+    pub include_members: bool,
+}
+
+/// Emails are compared case-insensitively, so they are normalized when read.
+fn deserialize_lowercase<'de, D>(deserializer: D) -> Result<String, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    String::deserialize(deserializer).map(|s| s.to_lowercase())
+}
+
+#[derive(Serialize, Debug, FromRow, ToSchema)]
 #[serde(rename_all = "camelCase")]
-struct GeometryVolumeHeightLimits {
-    lower_limit: Number,
-    height: Number,
+pub struct GeometrySearchResult {
+    pub project_id: Uuid,
+    pub project_title: String,
+    #[schema(value_type = Geometry)]
+    pub geometry: sqlx::types::Json<Geometry>,
+}
+
+#[derive(Serialize, Debug, ToSchema)]
+pub struct ProjectImage {
+    pub image: String,
+}
+
+#[derive(Deserialize, Debug, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct ImageQuery {
+    #[serde(default)]
+    pub size: ImageSize,
 }
 
-#[derive(Serialize)]
+#[derive(Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
 pub struct UploadResponse {
     pub key: String,
+    pub scan_status: ScanStatus,
 }
 
+#[utoipa::path(
+    get, path = "/api/client-config", tag = "config",
+    responses((status = 200, body = ClientConfig)),
+)]
 #[debug_handler]
-pub async fn get_client_config() -> Json<crate::config::ClientConfig> {
-    Json(crate::config::ClientConfig::parse())
+pub async fn get_client_config(Extension(config): Extension<Arc<Config>>) -> Json<ClientConfig> {
+    Json(config.client_config())
+}
+
+/// OpenAPI document of the API, also rendered at `/api/docs`.
+pub async fn get_openapi() -> Json<utoipa::openapi::OpenApi> {
+    Json(crate::openapi::openapi())
 }
 
 // Health check endpoint
+#[utoipa::path(
+    get, path = "/api/health_check", tag = "config",
+    responses(
+        (status = 200, description = "Version of the API", body = String, content_type = "text/plain"),
+        (status = 503, description = "Database unavailable", body = String, content_type = "text/plain"),
+    ),
+)]
 pub async fn health_check(Extension(pool): Extension<PgPool>) -> (StatusCode, String) {
     let version = format!("CARGO_PKG_VERSION: {}", env!("CARGO_PKG_VERSION"));
     let status = if sqlx::query("SELECT 1 AS test")
@@ -161,10 +284,58 @@ pub async fn health_check(Extension(pool): Extension<PgPool>) -> (StatusCode, St
     (status, version)
 }
 
+/// Liveness of the API, without checking its dependencies
+#[utoipa::path(
+    get, path = "/api/health/live", tag = "config",
+    responses((status = 200, description = "The API is running", body = HealthReport)),
+)]
+#[debug_handler]
+pub async fn health_live() -> Json<HealthReport> {
+    Json(HealthReport::live())
+}
+
+/// Readiness of the API, checking the database, the bucket and the keys validating the tokens
+#[utoipa::path(
+    get, path = "/api/health/ready", tag = "config",
+    responses(
+        (status = 200, description = "Every dependency is up", body = HealthReport),
+        (status = 503, description = "A dependency is down", body = HealthReport),
+    ),
+)]
+#[debug_handler]
+pub async fn health_ready(Extension(health): Extension<Health>) -> impl IntoResponse {
+    let report = health.ready().await;
+    let status = match report.status {
+        health::HealthStatus::Up => StatusCode::OK,
+        health::HealthStatus::Down => StatusCode::SERVICE_UNAVAILABLE,
+    };
+    (status, Json(report))
+}
+
+/// Metrics in the Prometheus text format
+pub async fn get_metrics(
+    Extension(pool): Extension<PgPool>,
+    Extension(recorder): Extension<PrometheusHandle>,
+) -> Result<impl IntoResponse> {
+    crate::metrics::record_gauges(&pool).await?;
+    Ok((
+        [(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static("text/plain; version=0.0.4"),
+        )],
+        recorder.render(),
+    ))
+}
+
+#[utoipa::path(
+    post, path = "/projects", tag = "projects",
+    request_body = CreateProject,
+    responses((status = 200, description = "Id of the created project", body = Uuid)),
+    security(("bearer" = [])),
+)]
 #[axum_macros::debug_handler]
 pub async fn create_project(
-    Extension(pool): Extension<PgPool>,
-    Extension(client): Extension<Client>,
+    Extension(projects): Extension<Projects>,
     claims: Claims,
     Json(project): Json<CreateProject>,
 ) -> Result<Json<Uuid>> {
@@ -176,8 +347,6 @@ pub async fn create_project(
         ));
     }
 
-    save_assets(client, &project.assets).await;
-
     // Create project
     let project = Project {
         id: Uuid::new_v4(),
@@ -193,51 +362,43 @@ pub async fn create_project(
         viewers: project.viewers,
         editors: project.editors,
         geometries: project.geometries,
+        tags: project.tags,
+        duplicated_from: None,
     };
 
-    let result = sqlx::query_scalar!(
-        "INSERT INTO projects (id, project) VALUES ($1, $2) RETURNING id",
-        &project.id,
-        sqlx::types::Json(&project) as _
-    )
-    .fetch_one(&pool)
-    .await?;
+    let result = projects.create(project).await?;
 
-    Ok(Json(result))
+    Ok(Json(result.id))
 }
 
+#[utoipa::path(
+    get, path = "/projects/{id}", tag = "projects",
+    params(("id" = Uuid, Path, description = "Project id")),
+    responses((status = 200, body = Project)),
+)]
 #[axum_macros::debug_handler]
 pub async fn get_project(
     Path(id): Path<Uuid>,
-    Extension(pool): Extension<PgPool>,
+    Extension(projects): Extension<Projects>,
 ) -> Result<Json<Project>> {
-    let result = sqlx::query_scalar!(
-        r#"SELECT project as "project: sqlx::types::Json<Project>" FROM projects WHERE id = $1"#,
-        id
-    )
-    .fetch_one(&pool)
-    .await?;
-
-    let mut project = result;
+    let result = projects.fetch(id).await?;
 
-    for viewer in project.viewers.iter_mut() {
-        viewer.email = viewer.email.to_lowercase();
-    }
-    for editor in project.editors.iter_mut() {
-        editor.email = editor.email.to_lowercase();
-    }
-    project.owner.email = project.owner.email.to_lowercase();
-
-    Ok(Json(project.0))
+    Ok(Json(result))
 }
 
+#[utoipa::path(
+    put, path = "/projects/{id}", tag = "projects",
+    params(("id" = Uuid, Path, description = "Project id")),
+    request_body = Project,
+    responses((status = 204)),
+    security(("bearer" = [])),
+)]
 #[axum_macros::debug_handler]
 pub async fn update_project(
     Path(id): Path<Uuid>,
-    Extension(pool): Extension<PgPool>,
-    Extension(client): Extension<Client>,
+    Extension(projects): Extension<Projects>,
     claims: Claims,
-    Json(mut project): Json<Project>,
+    Json(project): Json<Project>,
 ) -> Result<StatusCode> {
     let email = claims.email.to_lowercase();
     let member_emails: Vec<String> = project
@@ -252,101 +413,54 @@ pub async fn update_project(
         ));
     }
 
-    let bucket = std::env::var("PROJECTS_S3_BUCKET").unwrap();
-
-    let saved_project: Project = sqlx::query_scalar!(
-        r#"SELECT project as "project: sqlx::types::Json<Project>" FROM projects WHERE id = $1"#,
-        id
-    )
-    .fetch_one(&pool)
-    .await?
-    .0;
-
-    let project_assets = &project.assets;
-    let saved_project_keys: HashSet<_> = saved_project.assets.into_iter().map(|a| a.key).collect();
-    let new_project_keys: HashSet<_> = project_assets.iter().map(|a| a.key.clone()).collect();
-
-    // Find keys that are in saved_project_keys but not in new_project_keys
-    let keys_to_delete: HashSet<_> = saved_project_keys.difference(&new_project_keys).collect();
-
-    for key in keys_to_delete {
-        let path = format!("assets/saved/{}", key);
-
-        client
-            .delete_object()
-            .bucket(&bucket)
-            .key(&path)
-            .send()
-            .await
-            .unwrap();
-    }
-
-    save_assets(client, project_assets).await;
-
-    project.modified = Some(Utc::now());
-    sqlx::query_scalar!(
-        "UPDATE projects SET project = project || CAST( $2 as JSONB) WHERE id = $1 RETURNING id",
-        id,
-        sqlx::types::Json(project) as _
-    )
-    .fetch_one(&pool)
-    .await?;
+    projects.update(id, &email, project).await?;
 
     Ok(StatusCode::NO_CONTENT)
 }
 
+#[utoipa::path(
+    delete, path = "/projects/{id}", tag = "projects",
+    params(("id" = Uuid, Path, description = "Project id")),
+    responses((status = 204)),
+    security(("bearer" = [])),
+)]
 #[axum_macros::debug_handler]
 pub async fn delete_project(
     Path(id): Path<Uuid>,
-    Extension(pool): Extension<PgPool>,
-    Extension(client): Extension<Client>,
+    Extension(projects): Extension<Projects>,
     claims: Claims,
 ) -> Result<StatusCode> {
-    // Delete assets from bucket
-    let saved_project: Project = sqlx::query_scalar!(
-        r#"SELECT project as "project: sqlx::types::Json<Project>" FROM projects WHERE id = $1"#,
-        id
-    )
-    .fetch_one(&pool)
-    .await?
-    .0;
-
-    if saved_project.owner.email.to_lowercase() != claims.email.to_lowercase() {
-        return Err(Error::Api(
-            StatusCode::BAD_REQUEST,
-            "Project owner does not match token claims.",
-        ));
-    }
-
-    if !saved_project.assets.is_empty() {
-        delete_assets(client, &saved_project.assets).await
-    }
-
-    // Delete project from database
-    sqlx::query(r#"DELETE FROM projects WHERE id = $1"#)
-        .bind(id)
-        .execute(&pool)
-        .await?;
+    projects.delete(id, &claims.email).await?;
 
     Ok(StatusCode::NO_CONTENT)
 }
 
+#[utoipa::path(
+    put, path = "/projects/{id}/geometries", tag = "projects",
+    params(("id" = Uuid, Path, description = "Project id")),
+    request_body = Vec<Geometry>,
+    responses((status = 204)),
+    security(("bearer" = [])),
+)]
 #[axum_macros::debug_handler]
 pub async fn update_project_geometries(
     Path(id): Path<Uuid>,
     Extension(pool): Extension<PgPool>,
-    Extension(_client): Extension<Client>,
+    Extension(assets): Extension<AssetWorker>,
     claims: Claims,
     Json(geometries): Json<Vec<Geometry>>,
 ) -> Result<StatusCode> {
     let email = claims.email.to_lowercase();
 
-    let mut project: Project = sqlx::query_scalar!(
-        r#"SELECT project as "project: sqlx::types::Json<Project>" FROM projects WHERE id = $1"#,
+    // Locked until the geometries are saved, so that concurrent changes are not overwritten
+    let mut tx = pool.begin().await?;
+    let project: Project = sqlx::query_scalar!(
+        r#"SELECT project as "project: sqlx::types::Json<Project>" FROM projects WHERE id = $1 FOR UPDATE"#,
         id
     )
-    .fetch_one(&pool)
+    .fetch_optional(&mut *tx)
     .await?
+    .ok_or(Error::NotFound)?
     .0;
 
     let member_emails: Vec<String> = project
@@ -361,69 +475,341 @@ pub async fn update_project_geometries(
         ));
     }
 
-    project.geometries = geometries;
-
-    sqlx::query_scalar!(
-        "UPDATE projects SET project = project || CAST( $2 as JSONB) WHERE id = $1 RETURNING id",
+    sqlx::query!(
+        r#"
+        UPDATE projects
+        SET project = project || jsonb_build_object('geometries', $2::jsonb, 'modified', $3::jsonb)
+        WHERE id = $1
+        "#,
         id,
-        sqlx::types::Json(project) as _
+        sqlx::types::Json(&geometries) as _,
+        sqlx::types::Json(Utc::now()) as _
     )
-    .fetch_one(&pool)
+    .execute(&mut *tx)
     .await?;
+    save_geometry_extents(&mut tx, id, &geometries).await?;
+    AssetOperation::RenderThumbnail { project_id: id }
+        .enqueue(&mut tx)
+        .await?;
+    tx.commit().await?;
+    assets.notify();
 
     Ok(StatusCode::NO_CONTENT)
 }
 
+#[utoipa::path(
+    get, path = "/projects", tag = "projects",
+    params(BboxQuery, ProjectListQuery),
+    responses((
+        status = 200, body = Vec<Project>,
+        headers(
+            ("x-total-count" = i64, description = "Number of projects matching the filters"),
+            ("x-next-cursor" = String, description = "Cursor of the next page, if any"),
+        ),
+    )),
+    security(("bearer" = [])),
+)]
 #[axum_macros::debug_handler]
 pub async fn list_projects(
     Extension(pool): Extension<PgPool>,
     claims: Claims,
-) -> Result<Json<Vec<Project>>> {
+    Query(bbox): Query<BboxQuery>,
+    Query(query): Query<ProjectListQuery>,
+) -> Result<(HeaderMap, Json<Vec<Project>>)> {
+    let extent = bbox.extent()?;
+    let page =
+        project_list::list_projects(&pool, &claims.email.to_lowercase(), extent, &query).await?;
+
+    let headers = page.headers()?;
+
+    Ok((headers, Json(page.projects)))
+}
+
+#[utoipa::path(
+    get, path = "/geometries/search", tag = "projects",
+    params(BboxQuery),
+    responses((status = 200, body = Vec<GeometrySearchResult>)),
+    security(("bearer" = [])),
+)]
+#[axum_macros::debug_handler]
+pub async fn search_geometries(
+    Extension(pool): Extension<PgPool>,
+    claims: Claims,
+    Query(bbox): Query<BboxQuery>,
+) -> Result<Json<Vec<GeometrySearchResult>>> {
+    let extent = bbox
+        .extent()?
+        .ok_or(Error::Api(StatusCode::BAD_REQUEST, "Missing bbox."))?;
+
     let result = sqlx::query_as!(
-        ProjectQuery,
+        GeometrySearchResult,
         r#"
-        SELECT project AS "project!: sqlx::types::Json<Project>"
-        FROM projects
+        SELECT
+            projects.id AS project_id,
+            project->>'title' AS "project_title!",
+            project->'geometries'->geometry_index AS "geometry!: sqlx::types::Json<Geometry>"
+        FROM geometry_extents
+        JOIN projects ON projects.id = geometry_extents.project_id
         WHERE
-            LOWER(project->'owner'->>'email') = $1 OR
-            EXISTS (
-                SELECT 1 FROM jsonb_array_elements(project->'viewers') AS viewer
-                WHERE LOWER(viewer->>'email') = $1
-            ) OR
-            EXISTS (
-                SELECT 1 FROM jsonb_array_elements(project->'editors') AS editor
-                WHERE LOWER(editor->>'email') = $1
+            extent && box(point($2, $3), point($4, $5)) AND
+            (
+                LOWER(project->'owner'->>'email') = $1 OR
+                EXISTS (
+                    SELECT 1 FROM jsonb_array_elements(project->'viewers') AS viewer
+                    WHERE LOWER(viewer->>'email') = $1
+                ) OR
+                EXISTS (
+                    SELECT 1 FROM jsonb_array_elements(project->'editors') AS editor
+                    WHERE LOWER(editor->>'email') = $1
+                )
             )
+        ORDER BY projects.id, geometry_index
+        "#,
+        claims.email.to_lowercase(),
+        extent.min_x,
+        extent.min_y,
+        extent.max_x,
+        extent.max_y
+    )
+    .fetch_all(&pool)
+    .await?;
+
+    Ok(Json(result))
+}
+
+#[utoipa::path(
+    get, path = "/tags", tag = "tags",
+    responses((status = 200, body = Vec<TagUsage>)),
+    security(("bearer" = [])),
+)]
+#[axum_macros::debug_handler]
+pub async fn list_tags(
+    Extension(pool): Extension<PgPool>,
+    claims: Claims,
+) -> Result<Json<Vec<TagUsage>>> {
+    let result = sqlx::query_as!(
+        TagUsage,
+        r#"
+        WITH usage AS (
+            SELECT tag, COUNT(*) AS count
+            FROM projects, jsonb_array_elements_text(COALESCE(project->'tags', '[]')) AS tag
+            WHERE
+                LOWER(project->'owner'->>'email') = $1 OR
+                EXISTS (
+                    SELECT 1 FROM jsonb_array_elements(project->'viewers') AS viewer
+                    WHERE LOWER(viewer->>'email') = $1
+                ) OR
+                EXISTS (
+                    SELECT 1 FROM jsonb_array_elements(project->'editors') AS editor
+                    WHERE LOWER(editor->>'email') = $1
+                )
+            GROUP BY tag
+        )
+        SELECT
+            COALESCE(curated_tags.name, usage.tag) AS "name!",
+            curated_tags.description,
+            curated_tags.name IS NOT NULL AS "curated!",
+            COALESCE(usage.count, 0) AS "count!"
+        FROM usage
+        FULL OUTER JOIN curated_tags ON curated_tags.name = usage.tag
+        ORDER BY 4 DESC, 1
         "#,
         claims.email.to_lowercase()
     )
     .fetch_all(&pool)
     .await?;
 
-    let mut project_queries = result;
+    Ok(Json(result))
+}
 
-    for project_query in project_queries.iter_mut() {
-        for viewer in project_query.project.viewers.iter_mut() {
-            viewer.email = viewer.email.to_lowercase();
-        }
-        for editor in project_query.project.editors.iter_mut() {
-            editor.email = editor.email.to_lowercase();
-        }
-        project_query.project.owner.email = project_query.project.owner.email.to_lowercase();
+#[utoipa::path(
+    post, path = "/tags", tag = "tags",
+    request_body = CuratedTag,
+    responses((status = 201, body = CuratedTag)),
+    security(("bearer" = [])),
+)]
+#[axum_macros::debug_handler]
+pub async fn create_curated_tag(
+    Extension(pool): Extension<PgPool>,
+    claims: Claims,
+    Json(tag): Json<CuratedTag>,
+) -> Result<(StatusCode, Json<CuratedTag>)> {
+    if !claims.is_admin() {
+        return Err(Error::Forbidden);
+    }
+
+    let tag = sqlx::query_as!(
+        CuratedTag,
+        r#"
+        INSERT INTO curated_tags (name, description) VALUES ($1, $2)
+        ON CONFLICT (name) DO UPDATE SET description = EXCLUDED.description
+        RETURNING name, description
+        "#,
+        normalize_tag(&tag.name)?,
+        tag.description
+    )
+    .fetch_one(&pool)
+    .await?;
+
+    Ok((StatusCode::CREATED, Json(tag)))
+}
+
+#[utoipa::path(
+    delete, path = "/tags/{name}", tag = "tags",
+    params(("name" = String, Path, description = "Tag name")),
+    responses((status = 204)),
+    security(("bearer" = [])),
+)]
+#[axum_macros::debug_handler]
+pub async fn delete_curated_tag(
+    Path(name): Path<String>,
+    Extension(pool): Extension<PgPool>,
+    claims: Claims,
+) -> Result<StatusCode> {
+    if !claims.is_admin() {
+        return Err(Error::Forbidden);
+    }
+
+    let result = sqlx::query!(
+        "DELETE FROM curated_tags WHERE name = $1",
+        normalize_tag(&name)?
+    )
+    .execute(&pool)
+    .await?;
+    if result.rows_affected() == 0 {
+        return Err(Error::NotFound);
     }
 
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[utoipa::path(
+    get, path = "/templates", tag = "templates",
+    responses((status = 200, body = Vec<ProjectTemplate>)),
+    security(("bearer" = [])),
+)]
+#[axum_macros::debug_handler]
+pub async fn list_templates(
+    Extension(pool): Extension<PgPool>,
+    _claims: Claims,
+) -> Result<Json<Vec<ProjectTemplate>>> {
+    let result = sqlx::query_scalar!(
+        r#"
+        SELECT project AS "project!: sqlx::types::Json<Project>"
+        FROM projects
+        JOIN project_templates ON project_templates.project_id = projects.id
+        ORDER BY LOWER(project->>'title'), projects.id
+        "#
+    )
+    .fetch_all(&pool)
+    .await?;
+
     Ok(Json(
-        project_queries
-            .iter()
-            .map(|v: &ProjectQuery| v.project.to_owned().0)
-            .collect(),
+        result.into_iter().map(|project| project.0.into()).collect(),
     ))
 }
 
+#[utoipa::path(
+    put, path = "/templates/{id}", tag = "templates",
+    params(("id" = Uuid, Path, description = "Project id")),
+    responses((status = 204)),
+    security(("bearer" = [])),
+)]
+#[axum_macros::debug_handler]
+pub async fn create_template(
+    Path(id): Path<Uuid>,
+    Extension(pool): Extension<PgPool>,
+    claims: Claims,
+) -> Result<StatusCode> {
+    if !claims.is_admin() {
+        return Err(Error::Forbidden);
+    }
+
+    let exists = sqlx::query_scalar!(
+        r#"SELECT EXISTS (SELECT 1 FROM projects WHERE id = $1) AS "exists!""#,
+        id
+    )
+    .fetch_one(&pool)
+    .await?;
+    if !exists {
+        return Err(Error::NotFound);
+    }
+
+    sqlx::query!(
+        "INSERT INTO project_templates (project_id) VALUES ($1) ON CONFLICT (project_id) DO NOTHING",
+        id
+    )
+    .execute(&pool)
+    .await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[utoipa::path(
+    delete, path = "/templates/{id}", tag = "templates",
+    params(("id" = Uuid, Path, description = "Project id")),
+    responses((status = 204)),
+    security(("bearer" = [])),
+)]
+#[axum_macros::debug_handler]
+pub async fn delete_template(
+    Path(id): Path<Uuid>,
+    Extension(pool): Extension<PgPool>,
+    claims: Claims,
+) -> Result<StatusCode> {
+    if !claims.is_admin() {
+        return Err(Error::Forbidden);
+    }
+
+    let result = sqlx::query!("DELETE FROM project_templates WHERE project_id = $1", id)
+        .execute(&pool)
+        .await?;
+    if result.rows_affected() == 0 {
+        return Err(Error::NotFound);
+    }
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[utoipa::path(
+    post, path = "/projects/from-template/{id}", tag = "templates",
+    params(("id" = Uuid, Path, description = "Template id")),
+    request_body = CreateProjectFromTemplate,
+    responses((status = 200, description = "Id of the created project", body = Uuid)),
+    security(("bearer" = [])),
+)]
+#[axum_macros::debug_handler]
+pub async fn create_project_from_template(
+    Path(id): Path<Uuid>,
+    Extension(projects): Extension<Projects>,
+    claims: Claims,
+    Json(request): Json<CreateProjectFromTemplate>,
+) -> Result<Json<Uuid>> {
+    // Sanity check
+    if request.owner.email.to_lowercase() != claims.email.to_lowercase() {
+        return Err(Error::Api(
+            StatusCode::BAD_REQUEST,
+            "Project owner does not match token claims.",
+        ));
+    }
+
+    let result = projects
+        .create_from_template(id, request.owner, request.title)
+        .await?;
+
+    Ok(Json(result.id))
+}
+
+#[utoipa::path(
+    post, path = "/projects/duplicate", tag = "projects",
+    request_body = CreateProject,
+    responses((status = 200, description = "Id of the created project", body = Uuid)),
+    security(("bearer" = [])),
+)]
 #[axum_macros::debug_handler]
 pub async fn duplicate_project(
     Extension(pool): Extension<PgPool>,
-    Extension(client): Extension<Client>,
+    Extension(projects): Extension<Projects>,
     claims: Claims,
     Json(project): Json<CreateProject>,
 ) -> Result<Json<Uuid>> {
@@ -450,159 +836,947 @@ pub async fn duplicate_project(
         viewers: Vec::new(),
         editors: Vec::new(),
         geometries: project.geometries,
+        tags: normalize_tags(&project.tags)?,
+        duplicated_from: None,
     };
 
-    let mut assets: Vec<Asset> = Vec::new();
-    let bucket = std::env::var("PROJECTS_S3_BUCKET").unwrap();
+    // Only share assets of projects the user may view
+    let email = claims.email.to_lowercase();
+    let keys: Vec<String> = project.assets.iter().map(|a| a.key.clone()).collect();
+    let viewable_keys = sqlx::query_scalar!(
+        r#"
+        SELECT DISTINCT asset->>'key' AS "key!"
+        FROM projects, jsonb_array_elements(COALESCE(project->'assets', '[]')) AS asset
+        WHERE
+            asset->>'key' = ANY($2) AND
+            (
+                LOWER(project->'owner'->>'email') = $1 OR
+                EXISTS (
+                    SELECT 1 FROM jsonb_array_elements(project->'viewers') AS viewer
+                    WHERE LOWER(viewer->>'email') = $1
+                ) OR
+                EXISTS (
+                    SELECT 1 FROM jsonb_array_elements(project->'editors') AS editor
+                    WHERE LOWER(editor->>'email') = $1
+                )
+            )
+        "#,
+        email,
+        &keys
+    )
+    .fetch_all(&pool)
+    .await?;
+    duplicate.assets = project
+        .assets
+        .into_iter()
+        .filter(|a| viewable_keys.contains(&a.key))
+        .collect();
 
-    for asset in &project.assets {
-        let generated_file_name: String = generate_asset_name();
-        let asset_key = format!("assets/saved/{}", asset.key);
-        let dest_key = format!("assets/saved/{}", generated_file_name);
-        // Check if the file exists in the source directory
-        let source_exists = client
-            .head_object()
-            .bucket(&bucket)
-            .key(&asset_key)
-            .send()
-            .await
-            .is_ok();
+    let result = projects.insert(duplicate, &[]).await?;
 
-        if source_exists {
-            client
-                .copy_object()
-                .copy_source(format!("{}/{}", &bucket, &asset_key))
-                .bucket(&bucket)
-                .key(&dest_key)
-                .send()
-                .await
-                .context("Failed to copy object")?;
+    Ok(Json(result.id))
+}
 
-            assets.push(Asset {
-                name: asset.name.clone(),
-                key: generated_file_name,
-                clamp_to_ground: asset.clamp_to_ground,
-            });
-        }
+#[utoipa::path(
+    post, path = "/projects/{id}/duplicate", tag = "projects",
+    params(("id" = Uuid, Path, description = "Project id")),
+    request_body = DuplicateProject,
+    responses((status = 200, description = "Id of the created project", body = Uuid)),
+    security(("bearer" = [])),
+)]
+#[axum_macros::debug_handler]
+pub async fn duplicate_stored_project(
+    Path(id): Path<Uuid>,
+    Extension(projects): Extension<Projects>,
+    claims: Claims,
+    Json(request): Json<DuplicateProject>,
+) -> Result<Json<Uuid>> {
+    // Sanity check
+    if request.owner.email != claims.email.to_lowercase() {
+        return Err(Error::Api(
+            StatusCode::BAD_REQUEST,
+            "Project owner does not match token claims.",
+        ));
     }
 
-    duplicate.assets = assets;
+    let result = projects
+        .duplicate(id, request.owner, request.title, request.include_members)
+        .await?;
 
-    let result = sqlx::query_scalar!(
-        "INSERT INTO projects (id, project) VALUES ($1, $2) RETURNING id",
-        &duplicate.id,
-        sqlx::types::Json(&duplicate) as _
+    Ok(Json(result.id))
+}
+
+#[utoipa::path(
+    get, path = "/me/usage", tag = "quotas",
+    responses((status = 200, body = StorageUsage)),
+    security(("bearer" = [])),
+)]
+#[axum_macros::debug_handler]
+pub async fn get_storage_usage(
+    Extension(pool): Extension<PgPool>,
+    Extension(quotas): Extension<Quotas>,
+    claims: Claims,
+) -> Result<Json<StorageUsage>> {
+    let mut conn = pool.acquire().await?;
+    let usage = quotas.usage(&mut conn, &claims.email).await?;
+
+    Ok(Json(usage))
+}
+
+#[utoipa::path(
+    put, path = "/quotas/{email}", tag = "quotas",
+    params(("email" = String, Path, description = "Email of the user")),
+    request_body = StorageQuota,
+    responses((status = 200, body = StorageQuota)),
+    security(("bearer" = [])),
+)]
+#[axum_macros::debug_handler]
+pub async fn set_storage_quota(
+    Path(email): Path<String>,
+    Extension(pool): Extension<PgPool>,
+    claims: Claims,
+    Json(quota): Json<StorageQuota>,
+) -> Result<Json<StorageQuota>> {
+    if !claims.is_admin() {
+        return Err(Error::Forbidden);
+    }
+    if quota.quota_bytes < 0 {
+        return Err(Error::Api(
+            StatusCode::BAD_REQUEST,
+            "Invalid quota, expected a positive number of bytes.",
+        ));
+    }
+
+    sqlx::query!(
+        r#"
+        INSERT INTO storage_quotas (email, quota) VALUES ($1, $2)
+        ON CONFLICT (email) DO UPDATE SET quota = EXCLUDED.quota
+        "#,
+        email.to_lowercase(),
+        quota.quota_bytes
     )
-    .fetch_one(&pool)
+    .execute(&pool)
     .await?;
 
-    Ok(Json(result))
+    Ok(Json(quota))
 }
 
-pub async fn upload_asset(
-    Extension(_pool): Extension<PgPool>,
+#[utoipa::path(
+    delete, path = "/quotas/{email}", tag = "quotas",
+    params(("email" = String, Path, description = "Email of the user")),
+    responses((status = 204)),
+    security(("bearer" = [])),
+)]
+#[axum_macros::debug_handler]
+pub async fn delete_storage_quota(
+    Path(email): Path<String>,
+    Extension(pool): Extension<PgPool>,
+    claims: Claims,
+) -> Result<StatusCode> {
+    if !claims.is_admin() {
+        return Err(Error::Forbidden);
+    }
+
+    let result = sqlx::query!(
+        "DELETE FROM storage_quotas WHERE email = $1",
+        email.to_lowercase()
+    )
+    .execute(&pool)
+    .await?;
+    if result.rows_affected() == 0 {
+        return Err(Error::NotFound);
+    }
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[utoipa::path(
+    post, path = "/projects/{id}/image", tag = "assets",
+    params(("id" = Uuid, Path, description = "Project id")),
+    request_body(content = FileUpload, content_type = "multipart/form-data"),
+    responses((status = 200, body = ProjectImage)),
+    security(("bearer" = [])),
+)]
+#[axum_macros::debug_handler]
+pub async fn upload_project_image(
+    Path(id): Path<Uuid>,
+    Extension(pool): Extension<PgPool>,
+    Extension(config): Extension<Arc<Config>>,
     Extension(client): Extension<Client>,
+    Extension(assets): Extension<AssetWorker>,
+    claims: Claims,
     mut multipart: Multipart,
-) -> Result<Json<UploadResponse>> {
-    let bucket = std::env::var("PROJECTS_S3_BUCKET").unwrap();
-    let generated_file_name: String = generate_asset_name();
-    let temp_name = format!("assets/temp/{}", generated_file_name);
-    while let Some(field) = multipart.next_field().await.unwrap() {
-        if field.name() == Some("file") {
-            let bytes = field.bytes().await.unwrap();
+) -> Result<Json<ProjectImage>> {
+    let bucket = config.s3.projects_s3_bucket.clone();
 
-            client
-                .put_object()
-                .bucket(&bucket)
-                .key(&temp_name)
-                .body(bytes.into())
-                .send()
-                .await
-                .unwrap();
-        }
+    let project: Project = sqlx::query_scalar!(
+        r#"SELECT project as "project: sqlx::types::Json<Project>" FROM projects WHERE id = $1"#,
+        id
+    )
+    .fetch_optional(&pool)
+    .await?
+    .ok_or(Error::NotFound)?
+    .0;
+    if !project.is_editable_by(&claims.email.to_lowercase()) {
+        return Err(Error::Forbidden);
     }
 
-    Ok(Json(UploadResponse {
-        key: generated_file_name,
-    }))
-}
+    let mut content = None;
+    while let Some(field) = multipart
+        .next_field()
+        .await
+        .map_err(|_| Error::Api(StatusCode::BAD_REQUEST, "Invalid multipart body."))?
+    {
+        if field.name() == Some("file") {
+            content = Some(
+                field
+                    .bytes()
+                    .await
+                    .map_err(|_| Error::Api(StatusCode::BAD_REQUEST, "Invalid multipart body."))?,
+            );
+            break;
+        }
+    }
+    let content = content.ok_or(Error::Api(StatusCode::BAD_REQUEST, "Missing file."))?;
 
-async fn save_assets(client: Client, project_assets: &Vec<Asset>) {
-    let bucket = std::env::var("PROJECTS_S3_BUCKET").unwrap();
-    for asset in project_assets {
-        let temp_key = format!("assets/temp/{}", asset.key);
-        let permanent_key = format!("assets/saved/{}", asset.key);
+    let image = tokio::task::spawn_blocking(move || process_image(&content))
+        .await
+        .context("Failed to process image")??;
 
-        // Check if the file exists in the source directory
-        let source_exists = client
-            .head_object()
+    let mut tx = pool.begin().await?;
+    // Prevents the deletion of an identical image replaced in another project meanwhile
+    lock_key(&mut tx, &image.key).await?;
+    for (size, rendition) in image.renditions {
+        client
+            .put_object()
             .bucket(&bucket)
-            .key(&temp_key)
+            .key(image_object_key(&image.key, size))
+            .content_type("image/webp")
+            .body(rendition.into())
             .send()
             .await
-            .is_ok();
+            .context("Failed to upload image")?;
+    }
 
-        // Check if the file does not exist in the destination directory
-        let destination_exists = client
-            .head_object()
-            .bucket(&bucket)
-            .key(&permanent_key)
-            .send()
-            .await
-            .is_ok();
+    let previous = sqlx::query_scalar!(
+        r#"SELECT project->>'image' AS image FROM projects WHERE id = $1 FOR UPDATE"#,
+        id
+    )
+    .fetch_optional(&mut *tx)
+    .await?
+    .ok_or(Error::NotFound)?;
+    sqlx::query!(
+        r#"
+        UPDATE projects
+        SET project = project || jsonb_build_object('image', $2::text, 'modified', $3::jsonb)
+        WHERE id = $1
+        "#,
+        id,
+        image.key,
+        sqlx::types::Json(Utc::now()) as _
+    )
+    .execute(&mut *tx)
+    .await?;
+    if let Some(previous) = previous.filter(|p| is_managed_image(p) && *p != image.key) {
+        AssetOperation::DeleteImage { key: previous }
+            .enqueue(&mut tx)
+            .await?;
+    }
+    tx.commit().await?;
+    assets.notify();
 
-        if source_exists && !destination_exists {
-            client
-                .copy_object()
-                .bucket(&bucket)
-                .copy_source(format!("{}/{}", &bucket, &temp_key))
-                .key(&permanent_key)
-                .send()
-                .await
-                .unwrap();
+    Ok(Json(ProjectImage { image: image.key }))
+}
+
+#[utoipa::path(
+    get, path = "/projects/{id}/views", tag = "views",
+    params(("id" = Uuid, Path, description = "Project id")),
+    responses((status = 200, body = Vec<ViewResource>)),
+    security(("bearer" = [])),
+)]
+#[axum_macros::debug_handler]
+pub async fn list_views(
+    Path(id): Path<Uuid>,
+    Extension(pool): Extension<PgPool>,
+    claims: Claims,
+) -> Result<Json<Vec<ViewResource>>> {
+    let project = fetch_viewable_project(&pool, id, &claims).await?;
+
+    Ok(Json(
+        project
+            .views
+            .into_iter()
+            .enumerate()
+            .map(|(position, view)| ViewResource::new(position, view))
+            .collect(),
+    ))
+}
+
+#[utoipa::path(
+    get, path = "/projects/{id}/views/{view_id}", tag = "views",
+    params(
+        ("id" = Uuid, Path, description = "Project id"),
+        ("view_id" = String, Path, description = "View id"),
+    ),
+    responses((status = 200, body = ViewResource)),
+    security(("bearer" = [])),
+)]
+#[axum_macros::debug_handler]
+pub async fn get_view(
+    Path((id, view_id)): Path<(Uuid, String)>,
+    Extension(pool): Extension<PgPool>,
+    claims: Claims,
+) -> Result<Json<ViewResource>> {
+    let project = fetch_viewable_project(&pool, id, &claims).await?;
+
+    project
+        .views
+        .into_iter()
+        .enumerate()
+        .find(|(_, view)| view.id == view_id)
+        .map(|(position, view)| Json(ViewResource::new(position, view)))
+        .ok_or(Error::NotFound)
+}
+
+#[utoipa::path(
+    post, path = "/projects/{id}/views", tag = "views",
+    params(("id" = Uuid, Path, description = "Project id")),
+    request_body = ViewInput,
+    responses((status = 201, body = ViewResource)),
+    security(("bearer" = [])),
+)]
+#[axum_macros::debug_handler]
+pub async fn create_view(
+    Path(id): Path<Uuid>,
+    Extension(pool): Extension<PgPool>,
+    claims: Claims,
+    Json(input): Json<ViewInput>,
+) -> Result<(StatusCode, Json<ViewResource>)> {
+    let view = View {
+        id: Uuid::new_v4().to_string(),
+        title: input.title.clone(),
+        permalink: input.permalink()?,
+    };
+
+    let mut tx = pool.begin().await?;
+    let mut views = lock_project_views(&mut tx, id, &claims).await?;
+    let position = input.position.unwrap_or(views.len()).min(views.len());
+    views.insert(position, view.clone());
+    save_views(&mut tx, id, &views).await?;
+    tx.commit().await?;
+
+    Ok((StatusCode::CREATED, Json(ViewResource::new(position, view))))
+}
+
+#[utoipa::path(
+    put, path = "/projects/{id}/views/{view_id}", tag = "views",
+    params(
+        ("id" = Uuid, Path, description = "Project id"),
+        ("view_id" = String, Path, description = "View id"),
+    ),
+    request_body = ViewInput,
+    responses((status = 200, body = ViewResource)),
+    security(("bearer" = [])),
+)]
+#[axum_macros::debug_handler]
+pub async fn update_view(
+    Path((id, view_id)): Path<(Uuid, String)>,
+    Extension(pool): Extension<PgPool>,
+    claims: Claims,
+    Json(input): Json<ViewInput>,
+) -> Result<Json<ViewResource>> {
+    let permalink = input.permalink()?;
+
+    let mut tx = pool.begin().await?;
+    let mut views = lock_project_views(&mut tx, id, &claims).await?;
+    let position = views
+        .iter()
+        .position(|view| view.id == view_id)
+        .ok_or(Error::NotFound)?;
+    let mut view = views.remove(position);
+    view.title = input.title;
+    view.permalink = permalink;
+    let position = input.position.unwrap_or(position).min(views.len());
+    views.insert(position, view.clone());
+    save_views(&mut tx, id, &views).await?;
+    tx.commit().await?;
+
+    Ok(Json(ViewResource::new(position, view)))
+}
+
+#[utoipa::path(
+    delete, path = "/projects/{id}/views/{view_id}", tag = "views",
+    params(
+        ("id" = Uuid, Path, description = "Project id"),
+        ("view_id" = String, Path, description = "View id"),
+    ),
+    responses((status = 204)),
+    security(("bearer" = [])),
+)]
+#[axum_macros::debug_handler]
+pub async fn delete_view(
+    Path((id, view_id)): Path<(Uuid, String)>,
+    Extension(pool): Extension<PgPool>,
+    claims: Claims,
+) -> Result<StatusCode> {
+    let mut tx = pool.begin().await?;
+    let mut views = lock_project_views(&mut tx, id, &claims).await?;
+    let position = views
+        .iter()
+        .position(|view| view.id == view_id)
+        .ok_or(Error::NotFound)?;
+    views.remove(position);
+    save_views(&mut tx, id, &views).await?;
+    tx.commit().await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Reorder the views of a project, given all their ids in the new order.
+#[utoipa::path(
+    put, path = "/projects/{id}/views/order", tag = "views",
+    params(("id" = Uuid, Path, description = "Project id")),
+    request_body(content = Vec<String>, description = "Ids of all views of the project"),
+    responses((status = 200, body = Vec<ViewResource>)),
+    security(("bearer" = [])),
+)]
+#[axum_macros::debug_handler]
+pub async fn reorder_views(
+    Path(id): Path<Uuid>,
+    Extension(pool): Extension<PgPool>,
+    claims: Claims,
+    Json(order): Json<Vec<String>>,
+) -> Result<Json<Vec<ViewResource>>> {
+    let mut tx = pool.begin().await?;
+    let mut views = lock_project_views(&mut tx, id, &claims).await?;
+    let unique: HashSet<&String> = order.iter().collect();
+    if order.len() != views.len()
+        || unique.len() != order.len()
+        || views.iter().any(|view| !unique.contains(&view.id))
+    {
+        return Err(Error::Api(
+            StatusCode::BAD_REQUEST,
+            "Expected the ids of all views of the project.",
+        ));
+    }
+    views.sort_by_key(|view| order.iter().position(|id| *id == view.id));
+    save_views(&mut tx, id, &views).await?;
+    tx.commit().await?;
 
+    Ok(Json(
+        views
+            .into_iter()
+            .enumerate()
+            .map(|(position, view)| ViewResource::new(position, view))
+            .collect(),
+    ))
+}
+
+#[utoipa::path(
+    get, path = "/layer-aliases", tag = "layer aliases",
+    responses((status = 200, body = Vec<LayerAlias>)),
+    security(("bearer" = [])),
+)]
+#[axum_macros::debug_handler]
+pub async fn list_layer_aliases(
+    Extension(pool): Extension<PgPool>,
+    claims: Claims,
+) -> Result<Json<Vec<LayerAlias>>> {
+    if !claims.is_admin() {
+        return Err(Error::Forbidden);
+    }
+
+    let aliases = sqlx::query_as!(
+        LayerAlias,
+        "SELECT alias, layer FROM layer_aliases ORDER BY alias"
+    )
+    .fetch_all(&pool)
+    .await?;
+
+    Ok(Json(aliases))
+}
+
+/// Register a renamed layer, or a retired layer if `layer` is `null`.
+#[utoipa::path(
+    put, path = "/layer-aliases/{alias}", tag = "layer aliases",
+    params(("alias" = String, Path, description = "Former layer id")),
+    request_body = LayerAliasTarget,
+    responses((status = 200, body = LayerAlias)),
+    security(("bearer" = [])),
+)]
+#[axum_macros::debug_handler]
+pub async fn set_layer_alias(
+    Path(alias): Path<String>,
+    Extension(pool): Extension<PgPool>,
+    claims: Claims,
+    Json(target): Json<LayerAliasTarget>,
+) -> Result<Json<LayerAlias>> {
+    if !claims.is_admin() {
+        return Err(Error::Forbidden);
+    }
+    if alias.is_empty()
+        || target
+            .layer
+            .as_ref()
+            .is_some_and(|l| l.is_empty() || *l == alias)
+    {
+        return Err(Error::Api(StatusCode::BAD_REQUEST, "Invalid layer alias."));
+    }
+
+    let alias = sqlx::query_as!(
+        LayerAlias,
+        r#"
+        INSERT INTO layer_aliases (alias, layer) VALUES ($1, $2)
+        ON CONFLICT (alias) DO UPDATE SET layer = EXCLUDED.layer
+        RETURNING alias, layer
+        "#,
+        alias,
+        target.layer
+    )
+    .fetch_one(&pool)
+    .await?;
+
+    Ok(Json(alias))
+}
+
+#[utoipa::path(
+    delete, path = "/layer-aliases/{alias}", tag = "layer aliases",
+    params(("alias" = String, Path, description = "Former layer id")),
+    responses((status = 204)),
+    security(("bearer" = [])),
+)]
+#[axum_macros::debug_handler]
+pub async fn delete_layer_alias(
+    Path(alias): Path<String>,
+    Extension(pool): Extension<PgPool>,
+    claims: Claims,
+) -> Result<StatusCode> {
+    if !claims.is_admin() {
+        return Err(Error::Forbidden);
+    }
+
+    let result = sqlx::query!("DELETE FROM layer_aliases WHERE alias = $1", alias)
+        .execute(&pool)
+        .await?;
+    if result.rows_affected() == 0 {
+        return Err(Error::NotFound);
+    }
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Report the views referencing renamed, retired or unknown layers, and optionally rewrite them.
+#[utoipa::path(
+    post, path = "/maintenance/permalinks", tag = "layer aliases",
+    request_body = PermalinkCheck,
+    responses((status = 200, body = Vec<BrokenView>)),
+    security(("bearer" = [])),
+)]
+#[axum_macros::debug_handler]
+pub async fn check_permalinks(
+    Extension(pool): Extension<PgPool>,
+    claims: Claims,
+    Json(check): Json<PermalinkCheck>,
+) -> Result<Json<Vec<BrokenView>>> {
+    if !claims.is_admin() {
+        return Err(Error::Forbidden);
+    }
+
+    let report = layer_aliases::check_permalinks(&pool, &check).await?;
+
+    Ok(Json(report))
+}
+
+/// Number of attempts at generating an unused link code
+const LINK_CODE_ATTEMPTS: usize = 5;
+
+/// Create a short link for a viewer permalink, reusing the link of the same URL if any.
+#[utoipa::path(
+    post, path = "/links", tag = "links",
+    request_body = CreateLink,
+    responses(
+        (status = 201, body = CreatedLink, headers(("location" = String, description = "Short URL"))),
+        (status = 200, description = "Existing link of the URL", body = CreatedLink, headers(("location" = String, description = "Short URL"))),
+    ),
+)]
+#[axum_macros::debug_handler]
+pub async fn create_link(
+    Extension(config): Extension<Arc<Config>>,
+    Extension(pool): Extension<PgPool>,
+    Extension(links): Extension<Links>,
+    headers: HeaderMap,
+    Json(request): Json<CreateLink>,
+) -> Result<(StatusCode, HeaderMap, Json<CreatedLink>)> {
+    let url = validate_url(&request.url, &config.cors.cors_origins)?.to_string();
+    if request.expires.is_some_and(|expires| expires <= Utc::now()) {
+        return Err(Error::Api(
+            StatusCode::BAD_REQUEST,
+            "Invalid expiry, expected a future date.",
+        ));
+    }
+
+    let existing = match request.expires {
+        Some(_) => None,
+        None => {
+            sqlx::query_as!(
+                Link,
+                "SELECT * FROM links WHERE url = $1 AND expires IS NULL LIMIT 1",
+                url
+            )
+            .fetch_optional(&pool)
+            .await?
+        }
+    };
+    let (status, link) = match existing {
+        Some(link) => (StatusCode::OK, link),
+        None => {
+            let mut created = None;
+            for _ in 0..LINK_CODE_ATTEMPTS {
+                created = sqlx::query_as!(
+                    Link,
+                    r#"
+                    INSERT INTO links (code, url, expires) VALUES ($1, $2, $3)
+                    ON CONFLICT (code) DO NOTHING
+                    RETURNING *
+                    "#,
+                    generate_code(),
+                    url,
+                    request.expires
+                )
+                .fetch_optional(&pool)
+                .await?;
+                if created.is_some() {
+                    break;
+                }
+            }
+            let link = created.context("Failed to generate an unused link code")?;
+            (StatusCode::CREATED, link)
+        }
+    };
+
+    let short_url = links.short_url(&headers, &link.code);
+    let mut response_headers = HeaderMap::new();
+    response_headers.insert(
+        header::LOCATION,
+        HeaderValue::from_str(&short_url).context("Invalid short link")?,
+    );
+
+    Ok((
+        status,
+        response_headers,
+        Json(CreatedLink { link, short_url }),
+    ))
+}
+
+/// Statistics of a short link.
+#[utoipa::path(
+    get, path = "/links/{code}", tag = "links",
+    params(("code" = String, Path, description = "Link code")),
+    responses((status = 200, body = Link)),
+)]
+#[axum_macros::debug_handler]
+pub async fn get_link(
+    Path(code): Path<String>,
+    Extension(pool): Extension<PgPool>,
+) -> Result<Json<Link>> {
+    let link = sqlx::query_as!(Link, "SELECT * FROM links WHERE code = $1", code)
+        .fetch_optional(&pool)
+        .await?
+        .ok_or(Error::NotFound)?;
+
+    Ok(Json(link))
+}
+
+/// Redirect a short link to its permalink, counting the click.
+#[utoipa::path(
+    get, path = "/l/{code}", tag = "links",
+    params(("code" = String, Path, description = "Link code")),
+    responses(
+        (status = 307, description = "Redirect to the permalink", headers(("location" = String))),
+        (status = 410, description = "Link expired"),
+    ),
+)]
+#[axum_macros::debug_handler]
+pub async fn follow_link(
+    Path(code): Path<String>,
+    Extension(pool): Extension<PgPool>,
+) -> Result<Redirect> {
+    let url = sqlx::query_scalar!(
+        r#"
+        UPDATE links SET clicks = clicks + 1, last_clicked = now()
+        WHERE code = $1 AND (expires IS NULL OR expires > now())
+        RETURNING url
+        "#,
+        code
+    )
+    .fetch_optional(&pool)
+    .await?;
+
+    match url {
+        Some(url) => Ok(Redirect::temporary(&url)),
+        None => {
+            let expired = sqlx::query_scalar!(
+                r#"SELECT EXISTS (SELECT 1 FROM links WHERE code = $1) AS "exists!""#,
+                code
+            )
+            .fetch_one(&pool)
+            .await?;
+            if expired {
+                Err(Error::Api(StatusCode::GONE, "Link expired."))
+            } else {
+                Err(Error::NotFound)
+            }
+        }
+    }
+}
+
+/// Serve a rendition of an image uploaded through [`upload_project_image`].
+///
+/// Not authenticated, as images are loaded by the browser from `img` elements and stylesheets.
+#[utoipa::path(
+    get, path = "/projects/{id}/image", tag = "assets",
+    params(("id" = Uuid, Path, description = "Project id"), ImageQuery),
+    responses((status = 200, description = "WebP image", content_type = "image/webp")),
+)]
+#[axum_macros::debug_handler]
+pub async fn get_project_image(
+    Path(id): Path<Uuid>,
+    Query(query): Query<ImageQuery>,
+    Extension(pool): Extension<PgPool>,
+    Extension(config): Extension<Arc<Config>>,
+    Extension(client): Extension<Client>,
+) -> Result<Response> {
+    let bucket = config.s3.projects_s3_bucket.clone();
+
+    let image = sqlx::query_scalar!(
+        r#"SELECT project->>'image' AS image FROM projects WHERE id = $1"#,
+        id
+    )
+    .fetch_optional(&pool)
+    .await?
+    .flatten()
+    .filter(|image| is_managed_image(image))
+    .ok_or(Error::NotFound)?;
+
+    let object = client
+        .get_object()
+        .bucket(&bucket)
+        .key(image_object_key(&image, query.size))
+        .send()
+        .await
+        .context("Failed to get image")?;
+    let content = object
+        .body
+        .collect()
+        .await
+        .context("Failed to read image")?
+        .into_bytes();
+
+    Ok((
+        [
+            (header::CONTENT_TYPE, "image/webp"),
+            (header::CACHE_CONTROL, "public, max-age=3600"),
+        ],
+        content,
+    )
+        .into_response())
+}
+
+/// Serve the thumbnail of the geometries of a project, rendering it if it is not cached yet.
+///
+/// Not authenticated, like [`get_project_image`].
+#[utoipa::path(
+    get, path = "/projects/{id}/thumbnail", tag = "assets",
+    params(("id" = Uuid, Path, description = "Project id")),
+    responses((status = 200, description = "SVG image", content_type = "image/svg+xml")),
+)]
+#[axum_macros::debug_handler]
+pub async fn get_project_thumbnail(
+    Path(id): Path<Uuid>,
+    Extension(pool): Extension<PgPool>,
+    Extension(config): Extension<Arc<Config>>,
+    Extension(client): Extension<Client>,
+) -> Result<Response> {
+    let bucket = config.s3.projects_s3_bucket.clone();
+    let key = thumbnail_key(id);
+
+    let content = match client.get_object().bucket(&bucket).key(&key).send().await {
+        Ok(object) => object
+            .body
+            .collect()
+            .await
+            .context("Failed to read thumbnail")?
+            .into_bytes()
+            .to_vec(),
+        Err(e) if e.as_service_error().is_some_and(|e| e.is_no_such_key()) => {
+            let geometries = sqlx::query_scalar!(
+                r#"
+                SELECT COALESCE(project->'geometries', '[]') AS "geometries!: sqlx::types::Json<Vec<Geometry>>"
+                FROM projects WHERE id = $1
+                "#,
+                id
+            )
+            .fetch_optional(&pool)
+            .await?
+            .ok_or(Error::NotFound)?;
+            let content = render_thumbnail(&geometries).into_bytes();
             client
-                .delete_object()
+                .put_object()
                 .bucket(&bucket)
-                .key(&temp_key)
+                .key(&key)
+                .content_type("image/svg+xml")
+                .body(content.clone().into())
                 .send()
                 .await
-                .unwrap();
+                .context("Failed to cache thumbnail")?;
+            content
         }
-    }
+        Err(e) => {
+            return Err(anyhow::Error::from(e)
+                .context("Failed to get thumbnail")
+                .into())
+        }
+    };
+
+    Ok((
+        [
+            (header::CONTENT_TYPE, "image/svg+xml"),
+            // Short lived, as the thumbnail changes with the geometries
+            (header::CACHE_CONTROL, "public, max-age=300"),
+        ],
+        content,
+    )
+        .into_response())
 }
 
-async fn delete_assets(client: Client, project_assets: &Vec<Asset>) {
-    let bucket = std::env::var("PROJECTS_S3_BUCKET").unwrap();
-    for asset in project_assets {
-        let permanent_key = format!("assets/saved/{}", asset.key);
+#[utoipa::path(
+    post, path = "/projects/upload_asset", tag = "assets",
+    request_body(content = FileUpload, content_type = "multipart/form-data"),
+    responses((status = 200, body = UploadResponse)),
+    security(("bearer" = [])),
+)]
+pub async fn upload_asset(
+    Extension(pool): Extension<PgPool>,
+    Extension(config): Extension<Arc<Config>>,
+    Extension(client): Extension<Client>,
+    Extension(quotas): Extension<Quotas>,
+    Extension(scanner): Extension<Arc<dyn Scanner>>,
+    claims: Claims,
+    mut multipart: Multipart,
+) -> Result<Json<UploadResponse>> {
+    let bucket = config.s3.projects_s3_bucket.clone();
+    while let Some(field) = multipart
+        .next_field()
+        .await
+        .map_err(|_| Error::Api(StatusCode::BAD_REQUEST, "Invalid multipart body."))?
+    {
+        if field.name() == Some("file") {
+            let bytes = field
+                .bytes()
+                .await
+                .map_err(|_| Error::Api(StatusCode::BAD_REQUEST, "Invalid multipart body."))?;
 
-        // Check if the file exists in the destination directory
-        let destination_exists = client
-            .head_object()
-            .bucket(&bucket)
-            .key(&permanent_key)
-            .send()
-            .await
-            .is_ok();
+            // Identical files share the same key, and thus the same saved object
+            let key = asset_key(&bytes);
+            let size = bytes.len() as i64;
+            let mut conn = pool.acquire().await?;
+            quotas.check_upload(&mut conn, &claims.email, size).await?;
 
-        if destination_exists {
+            let scan = scanner
+                .scan(&bytes)
+                .await
+                .context("Failed to scan upload")?;
+            let prefix = match &scan {
+                ScanResult::Infected(signature) => {
+                    tracing::warn!(
+                        "Quarantining asset {} uploaded by {}: {}",
+                        key,
+                        claims.email,
+                        signature
+                    );
+                    "assets/quarantine"
+                }
+                ScanResult::Clean | ScanResult::Skipped => "assets/temp",
+            };
+            if !matches!(scan, ScanResult::Infected(_)) {
+                // Recorded right away, so that the quota applies before the asset is saved, and
+                // before the upload, so that an expiring earlier upload keeps the object
+                record_asset_size(&mut conn, &key, size).await?;
+                record_pending_upload(&mut conn, &claims.email, &key, size).await?;
+            }
             client
-                .delete_object()
+                .put_object()
                 .bucket(&bucket)
-                .key(&permanent_key)
+                .key(format!("{}/{}", prefix, key))
+                .body(bytes.into())
                 .send()
                 .await
-                .unwrap();
+                .context("Failed to upload object")?;
+            record_scan(&mut conn, &key, &scan).await?;
+            if let ScanResult::Infected(_) = scan {
+                return Err(Error::Api(
+                    StatusCode::UNPROCESSABLE_ENTITY,
+                    "Asset rejected by the virus scanner.",
+                ));
+            }
+
+            return Ok(Json(UploadResponse {
+                key,
+                scan_status: ScanStatus::from(&scan),
+            }));
         }
     }
+
+    Err(Error::Api(StatusCode::BAD_REQUEST, "Missing file."))
 }
 
-fn generate_asset_name() -> String {
-    let rand_string: String = rand::thread_rng()
-        .sample_iter(&Alphanumeric)
-        .take(40)
-        .map(char::from)
-        .collect();
-    format!("{}_{}.kml", Utc::now().timestamp(), rand_string)
+async fn fetch_viewable_project(pool: &PgPool, id: Uuid, claims: &Claims) -> Result<Project> {
+    let project: Project = sqlx::query_scalar!(
+        r#"SELECT project as "project: sqlx::types::Json<Project>" FROM projects WHERE id = $1"#,
+        id
+    )
+    .fetch_optional(pool)
+    .await?
+    .ok_or(Error::NotFound)?
+    .0;
+    if !project.is_viewable_by(&claims.email.to_lowercase()) {
+        return Err(Error::Forbidden);
+    }
+    Ok(project)
+}
+
+/// Lock a project editable by the user and return its views.
+async fn lock_project_views(
+    conn: &mut sqlx::PgConnection,
+    id: Uuid,
+    claims: &Claims,
+) -> Result<Vec<View>> {
+    let project: Project = sqlx::query_scalar!(
+        r#"SELECT project as "project: sqlx::types::Json<Project>" FROM projects WHERE id = $1 FOR UPDATE"#,
+        id
+    )
+    .fetch_optional(conn)
+    .await?
+    .ok_or(Error::NotFound)?
+    .0;
+    if !project.is_editable_by(&claims.email.to_lowercase()) {
+        return Err(Error::Forbidden);
+    }
+    Ok(project.views)
+}
+
+async fn save_views(conn: &mut sqlx::PgConnection, id: Uuid, views: &[View]) -> Result<()> {
+    sqlx::query!(
+        r#"
+        UPDATE projects
+        SET project = project || jsonb_build_object('views', $2::jsonb, 'modified', $3::jsonb)
+        WHERE id = $1
+        "#,
+        id,
+        sqlx::types::Json(views) as _,
+        sqlx::types::Json(Utc::now()) as _
+    )
+    .execute(conn)
+    .await?;
+    Ok(())
 }
@@ -0,0 +1,143 @@
+use aws_sdk_s3::Client;
+use serde::Serialize;
+use sqlx::PgPool;
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::time::{Duration, Instant};
+use utoipa::ToSchema;
+
+use crate::auth::check_key_set;
+
+/// Version of the API, set at build time
+pub const VERSION: &str = match option_env!("APP_VERSION") {
+    Some(version) => version,
+    None => env!("CARGO_PKG_VERSION"),
+};
+
+/// Commit the API was built from, see `build.rs`
+pub const GIT_COMMIT: &str = env!("GIT_COMMIT");
+
+/// Time after which a dependency is reported down
+const CHECK_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Dependencies checked by the readiness probe.
+#[derive(Clone)]
+pub struct Health {
+    pool: PgPool,
+    client: Client,
+    bucket: String,
+}
+
+#[derive(Serialize, ToSchema, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum HealthStatus {
+    Up,
+    Down,
+}
+
+/// Status of the API and of its dependencies.
+#[derive(Serialize, ToSchema)]
+pub struct HealthReport {
+    pub status: HealthStatus,
+    pub version: &'static str,
+    pub commit: &'static str,
+    /// Status of every dependency, by name
+    pub checks: BTreeMap<&'static str, Check>,
+}
+
+/// Status of a dependency.
+///
+/// The details of the dependency, such as the reason it is down, are only logged.
+#[derive(Serialize, ToSchema)]
+pub struct Check {
+    pub status: HealthStatus,
+    /// Duration of the check
+    pub latency_ms: u64,
+}
+
+impl HealthReport {
+    fn new(checks: BTreeMap<&'static str, Check>) -> Self {
+        let status = if checks.values().all(|c| c.status == HealthStatus::Up) {
+            HealthStatus::Up
+        } else {
+            HealthStatus::Down
+        };
+        Self {
+            status,
+            version: VERSION,
+            commit: GIT_COMMIT,
+            checks,
+        }
+    }
+
+    /// Report of a running process, without checking its dependencies.
+    pub fn live() -> Self {
+        Self::new(BTreeMap::new())
+    }
+}
+
+impl Health {
+    pub fn new(pool: PgPool, client: Client, bucket: String) -> Self {
+        Self {
+            pool,
+            client,
+            bucket,
+        }
+    }
+
+    /// Check the database, the bucket of the projects and the keys validating the tokens.
+    pub async fn ready(&self) -> HealthReport {
+        let (postgres, s3) = tokio::join!(
+            check("postgres", async {
+                sqlx::query("SELECT 1").execute(&self.pool).await?;
+                Ok(None)
+            }),
+            check("s3", async {
+                self.client
+                    .head_bucket()
+                    .bucket(&self.bucket)
+                    .send()
+                    .await?;
+                Ok(Some(self.bucket.clone()))
+            }),
+        );
+        let jwks = check("jwks", async {
+            let (keys, fetched) = check_key_set().map_err(anyhow::Error::msg)?;
+            Ok(Some(format!(
+                "{keys} keys fetched at {}",
+                fetched.to_rfc3339()
+            )))
+        })
+        .await;
+
+        HealthReport::new(BTreeMap::from([
+            ("postgres", postgres),
+            ("s3", s3),
+            ("jwks", jwks),
+        ]))
+    }
+}
+
+/// Check a dependency, logging its details or the reason it is down.
+async fn check(name: &str, future: impl Future<Output = anyhow::Result<Option<String>>>) -> Check {
+    let start = Instant::now();
+    let result = tokio::time::timeout(CHECK_TIMEOUT, future).await;
+    let latency_ms = start.elapsed().as_millis() as u64;
+    let status = match result {
+        Ok(Ok(details)) => {
+            if let Some(details) = details {
+                tracing::debug!("Health check of {} passed: {}", name, details);
+            }
+            HealthStatus::Up
+        }
+        Ok(Err(e)) => {
+            tracing::warn!("Health check of {} failed: {:?}", name, e);
+            HealthStatus::Down
+        }
+        Err(_) => {
+            tracing::warn!("Health check of {} timed out", name);
+            HealthStatus::Down
+        }
+    };
+    Check { status, latency_ms }
+}
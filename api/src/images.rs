@@ -0,0 +1,205 @@
+use std::io::Cursor;
+
+use axum::http::StatusCode;
+use image::codecs::webp::WebPEncoder;
+use image::imageops::FilterType;
+use image::{DynamicImage, ImageDecoder, ImageFormat, ImageReader, Limits};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use utoipa::ToSchema;
+
+use crate::{Error, Result};
+
+/// Prefix of the project images stored in the bucket
+const IMAGE_PREFIX: &str = "images/";
+/// Upper bound of the width and height of an uploaded image
+const MAX_DIMENSION: u32 = 4096;
+/// Upper bound of the memory allocated to decode an image, enough for 16-bit RGBA pixels
+const MAX_ALLOC: u64 = 4 * 2 * MAX_DIMENSION as u64 * MAX_DIMENSION as u64;
+
+/// Rendition of a project image.
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum ImageSize {
+    /// Square image shown in lists
+    Thumbnail,
+    /// Wide image shown on the project cards
+    #[default]
+    Card,
+}
+
+impl ImageSize {
+    pub const ALL: [Self; 2] = [Self::Thumbnail, Self::Card];
+
+    fn dimensions(self) -> (u32, u32) {
+        match self {
+            Self::Thumbnail => (256, 256),
+            Self::Card => (800, 450),
+        }
+    }
+
+    fn suffix(self) -> &'static str {
+        match self {
+            Self::Thumbnail => "thumbnail",
+            Self::Card => "card",
+        }
+    }
+}
+
+/// Whether `image` is a key of an image uploaded through the API, rather than an external URL.
+pub fn is_managed_image(image: &str) -> bool {
+    image.starts_with(IMAGE_PREFIX)
+}
+
+/// Bucket key of a rendition of a managed image.
+pub fn image_object_key(image: &str, size: ImageSize) -> String {
+    format!("{}-{}.webp", image, size.suffix())
+}
+
+/// An uploaded image resized into all renditions.
+pub struct ProcessedImage {
+    /// Managed key, derived from the uploaded content
+    pub key: String,
+    pub renditions: Vec<(ImageSize, Vec<u8>)>,
+}
+
+/// Decode a PNG, JPEG or WebP image and encode its renditions as WebP.
+///
+/// Only the pixels are re-encoded, which drops EXIF and other metadata.
+pub fn process_image(content: &[u8]) -> Result<ProcessedImage> {
+    let image = decode(content)?;
+    let key = format!("{}{:x}", IMAGE_PREFIX, Sha256::digest(content));
+
+    let renditions = ImageSize::ALL
+        .into_iter()
+        .map(|size| {
+            let (width, height) = size.dimensions();
+            let resized = image.resize_to_fill(width, height, FilterType::Lanczos3);
+            Ok((size, encode(resized)?))
+        })
+        .collect::<Result<_>>()?;
+
+    Ok(ProcessedImage { key, renditions })
+}
+
+fn decode(content: &[u8]) -> Result<DynamicImage> {
+    let invalid = || Error::Api(StatusCode::BAD_REQUEST, "Invalid image.");
+
+    let mut reader = ImageReader::new(Cursor::new(content))
+        .with_guessed_format()
+        .map_err(|_| invalid())?;
+    if !matches!(
+        reader.format(),
+        Some(ImageFormat::Png | ImageFormat::Jpeg | ImageFormat::WebP)
+    ) {
+        return Err(Error::Api(
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            "Unsupported image, expected PNG, JPEG or WebP.",
+        ));
+    }
+    let mut limits = Limits::default();
+    limits.max_image_width = Some(MAX_DIMENSION);
+    limits.max_image_height = Some(MAX_DIMENSION);
+    limits.max_alloc = Some(MAX_ALLOC);
+    reader.limits(limits.clone());
+
+    let mut decoder = reader.into_decoder().map_err(|_| invalid())?;
+    limits
+        .reserve(decoder.total_bytes())
+        .map_err(|_| invalid())?;
+    // Cameras store photos as taken, with their rotation in the EXIF metadata
+    let orientation = decoder.orientation().map_err(|_| invalid())?;
+    let mut image = DynamicImage::from_decoder(decoder).map_err(|_| invalid())?;
+    image.apply_orientation(orientation);
+    Ok(image)
+}
+
+fn encode(image: DynamicImage) -> Result<Vec<u8>> {
+    let image = DynamicImage::ImageRgba8(image.into_rgba8());
+    let mut buffer = Vec::new();
+    image
+        .write_with_encoder(WebPEncoder::new_lossless(&mut buffer))
+        .map_err(anyhow::Error::from)?;
+    Ok(buffer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{ImageEncoder, RgbImage};
+
+    fn png(width: u32, height: u32) -> Vec<u8> {
+        let image = RgbImage::from_fn(width, height, |x, y| image::Rgb([x as u8, y as u8, 128]));
+        let mut buffer = Vec::new();
+        image::codecs::png::PngEncoder::new(&mut buffer)
+            .write_image(&image, width, height, image::ExtendedColorType::Rgb8)
+            .unwrap();
+        buffer
+    }
+
+    #[test]
+    fn resizes_into_renditions() {
+        let processed = process_image(&png(400, 100)).unwrap();
+
+        assert!(is_managed_image(&processed.key));
+        for (size, content) in &processed.renditions {
+            let image = image::load_from_memory_with_format(content, ImageFormat::WebP).unwrap();
+            assert_eq!((image.width(), image.height()), size.dimensions());
+        }
+        assert_eq!(process_image(&png(400, 100)).unwrap().key, processed.key);
+    }
+
+    #[test]
+    fn applies_the_exif_orientation() {
+        // Little endian TIFF with an orientation tag rotating the image by 90° clockwise
+        let exif = [
+            b"II*\0\x08\0\0\0\x01\0".as_slice(),
+            b"\x12\x01\x03\0\x01\0\0\0\x06\0\0\0",
+            b"\0\0\0\0",
+        ]
+        .concat();
+        let image = RgbImage::new(40, 10);
+        let mut jpeg = Vec::new();
+        let mut encoder = image::codecs::jpeg::JpegEncoder::new(&mut jpeg);
+        encoder.set_exif_metadata(exif).unwrap();
+        encoder
+            .write_image(&image, 40, 10, image::ExtendedColorType::Rgb8)
+            .unwrap();
+
+        let image = decode(&jpeg).unwrap();
+        assert_eq!((image.width(), image.height()), (10, 40));
+    }
+
+    #[test]
+    fn rejects_too_large_images() {
+        assert!(matches!(
+            process_image(&png(MAX_DIMENSION + 1, 1)),
+            Err(Error::Api(StatusCode::BAD_REQUEST, _))
+        ));
+    }
+
+    #[test]
+    fn rejects_other_content() {
+        let gif = b"GIF89a\x01\x00\x01\x00\x00\x00\x00;";
+        assert!(matches!(
+            process_image(gif),
+            Err(Error::Api(StatusCode::UNSUPPORTED_MEDIA_TYPE, _))
+        ));
+
+        let mut truncated = png(10, 10);
+        truncated.truncate(40);
+        assert!(matches!(
+            process_image(&truncated),
+            Err(Error::Api(StatusCode::BAD_REQUEST, _))
+        ));
+    }
+
+    #[test]
+    fn managed_image_keys() {
+        assert!(!is_managed_image("https://example.com/image.png"));
+        assert_eq!(
+            image_object_key("images/abc", ImageSize::Thumbnail),
+            "images/abc-thumbnail.webp"
+        );
+    }
+}
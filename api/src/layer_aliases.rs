@@ -0,0 +1,284 @@
+use std::collections::{HashMap, HashSet};
+
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use crate::handlers::{Project, View};
+use crate::views::{ViewLayer, ViewState};
+use crate::{Error, Result};
+
+/// Upper bound of the length of an alias chain, guarding against cycles
+const MAX_ALIAS_DEPTH: usize = 16;
+
+/// A former layer id and the id replacing it, `None` if the layer was retired.
+#[derive(Serialize, Deserialize, Clone, Debug, ToSchema)]
+pub struct LayerAlias {
+    pub alias: String,
+    pub layer: Option<String>,
+}
+
+/// Body of a layer alias update.
+#[derive(Deserialize, Debug, ToSchema)]
+pub struct LayerAliasTarget {
+    pub layer: Option<String>,
+}
+
+/// Options of a permalink check.
+#[derive(Deserialize, Debug, Default, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct PermalinkCheck {
+    /// Rewrite the permalinks instead of only reporting them
+    #[serde(default)]
+    pub apply: bool,
+    /// Current layer ids of the layer tree, to also report layers that are neither current
+    /// nor aliased
+    pub known_layers: Option<HashSet<String>>,
+}
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum LayerIssue {
+    Renamed,
+    Retired,
+    Unknown,
+}
+
+#[derive(Serialize, Debug, PartialEq, ToSchema)]
+pub struct BrokenLayer {
+    pub layer: String,
+    pub issue: LayerIssue,
+    pub replacement: Option<String>,
+}
+
+/// A view with broken layer references or an invalid permalink.
+#[derive(Serialize, Debug, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct BrokenView {
+    pub project_id: Uuid,
+    pub view_id: String,
+    pub view_title: String,
+    pub layers: Vec<BrokenLayer>,
+    /// Error of a permalink that cannot be parsed
+    pub error: Option<String>,
+    /// Whether the permalink was rewritten
+    pub rewritten: bool,
+}
+
+/// Registry of renamed and retired layers.
+#[derive(Debug, Default)]
+pub struct LayerAliases(HashMap<String, Option<String>>);
+
+impl FromIterator<LayerAlias> for LayerAliases {
+    fn from_iter<T: IntoIterator<Item = LayerAlias>>(aliases: T) -> Self {
+        Self(aliases.into_iter().map(|a| (a.alias, a.layer)).collect())
+    }
+}
+
+impl LayerAliases {
+    pub async fn load(pool: &PgPool) -> sqlx::Result<Self> {
+        let aliases = sqlx::query_as!(LayerAlias, "SELECT alias, layer FROM layer_aliases")
+            .fetch_all(pool)
+            .await?;
+        Ok(aliases.into_iter().collect())
+    }
+
+    /// Follow the aliases of a layer, `None` if it is current.
+    fn resolve(&self, layer: &str) -> Option<BrokenLayer> {
+        let mut current = self.0.get(layer)?;
+        for _ in 0..MAX_ALIAS_DEPTH {
+            match current {
+                Some(next) => match self.0.get(next) {
+                    Some(further) => current = further,
+                    None => break,
+                },
+                None => break,
+            }
+        }
+        Some(BrokenLayer {
+            layer: layer.to_owned(),
+            issue: match current {
+                Some(_) => LayerIssue::Renamed,
+                None => LayerIssue::Retired,
+            },
+            replacement: current.clone(),
+        })
+    }
+
+    /// Check the layers of a permalink, returning the broken layers and the rewritten
+    /// permalink if any layer was renamed or retired.
+    pub fn check(
+        &self,
+        permalink: &str,
+        known_layers: Option<&HashSet<String>>,
+    ) -> Result<(Vec<BrokenLayer>, Option<String>)> {
+        let mut state = ViewState::parse(permalink)?;
+        let mut broken = Vec::new();
+        let mut layers = Vec::with_capacity(state.layers.len());
+
+        for mut layer in state.layers.drain(..) {
+            match self.resolve(&layer.layer) {
+                Some(resolved) => {
+                    if let Some(replacement) = &resolved.replacement {
+                        // Drop duplicates created by merged layers
+                        if !layers.iter().any(|l: &ViewLayer| l.layer == *replacement) {
+                            layer.layer = replacement.clone();
+                            layers.push(layer);
+                        }
+                    }
+                    broken.push(resolved);
+                }
+                None => {
+                    if known_layers.is_some_and(|known| !known.contains(&layer.layer)) {
+                        broken.push(BrokenLayer {
+                            layer: layer.layer.clone(),
+                            issue: LayerIssue::Unknown,
+                            replacement: None,
+                        });
+                    }
+                    layers.push(layer);
+                }
+            }
+        }
+
+        let rewritten = broken
+            .iter()
+            .any(|b| b.issue != LayerIssue::Unknown)
+            .then(|| {
+                state.layers = layers;
+                // Keep the URL in front of the query string, if any
+                let prefix = permalink.split_once('?').map_or("", |(prefix, _)| prefix);
+                format!("{}{}", prefix, state.to_permalink())
+            });
+        Ok((broken, rewritten))
+    }
+}
+
+/// Check the permalinks of the views of all projects, rewriting them if `check.apply` is set.
+pub async fn check_permalinks(pool: &PgPool, check: &PermalinkCheck) -> Result<Vec<BrokenView>> {
+    let aliases = LayerAliases::load(pool).await?;
+    let ids = sqlx::query_scalar!(
+        "SELECT id FROM projects WHERE jsonb_array_length(COALESCE(project->'views', '[]')) > 0 ORDER BY id"
+    )
+    .fetch_all(pool)
+    .await?;
+
+    let mut report = Vec::new();
+    for id in ids {
+        let mut tx = pool.begin().await?;
+        let Some(project) = sqlx::query_scalar!(
+            r#"SELECT project as "project: sqlx::types::Json<Project>" FROM projects WHERE id = $1 FOR UPDATE"#,
+            id
+        )
+        .fetch_optional(&mut *tx)
+        .await?
+        else {
+            continue;
+        };
+
+        let mut views: Vec<View> = project.0.views;
+        let mut changed = false;
+        for view in &mut views {
+            let (layers, rewritten, error) =
+                match aliases.check(&view.permalink, check.known_layers.as_ref()) {
+                    Ok((layers, rewritten)) => (layers, rewritten, None),
+                    Err(Error::Api(_, message)) => (Vec::new(), None, Some(message.to_owned())),
+                    Err(e) => return Err(e),
+                };
+            if layers.is_empty() && error.is_none() {
+                continue;
+            }
+            let apply = check.apply && rewritten.is_some();
+            if let Some(permalink) = rewritten.filter(|_| check.apply) {
+                view.permalink = permalink;
+                changed = true;
+            }
+            report.push(BrokenView {
+                project_id: id,
+                view_id: view.id.clone(),
+                view_title: view.title.clone(),
+                layers,
+                error,
+                rewritten: apply,
+            });
+        }
+
+        if changed {
+            sqlx::query!(
+                "UPDATE projects SET project = jsonb_set(project, '{views}', $2) WHERE id = $1",
+                id,
+                sqlx::types::Json(&views) as _
+            )
+            .execute(&mut *tx)
+            .await?;
+        }
+        tx.commit().await?;
+    }
+
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn aliases() -> LayerAliases {
+        [
+            ("voxel_2023", Some("voxel_2024")),
+            ("voxel_2024", Some("voxel_2025")),
+            ("old_geology", None),
+            ("loop_a", Some("loop_b")),
+            ("loop_b", Some("loop_a")),
+        ]
+        .into_iter()
+        .map(|(alias, layer)| LayerAlias {
+            alias: alias.into(),
+            layer: layer.map(String::from),
+        })
+        .collect()
+    }
+
+    #[test]
+    fn rewrites_renamed_and_retired_layers() {
+        let permalink = "?lon=7.4&lat=46.9&elevation=1000&layers=voxel_2023,old_geology,boreholes\
+                         &layers_visibility=true,true,false&layers_transparency=0.5,0,0\
+                         &layers_timestamp=,,";
+
+        let (broken, rewritten) = aliases().check(permalink, None).unwrap();
+
+        assert_eq!(
+            broken,
+            vec![
+                BrokenLayer {
+                    layer: "voxel_2023".into(),
+                    issue: LayerIssue::Renamed,
+                    replacement: Some("voxel_2025".into()),
+                },
+                BrokenLayer {
+                    layer: "old_geology".into(),
+                    issue: LayerIssue::Retired,
+                    replacement: None,
+                },
+            ]
+        );
+        let state = ViewState::parse(&rewritten.unwrap()).unwrap();
+        let layers: Vec<_> = state.layers.iter().map(|l| l.layer.as_str()).collect();
+        assert_eq!(layers, ["voxel_2025", "boreholes"]);
+        assert_eq!(state.layers[0].opacity, 0.5);
+    }
+
+    #[test]
+    fn reports_unknown_layers_without_rewriting() {
+        let known: HashSet<String> = ["boreholes".to_owned()].into();
+        let (broken, rewritten) = aliases()
+            .check("?layers=boreholes,typo", Some(&known))
+            .unwrap();
+
+        assert_eq!(broken[0].issue, LayerIssue::Unknown);
+        assert_eq!(rewritten, None);
+
+        // Cycles do not loop forever
+        assert!(aliases().check("?layers=loop_a", None).is_ok());
+    }
+}
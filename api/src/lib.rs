@@ -1,80 +1,250 @@
 use axum::{
     extract::{DefaultBodyLimit, Extension},
-    http::{HeaderValue, Method},
+    http::HeaderName,
+    middleware,
+    routing::delete,
     routing::get,
     routing::post,
     routing::put,
     Router,
 };
-use clap::Parser;
-use hyper::header::{ACCEPT, AUTHORIZATION, CONTENT_TYPE};
+use hyper::header::{AUTHORIZATION, COOKIE};
 use sqlx::PgPool;
+use std::sync::Arc;
 use tower::ServiceBuilder;
-use tower_http::{cors::CorsLayer, trace::TraceLayer};
+use tower_http::request_id::{MakeRequestUuid, PropagateRequestIdLayer, SetRequestIdLayer};
+use tower_http::sensitive_headers::SetSensitiveRequestHeadersLayer;
+use tower_http::trace::TraceLayer;
+use utoipa_redoc::{Redoc, Servable};
 
-pub use config::Config;
+use asset_operations::AssetWorker;
+pub use config::{load_config, Config};
 pub use error::Error;
+pub use layer_aliases::{check_permalinks, PermalinkCheck};
+pub use shutdown::{signal as shutdown_signal, Workers};
+pub use spatial::index_geometry_extents;
 
+mod asset_operations;
 mod auth;
 mod config;
+mod cors;
 mod database;
+mod deprecation;
 mod error;
+mod geometry;
 mod handlers;
+mod health;
+mod images;
+mod layer_aliases;
+mod links;
+mod metrics;
+mod openapi;
+mod project_list;
+mod projects;
+mod quotas;
+mod rate_limit;
 mod s3;
+mod scanning;
+mod shutdown;
+mod spatial;
+mod tags;
+mod telemetry;
+mod thumbnails;
+mod v2;
+mod views;
 
 pub type Result<T, E = Error> = std::result::Result<T, E>;
 
-const CORS_ORIGINS: &[&str] = &[
-    "http://localhost:8000",
-    "https://api.dev-viewer.swissgeol.ch",
-    "https://api.int-viewer.swissgeol.ch",
-    "https://api.swissgeol.ch",
-    "https://review-viewer.swissgeol.ch",
-    "https://dev-viewer.swissgeol.ch",
-    "https://int-viewer.swissgeol.ch",
-    "https://viewer.swissgeol.ch",
-];
+const X_REQUEST_ID: HeaderName = HeaderName::from_static("x-request-id");
 
-pub async fn app(pool: PgPool) -> Router {
-    let aws_config = s3::S3::parse();
-    let aws_client = aws_config.create_client().await;
+/// Build the router of the API, spawning its background tasks on the given workers.
+pub async fn app(config: &Config, pool: PgPool, workers: &Workers) -> Router {
+    let recorder = metrics::recorder();
 
-    Router::new()
-        .route("/api/client-config", get(handlers::get_client_config))
-        .route("/api/health_check", get(handlers::health_check))
+    let aws_client = config.s3.create_client().await;
+    tracing::info!(s3 = ?config.s3, "created the S3 client");
+
+    // Execute the S3 operations recorded with project changes
+    let bucket = config.s3.projects_s3_bucket.clone();
+    let asset_worker = AssetWorker::new(pool.clone(), aws_client.clone(), bucket.clone());
+    let health = health::Health::new(pool.clone(), aws_client.clone(), bucket);
+    let worker = asset_worker.clone();
+    workers.spawn(|token| worker.run(token));
+
+    let quotas = config.quotas.clone();
+    let scanner = config.scanning.create_scanner();
+    let links = config.links.clone();
+
+    let projects = projects::Projects::new(pool.clone(), asset_worker.clone(), quotas.clone());
+    let deprecation = config.deprecation.clone();
+    let limits = rate_limit::Limiters::new(&config.rate_limits);
+
+    // The first version of the API, frozen and deprecated
+    let v1 = routes(&limits)
+        .route(
+            "/projects",
+            get(handlers::list_projects)
+                .layer(middleware::from_fn_with_state(
+                    limits.lists.clone(),
+                    rate_limit::limit,
+                ))
+                .post(handlers::create_project),
+        )
+        .route("/projects/duplicate", post(handlers::duplicate_project))
         .route(
-            "/api/projects",
-            get(handlers::list_projects).post(handlers::create_project),
+            "/projects/from-template/:id",
+            post(handlers::create_project_from_template),
         )
-        .route("/api/projects/duplicate", post(handlers::duplicate_project))
         .route(
-            "/api/projects/:id",
+            "/projects/:id",
             get(handlers::get_project)
                 .put(handlers::update_project)
                 .delete(handlers::delete_project),
         )
         .route(
-            "/api/projects/:id/geometries",
-            put(handlers::update_project_geometries),
+            "/projects/:id/duplicate",
+            post(handlers::duplicate_stored_project),
+        )
+        // Lists and uploads are charged to the API limit too, bounding all the requests of a client
+        .route_layer(middleware::from_fn_with_state(
+            limits.api.clone(),
+            rate_limit::limit,
+        ))
+        .layer(middleware::map_response_with_state(
+            deprecation,
+            deprecation::deprecate_v1,
+        ));
+
+    let v2 = routes(&limits)
+        .route(
+            "/projects",
+            get(v2::list_projects)
+                .layer(middleware::from_fn_with_state(
+                    limits.lists.clone(),
+                    rate_limit::limit,
+                ))
+                .post(v2::create_project),
+        )
+        .route(
+            "/projects/from-template/:id",
+            post(v2::create_project_from_template),
+        )
+        .route(
+            "/projects/:id",
+            get(v2::get_project)
+                .put(v2::update_project)
+                .delete(handlers::delete_project),
+        )
+        .route("/projects/:id/duplicate", post(v2::duplicate_project))
+        .route_layer(middleware::from_fn_with_state(
+            limits.api.clone(),
+            rate_limit::limit,
+        ));
+
+    Router::new()
+        .route("/api/client-config", get(handlers::get_client_config))
+        .route("/api/health_check", get(handlers::health_check))
+        .route("/api/health/live", get(handlers::health_live))
+        .route("/api/health/ready", get(handlers::health_ready))
+        .route("/api/openapi.json", get(handlers::get_openapi))
+        .merge(Redoc::with_url("/api/docs", openapi::openapi()))
+        .route(
+            "/l/:code",
+            get(handlers::follow_link).layer(middleware::from_fn_with_state(
+                limits.api,
+                rate_limit::limit,
+            )),
         )
-        .route("/api/projects/upload_asset", post(handlers::upload_asset))
+        .nest("/api/v2", v2)
+        .nest("/api", v1)
+        .route_layer(middleware::from_fn(metrics::track_requests))
+        .route_layer(middleware::from_fn(telemetry::record_route))
+        // Scraped from within the cluster, not exposed by the ingress
+        .route("/metrics", get(handlers::get_metrics))
         .layer(
             ServiceBuilder::new()
-                .layer(TraceLayer::new_for_http())
+                .layer(middleware::from_fn(telemetry::check_request_id))
+                .layer(SetRequestIdLayer::x_request_id(MakeRequestUuid))
+                .layer(SetSensitiveRequestHeadersLayer::new([
+                    AUTHORIZATION,
+                    COOKIE,
+                ]))
                 .layer(
-                    CorsLayer::new()
-                        .allow_credentials(true)
-                        .allow_methods([Method::GET, Method::POST, Method::PUT, Method::DELETE])
-                        .allow_origin(
-                            CORS_ORIGINS
-                                .iter()
-                                .map(|s| s.parse().expect("parse origin"))
-                                .collect::<Vec<HeaderValue>>(),
-                        )
-                        .allow_headers([AUTHORIZATION, ACCEPT, CONTENT_TYPE]),
+                    TraceLayer::new_for_http()
+                        .make_span_with(telemetry::request_span)
+                        .on_response(telemetry::log_response),
                 )
+                .layer(PropagateRequestIdLayer::x_request_id())
+                .layer(middleware::from_fn(telemetry::scope_request_id))
+                .layer(config.cors.layer())
+                .layer(Extension(Arc::new(config.clone())))
                 .layer(Extension(pool))
                 .layer(Extension(aws_client))
+                .layer(Extension(asset_worker))
+                .layer(Extension(quotas))
+                .layer(Extension(projects))
+                .layer(Extension(scanner))
+                .layer(Extension(links))
+                .layer(Extension(recorder))
+                .layer(Extension(health))
                 .layer(DefaultBodyLimit::max(2 * 1024 * 1024)), // 2 MB limit (default value). PROJECT_ASSET_MAX_SIZE should be updated on frontend after this value update
         )
 }
+
+/// Routes shared by the versions of the API, relative to their prefix.
+fn routes(limits: &rate_limit::Limiters) -> Router {
+    let uploads = middleware::from_fn_with_state(limits.uploads.clone(), rate_limit::limit);
+    Router::new()
+        .route(
+            "/projects/:id/image",
+            get(handlers::get_project_image)
+                .merge(post(handlers::upload_project_image).layer(uploads.clone())),
+        )
+        .route(
+            "/projects/:id/views",
+            get(handlers::list_views).post(handlers::create_view),
+        )
+        .route("/projects/:id/views/order", put(handlers::reorder_views))
+        .route(
+            "/projects/:id/views/:view_id",
+            get(handlers::get_view)
+                .put(handlers::update_view)
+                .delete(handlers::delete_view),
+        )
+        .route(
+            "/projects/:id/thumbnail",
+            get(handlers::get_project_thumbnail),
+        )
+        .route(
+            "/projects/:id/geometries",
+            put(handlers::update_project_geometries),
+        )
+        .route(
+            "/projects/upload_asset",
+            post(handlers::upload_asset).layer(uploads),
+        )
+        .route("/layer-aliases", get(handlers::list_layer_aliases))
+        .route(
+            "/layer-aliases/:alias",
+            put(handlers::set_layer_alias).delete(handlers::delete_layer_alias),
+        )
+        .route("/maintenance/permalinks", post(handlers::check_permalinks))
+        .route("/links", post(handlers::create_link))
+        .route("/links/:code", get(handlers::get_link))
+        .route("/me/usage", get(handlers::get_storage_usage))
+        .route(
+            "/quotas/:email",
+            put(handlers::set_storage_quota).delete(handlers::delete_storage_quota),
+        )
+        .route("/geometries/search", get(handlers::search_geometries))
+        .route(
+            "/tags",
+            get(handlers::list_tags).post(handlers::create_curated_tag),
+        )
+        .route("/tags/:name", delete(handlers::delete_curated_tag))
+        .route("/templates", get(handlers::list_templates))
+        .route(
+            "/templates/:id",
+            put(handlers::create_template).delete(handlers::delete_template),
+        )
+}
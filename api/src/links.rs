@@ -0,0 +1,141 @@
+use axum::http::{header, HeaderMap, StatusCode};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::FromRow;
+use url::Url;
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use crate::cors::OriginPattern;
+use crate::{Error, Result};
+
+/// Number of random bytes of a code, encoded as 8 characters
+const CODE_BYTES: usize = 6;
+/// Upper bound of the length of a shortened URL
+const MAX_URL_LENGTH: usize = 8 * 1024;
+
+/// Configuration of the permalink shortener
+#[derive(clap::Parser, Clone, Debug)]
+pub struct Links {
+    /// Public URL of the API, used to build the short links.
+    /// Derived from the `Host` and `X-Forwarded-Proto` request headers if omitted.
+    #[clap(long, env)]
+    pub public_api_url: Option<Url>,
+}
+
+impl Links {
+    /// Short URL of a code.
+    pub fn short_url(&self, headers: &HeaderMap, code: &str) -> String {
+        let base = match &self.public_api_url {
+            Some(url) => url.as_str().trim_end_matches('/').to_owned(),
+            None => {
+                let header = |name| headers.get(name).and_then(|v| v.to_str().ok());
+                let host = header(header::HOST).unwrap_or("localhost");
+                let proto =
+                    header(header::HeaderName::from_static("x-forwarded-proto")).unwrap_or("http");
+                format!("{proto}://{host}")
+            }
+        };
+        format!("{base}/l/{code}")
+    }
+}
+
+#[derive(Deserialize, Debug, ToSchema)]
+pub struct CreateLink {
+    pub url: String,
+    /// The link stops redirecting after this date
+    pub expires: Option<DateTime<Utc>>,
+}
+
+/// A short link with its click statistics.
+#[derive(Serialize, Debug, FromRow, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct Link {
+    pub code: String,
+    pub url: String,
+    pub created: DateTime<Utc>,
+    pub expires: Option<DateTime<Utc>>,
+    pub clicks: i64,
+    pub last_clicked: Option<DateTime<Utc>>,
+}
+
+#[derive(Serialize, Debug, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct CreatedLink {
+    #[serde(flatten)]
+    pub link: Link,
+    pub short_url: String,
+}
+
+/// Check that `url` is a permalink of a viewer served from one of the `allowed_origins`.
+pub fn validate_url(url: &str, allowed_origins: &[OriginPattern]) -> Result<Url> {
+    let invalid = || Error::Api(StatusCode::BAD_REQUEST, "Invalid URL.");
+    if url.len() > MAX_URL_LENGTH {
+        return Err(invalid());
+    }
+    let parsed = Url::parse(url).map_err(|_| invalid())?;
+    let origin = parsed.origin().ascii_serialization();
+    if !allowed_origins
+        .iter()
+        .any(|allowed| allowed.matches(&origin))
+    {
+        return Err(Error::Api(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "URL host is not allowed.",
+        ));
+    }
+    Ok(parsed)
+}
+
+pub fn generate_code() -> String {
+    URL_SAFE_NO_PAD.encode(&Uuid::new_v4().as_bytes()[..CODE_BYTES])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accepts_only_allowed_hosts() {
+        let origins = ["http://localhost:8000", "https://viewer.swissgeol.ch"]
+            .map(|origin| origin.parse::<OriginPattern>().unwrap());
+        assert!(validate_url("https://viewer.swissgeol.ch/?lon=7.4&lat=46.9", &origins).is_ok());
+        assert!(validate_url("http://localhost:8000/?layers=a", &origins).is_ok());
+
+        for url in [
+            "https://viewer.swissgeol.ch.example.com/",
+            "http://viewer.swissgeol.ch/",
+            "https://example.com/?https://viewer.swissgeol.ch/",
+            "javascript:alert(1)",
+            "not a url",
+        ] {
+            assert!(validate_url(url, &origins).is_err(), "{url}");
+        }
+    }
+
+    #[test]
+    fn builds_short_urls() {
+        let code = generate_code();
+        assert_eq!(code.len(), 8);
+
+        let mut headers = HeaderMap::new();
+        headers.insert(header::HOST, "api.swissgeol.ch".parse().unwrap());
+        headers.insert("x-forwarded-proto", "https".parse().unwrap());
+        let links = Links {
+            public_api_url: None,
+        };
+        assert_eq!(
+            links.short_url(&headers, "abc"),
+            "https://api.swissgeol.ch/l/abc"
+        );
+
+        let links = Links {
+            public_api_url: Some("http://localhost:8480/".parse().unwrap()),
+        };
+        assert_eq!(
+            links.short_url(&headers, "abc"),
+            "http://localhost:8480/l/abc"
+        );
+    }
+}
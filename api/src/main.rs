@@ -1,5 +1,8 @@
-use clap::Parser;
+use std::future::IntoFuture;
 use std::net::SocketAddr;
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant};
+use tokio_util::sync::CancellationToken;
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
@@ -10,27 +13,78 @@ async fn main() -> anyhow::Result<()> {
     if std::env::var_os("RUST_LOG").is_none() {
         std::env::set_var("RUST_LOG", "api=debug,tower_http=debug")
     }
-    tracing_subscriber::fmt::init();
 
-    // Panic if we can't parse configuration
-    let config = api::Config::parse();
+    // Exit with the missing or invalid settings if the configuration can't be loaded
+    let config = api::Config::load();
+
+    // Log, and export the traces until the end of main
+    let _traces = config.telemetry.init()?;
+    tracing::info!(?config, "starting");
 
     // Setup a database connection pool & run any pending migrations
     let pool = config.database.setup().await;
 
+    // Index the geometries of projects saved before spatial search was available
+    api::index_geometry_extents(&pool).await?;
+
+    // Background tasks, stopped on shutdown
+    let workers = api::Workers::default();
+
     // Initialize JSON Web Key Set (JWKS)
     config.auth.initialize().await?;
+    let auth = config.auth.clone();
+    workers.spawn(|token| auth.refresh_key_set(token));
 
     // Build our application
-    let app = api::app(pool).await;
+    let app = api::app(&config, pool.clone(), &workers).await;
 
-    // run our app with hyper
+    // run our app with hyper, until a termination signal
     let address = SocketAddr::from(([0, 0, 0, 0], config.app_port));
     tracing::debug!("listening on {}", address);
     let listener = tokio::net::TcpListener::bind(address).await.unwrap();
-    axum::serve(listener, app.into_make_service())
+    let timeout = Duration::from_secs(config.shutdown_timeout);
+    let stopping = CancellationToken::new();
+    let deadline = Arc::new(OnceLock::new());
+    let server = axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .with_graceful_shutdown({
+        let (stopping, deadline, workers) = (stopping.clone(), deadline.clone(), workers.clone());
+        async move {
+            api::shutdown_signal().await;
+            deadline.get_or_init(|| Instant::now() + timeout);
+            workers.cancel();
+            stopping.cancel();
+        }
+    });
+
+    // Stop accepting connections and let the in-flight requests finish, up to the timeout
+    tokio::select! {
+        result = server.into_future() => result?,
+        _ = async {
+            stopping.cancelled().await;
+            tokio::time::sleep(timeout).await;
+        } => tracing::warn!("Shutdown timeout elapsed, closing the remaining connections"),
+    }
+
+    // Let the workers finish their current work, committed or rolled back as a whole, within
+    // the same timeout
+    let remaining = || {
+        deadline.get().map_or(timeout, |deadline| {
+            deadline.saturating_duration_since(Instant::now())
+        })
+    };
+    if !workers.stop(remaining()).await {
+        tracing::warn!("Shutdown timeout elapsed, aborted the background tasks");
+    }
+    if tokio::time::timeout(remaining(), pool.close())
         .await
-        .unwrap();
+        .is_err()
+    {
+        tracing::warn!("Shutdown timeout elapsed, closing the database connections in use");
+    }
+    tracing::info!("Shut down");
 
     Ok(())
 }
@@ -0,0 +1,158 @@
+use ::metrics::{counter, describe_counter, describe_gauge, describe_histogram, gauge, histogram};
+use axum::extract::{MatchedPath, Request};
+use axum::middleware::Next;
+use axum::response::Response;
+use metrics_exporter_prometheus::{Matcher, PrometheusBuilder, PrometheusHandle};
+use once_cell::sync::OnceCell;
+use sqlx::PgPool;
+use std::time::{Duration, Instant};
+
+use crate::Result;
+
+/// Recorder of the metrics, installed once per process
+static RECORDER: OnceCell<PrometheusHandle> = OnceCell::new();
+
+/// Buckets of the latency histograms, in seconds
+const LATENCY_BUCKETS: &[f64] = &[
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// Install the Prometheus recorder of the metrics, or return the installed one.
+pub fn recorder() -> PrometheusHandle {
+    RECORDER
+        .get_or_init(|| {
+            let handle = PrometheusBuilder::new()
+                .set_buckets_for_metric(Matcher::Suffix("_seconds".to_owned()), LATENCY_BUCKETS)
+                .expect("Failed to set the latency buckets")
+                .install_recorder()
+                .expect("Failed to install the metrics recorder");
+            describe();
+
+            // Drain the histograms between scrapes
+            let upkeep = handle.clone();
+            std::thread::spawn(move || loop {
+                std::thread::sleep(Duration::from_secs(5));
+                upkeep.run_upkeep();
+            });
+
+            handle
+        })
+        .clone()
+}
+
+fn describe() {
+    describe_counter!("http_requests_total", "HTTP requests by route and status");
+    describe_histogram!(
+        "http_request_duration_seconds",
+        "Latency of the HTTP requests by route and status"
+    );
+    describe_counter!("s3_requests_total", "S3 calls by operation and outcome");
+    describe_histogram!(
+        "s3_request_duration_seconds",
+        "Latency of the S3 calls by operation and outcome"
+    );
+    describe_counter!(
+        "jwt_validation_failures_total",
+        "Rejected bearer tokens by reason"
+    );
+    describe_counter!(
+        "rate_limited_requests_total",
+        "Requests rejected by the rate limiter by group of routes"
+    );
+    describe_gauge!(
+        "db_pool_connections",
+        "Connections of the database pool by state"
+    );
+    describe_gauge!(
+        "db_pool_max_connections",
+        "Maximum size of the database pool"
+    );
+    describe_gauge!("projects", "Stored projects");
+    describe_gauge!("assets", "Assets referenced by the projects");
+    describe_gauge!(
+        "asset_bytes",
+        "Size of the assets referenced by the projects"
+    );
+    describe_gauge!(
+        "asset_operations",
+        "Asset operations waiting for the worker, by status"
+    );
+}
+
+/// Count the requests and their latency by matched route, method and status.
+///
+/// Added as a route layer, so that unmatched paths do not create new series.
+pub async fn track_requests(request: Request, next: Next) -> Response {
+    let start = Instant::now();
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|path| path.as_str().to_owned())
+        .unwrap_or_default();
+    let method = request.method().to_string();
+
+    let response = next.run(request).await;
+
+    let labels = [
+        ("route", route),
+        ("method", method),
+        ("status", response.status().as_u16().to_string()),
+    ];
+    counter!("http_requests_total", &labels).increment(1);
+    histogram!("http_request_duration_seconds", &labels).record(start.elapsed());
+    response
+}
+
+/// Count a rejected bearer token.
+pub fn jwt_validation_failure(reason: &'static str) {
+    counter!("jwt_validation_failures_total", "reason" => reason).increment(1);
+}
+
+/// Count a request rejected by the rate limiter of a group of routes.
+pub fn rate_limited(group: &'static str) {
+    counter!("rate_limited_requests_total", "group" => group).increment(1);
+}
+
+/// Count an S3 call, retries included, and its latency.
+pub fn s3_call(operation: &str, outcome: &'static str, duration: Duration) {
+    let labels = [
+        ("operation", operation.to_owned()),
+        ("outcome", outcome.to_owned()),
+    ];
+    counter!("s3_requests_total", &labels).increment(1);
+    histogram!("s3_request_duration_seconds", &labels).record(duration);
+}
+
+/// Sample the database pool and the stored projects and assets, before a scrape.
+pub async fn record_gauges(pool: &PgPool) -> Result<()> {
+    // Before acquiring a connection for the counts below
+    let size = pool.size() as usize;
+    let idle = pool.num_idle();
+    gauge!("db_pool_connections", "state" => "idle").set(idle as f64);
+    gauge!("db_pool_connections", "state" => "active").set(size.saturating_sub(idle) as f64);
+    gauge!("db_pool_max_connections").set(pool.options().get_max_connections() as f64);
+
+    let counts = sqlx::query!(
+        r#"
+        SELECT
+            (SELECT count(*) FROM projects) AS "projects!",
+            (SELECT count(DISTINCT key) FROM project_assets) AS "assets!",
+            (
+                SELECT coalesce(sum(size), 0)
+                FROM asset_sizes
+                WHERE key IN (SELECT key FROM project_assets)
+            )::bigint AS "asset_bytes!",
+            (SELECT count(*) FROM asset_operations WHERE status = 'pending') AS "pending!",
+            (SELECT count(*) FROM asset_operations WHERE status = 'failed') AS "failed!"
+        "#
+    )
+    .fetch_one(pool)
+    .await?;
+    gauge!("projects").set(counts.projects as f64);
+    gauge!("assets").set(counts.assets as f64);
+    gauge!("asset_bytes").set(counts.asset_bytes as f64);
+    gauge!("asset_operations", "status" => "pending").set(counts.pending as f64);
+    gauge!("asset_operations", "status" => "failed").set(counts.failed as f64);
+
+    Ok(())
+}
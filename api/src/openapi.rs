@@ -0,0 +1,219 @@
+use serde::Serialize;
+use utoipa::openapi::path::{Operation, PathItem};
+use utoipa::openapi::security::{HttpAuthScheme, HttpBuilder, SecurityScheme};
+use utoipa::openapi::Deprecated;
+use utoipa::openapi::{ContentBuilder, Ref, ResponseBuilder};
+use utoipa::{Modify, OpenApi, ToSchema};
+
+use crate::images::ImageSize;
+use crate::project_list::{ProjectRole, ProjectSort, SortOrder};
+use crate::{handlers, v2};
+
+/// OpenAPI document of the API, generated from the handlers and their types.
+///
+/// Both versions of the API are documented, the first one as deprecated. A copy is committed in
+/// `openapi.json`, see the test below to update it.
+pub fn openapi() -> utoipa::openapi::OpenApi {
+    let mut v1 = SharedApi::openapi().merge_from(V1Api::openapi());
+    deprecate(&mut v1);
+    let v2 = SharedApi::openapi().merge_from(V2Api::openapi());
+
+    let mut openapi = ApiDoc::openapi().nest("/api/v2", v2).nest("/api", v1);
+    Security.modify(&mut openapi);
+    Errors.modify(&mut openapi);
+    openapi
+}
+
+/// Unversioned routes.
+#[derive(OpenApi)]
+#[openapi(
+    info(
+        title = "swissgeol viewer API",
+        description = "Projects of the swissgeol viewer, their assets and short links",
+        license(name = "BSD-3-Clause"),
+    ),
+    paths(
+        handlers::get_client_config,
+        handlers::health_check,
+        handlers::health_live,
+        handlers::health_ready,
+        handlers::follow_link,
+    ),
+    components(schemas(ErrorBody, ImageSize, ProjectRole, ProjectSort, SortOrder)),
+    tags(
+        (name = "projects", description = "Projects and their geometries"),
+        (name = "views", description = "Saved views of a project"),
+        (name = "assets", description = "KML assets and project images"),
+        (name = "templates", description = "Projects usable as templates"),
+        (name = "tags", description = "Project tags"),
+        (name = "quotas", description = "Storage usage and quotas"),
+        (name = "links", description = "Short links to viewer permalinks"),
+        (name = "layer aliases", description = "Renamed layers and permalink maintenance"),
+        (name = "config", description = "Configuration and status"),
+    )
+)]
+struct ApiDoc;
+
+/// Routes shared by the versions of the API.
+#[derive(OpenApi)]
+#[openapi(paths(
+    handlers::upload_project_image,
+    handlers::get_project_image,
+    handlers::list_views,
+    handlers::create_view,
+    handlers::reorder_views,
+    handlers::get_view,
+    handlers::update_view,
+    handlers::delete_view,
+    handlers::get_project_thumbnail,
+    handlers::update_project_geometries,
+    handlers::upload_asset,
+    handlers::list_layer_aliases,
+    handlers::set_layer_alias,
+    handlers::delete_layer_alias,
+    handlers::check_permalinks,
+    handlers::create_link,
+    handlers::get_link,
+    handlers::get_storage_usage,
+    handlers::set_storage_quota,
+    handlers::delete_storage_quota,
+    handlers::search_geometries,
+    handlers::list_tags,
+    handlers::create_curated_tag,
+    handlers::delete_curated_tag,
+    handlers::list_templates,
+    handlers::create_template,
+    handlers::delete_template,
+))]
+struct SharedApi;
+
+#[derive(OpenApi)]
+#[openapi(paths(
+    handlers::list_projects,
+    handlers::create_project,
+    handlers::duplicate_project,
+    handlers::create_project_from_template,
+    handlers::get_project,
+    handlers::update_project,
+    handlers::delete_project,
+    handlers::duplicate_stored_project,
+))]
+struct V1Api;
+
+#[derive(OpenApi)]
+#[openapi(paths(
+    v2::list_projects,
+    v2::create_project,
+    v2::create_project_from_template,
+    v2::get_project,
+    v2::update_project,
+    handlers::delete_project,
+    v2::duplicate_project,
+))]
+struct V2Api;
+
+/// Body of every error response, see [`crate::Error`].
+#[derive(Serialize, ToSchema)]
+pub struct ErrorBody {
+    pub status: u16,
+    pub message: String,
+    /// Id of the request, as in its `X-Request-Id` header
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+}
+
+/// Multipart body of a file upload.
+#[derive(ToSchema)]
+#[allow(dead_code)]
+pub struct FileUpload {
+    #[schema(value_type = String, format = Binary)]
+    file: Vec<u8>,
+}
+
+/// Cognito ID token, sent as `Authorization: Bearer <token>`.
+struct Security;
+
+impl Modify for Security {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        let components = openapi.components.get_or_insert_with(Default::default);
+        components.add_security_scheme(
+            "bearer",
+            SecurityScheme::Http(
+                HttpBuilder::new()
+                    .scheme(HttpAuthScheme::Bearer)
+                    .bearer_format("JWT")
+                    .build(),
+            ),
+        );
+    }
+}
+
+/// Document the error body as the default response of every operation.
+struct Errors;
+
+impl Modify for Errors {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        let components = openapi.components.get_or_insert_with(Default::default);
+        components.responses.insert(
+            "Error".to_owned(),
+            ResponseBuilder::new()
+                .description("Error")
+                .content(
+                    "application/json",
+                    ContentBuilder::new()
+                        .schema(Some(Ref::from_schema_name("ErrorBody")))
+                        .build(),
+                )
+                .build()
+                .into(),
+        );
+
+        for operation in openapi.paths.paths.values_mut().flat_map(operations) {
+            operation.responses.responses.insert(
+                "default".to_owned(),
+                Ref::from_response_name("Error").into(),
+            );
+        }
+    }
+}
+
+fn operations(item: &mut PathItem) -> impl Iterator<Item = &mut Operation> {
+    [
+        &mut item.get,
+        &mut item.put,
+        &mut item.post,
+        &mut item.delete,
+    ]
+    .into_iter()
+    .flatten()
+}
+
+/// Mark the operations of the first version as deprecated, keeping their ids unique.
+fn deprecate(openapi: &mut utoipa::openapi::OpenApi) {
+    for operation in openapi.paths.paths.values_mut().flat_map(operations) {
+        operation.deprecated = Some(Deprecated::True);
+        operation.operation_id = operation.operation_id.take().map(|id| format!("v1_{id}"));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Set `UPDATE_OPENAPI=1` to write the current document instead.
+    #[test]
+    fn committed_spec_is_up_to_date() {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/openapi.json");
+        let spec = openapi().to_pretty_json().unwrap() + "\n";
+
+        if std::env::var_os("UPDATE_OPENAPI").is_some() {
+            std::fs::write(path, &spec).unwrap();
+            return;
+        }
+        let committed = std::fs::read_to_string(path).unwrap_or_default();
+        assert!(
+            committed == spec,
+            "openapi.json is out of date, update it with `UPDATE_OPENAPI=1 cargo test --lib openapi`"
+        );
+    }
+}
@@ -0,0 +1,352 @@
+use anyhow::Context;
+use axum::http::{HeaderMap, HeaderValue, StatusCode};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use chrono::DateTime;
+use serde::{Deserialize, Serialize};
+use sqlx::{PgPool, Postgres, QueryBuilder};
+use utoipa::{IntoParams, ToSchema};
+use uuid::Uuid;
+
+use crate::handlers::{Project, NEXT_CURSOR_HEADER, TOTAL_COUNT_HEADER};
+use crate::spatial::Extent;
+use crate::tags::normalize_tag;
+use crate::{Error, Result};
+
+/// Upper bound for the `limit` query parameter
+const MAX_LIMIT: i64 = 100;
+
+/// Query parameters of `GET /api/projects`.
+#[derive(Deserialize, Debug, Default, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct ProjectListQuery {
+    /// Case-insensitive text searched in title and description
+    pub q: Option<String>,
+    /// Only return projects in which the user has this role
+    pub role: Option<ProjectRole>,
+    /// Comma separated tags the projects must all have
+    pub tags: Option<String>,
+    #[serde(default)]
+    pub sort: ProjectSort,
+    /// Defaults to ascending for `title` and descending otherwise
+    pub order: Option<SortOrder>,
+    /// Page size, all projects are returned if omitted
+    pub limit: Option<i64>,
+    /// Opaque cursor returned in `X-Next-Cursor` by the previous page
+    pub cursor: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum ProjectRole {
+    Owned,
+    Editing,
+    Viewing,
+}
+
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum ProjectSort {
+    #[default]
+    Created,
+    Modified,
+    Title,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum SortOrder {
+    Asc,
+    Desc,
+}
+
+/// A page of projects.
+pub struct ProjectPage {
+    pub projects: Vec<Project>,
+    /// Number of projects matching the filters, across all pages
+    pub total: i64,
+    pub next_cursor: Option<String>,
+}
+
+impl ProjectPage {
+    /// Headers describing the page in the response.
+    pub fn headers(&self) -> Result<HeaderMap> {
+        let mut headers = HeaderMap::new();
+        headers.insert(TOTAL_COUNT_HEADER, self.total.into());
+        if let Some(cursor) = &self.next_cursor {
+            headers.insert(
+                NEXT_CURSOR_HEADER,
+                HeaderValue::from_str(cursor).context("Invalid cursor header")?,
+            );
+        }
+        Ok(headers)
+    }
+}
+
+/// Position after the last project of a page.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+struct Cursor {
+    key: String,
+    id: Uuid,
+}
+
+impl Cursor {
+    fn encode(&self) -> String {
+        URL_SAFE_NO_PAD.encode(serde_json::to_vec(self).expect("serialize cursor"))
+    }
+
+    /// Decode a cursor, checking that its key can be compared with the `sort` expression.
+    fn decode(cursor: &str, sort: ProjectSort) -> Result<Self> {
+        URL_SAFE_NO_PAD
+            .decode(cursor)
+            .ok()
+            .and_then(|bytes| serde_json::from_slice::<Self>(&bytes).ok())
+            .filter(|cursor| match sort {
+                ProjectSort::Title => true,
+                ProjectSort::Created | ProjectSort::Modified => {
+                    DateTime::parse_from_rfc3339(&cursor.key).is_ok()
+                }
+            })
+            .ok_or(Error::Api(StatusCode::BAD_REQUEST, "Invalid cursor."))
+    }
+}
+
+impl ProjectSort {
+    fn expression(self) -> &'static str {
+        match self {
+            Self::Created => "(project->>'created')::timestamptz",
+            Self::Modified => {
+                "COALESCE((project->>'modified')::timestamptz, (project->>'created')::timestamptz)"
+            }
+            Self::Title => "LOWER(project->>'title')",
+        }
+    }
+
+    /// Key of a project in the cursors, timestamps being formatted as RFC 3339.
+    fn key_expression(self) -> String {
+        match self {
+            Self::Title => format!("CAST({} AS text)", self.expression()),
+            Self::Created | Self::Modified => format!("to_json({}) #>> '{{}}'", self.expression()),
+        }
+    }
+
+    fn default_order(self) -> SortOrder {
+        match self {
+            Self::Title => SortOrder::Asc,
+            Self::Created | Self::Modified => SortOrder::Desc,
+        }
+    }
+}
+
+impl ProjectListQuery {
+    fn limit(&self) -> Result<Option<i64>> {
+        match self.limit {
+            Some(limit) if !(1..=MAX_LIMIT).contains(&limit) => Err(Error::Api(
+                StatusCode::BAD_REQUEST,
+                "Invalid limit, expected a value between 1 and 100.",
+            )),
+            limit => Ok(limit),
+        }
+    }
+
+    fn tags(&self) -> Result<Vec<String>> {
+        self.tags
+            .as_deref()
+            .map(|tags| tags.split(',').map(normalize_tag).collect())
+            .unwrap_or(Ok(Vec::new()))
+    }
+
+    /// Append the conditions selecting the projects visible to `email` matching the filters.
+    fn push_filters<'a>(
+        &'a self,
+        builder: &mut QueryBuilder<'a, Postgres>,
+        email: &'a str,
+        extent: Option<Extent>,
+        tags: &[String],
+    ) {
+        let owner = |builder: &mut QueryBuilder<'a, Postgres>| {
+            builder.push("LOWER(project->'owner'->>'email') = ");
+            builder.push_bind(email);
+        };
+        let member = |builder: &mut QueryBuilder<'a, Postgres>, role: &str| {
+            builder.push(format!(
+                "EXISTS (SELECT 1 FROM jsonb_array_elements(project->'{role}') AS member \
+                 WHERE LOWER(member->>'email') = "
+            ));
+            builder.push_bind(email);
+            builder.push(")");
+        };
+
+        builder.push(" WHERE (");
+        match self.role {
+            Some(ProjectRole::Owned) => owner(builder),
+            Some(ProjectRole::Editing) => member(builder, "editors"),
+            Some(ProjectRole::Viewing) => member(builder, "viewers"),
+            None => {
+                owner(builder);
+                builder.push(" OR ");
+                member(builder, "viewers");
+                builder.push(" OR ");
+                member(builder, "editors");
+            }
+        }
+        builder.push(")");
+
+        if let Some(q) = self.q.as_deref().map(str::trim).filter(|q| !q.is_empty()) {
+            let pattern = format!(
+                "%{}%",
+                q.replace('\\', "\\\\")
+                    .replace('%', "\\%")
+                    .replace('_', "\\_")
+            );
+            builder.push(" AND (project->>'title' ILIKE ");
+            builder.push_bind(pattern.clone());
+            builder.push(" OR project->>'description' ILIKE ");
+            builder.push_bind(pattern);
+            builder.push(")");
+        }
+
+        // Matches the expression of the GIN index, projects without tags are excluded as null
+        if !tags.is_empty() {
+            builder.push(" AND project->'tags' ?& ");
+            builder.push_bind(tags.to_vec());
+        }
+
+        if let Some(extent) = extent {
+            builder.push(
+                " AND EXISTS (SELECT 1 FROM geometry_extents \
+                 WHERE project_id = projects.id AND extent && box(point(",
+            );
+            builder.push_bind(extent.min_x);
+            builder.push(", ");
+            builder.push_bind(extent.min_y);
+            builder.push("), point(");
+            builder.push_bind(extent.max_x);
+            builder.push(", ");
+            builder.push_bind(extent.max_y);
+            builder.push(")))");
+        }
+    }
+}
+
+/// List the projects visible to `email`.
+pub async fn list_projects(
+    pool: &PgPool,
+    email: &str,
+    extent: Option<Extent>,
+    query: &ProjectListQuery,
+) -> Result<ProjectPage> {
+    let limit = query.limit()?;
+    let tags = query.tags()?;
+    let cursor = query
+        .cursor
+        .as_deref()
+        .map(|cursor| Cursor::decode(cursor, query.sort))
+        .transpose()?;
+    let sort = query.sort.expression();
+    let order = query.order.unwrap_or(query.sort.default_order());
+
+    let mut count = QueryBuilder::new("SELECT COUNT(*) FROM projects");
+    query.push_filters(&mut count, email, extent, &tags);
+    let total: i64 = count.build_query_scalar().fetch_one(pool).await?;
+
+    let mut select = QueryBuilder::new(format!(
+        "SELECT project, {} AS sort_key FROM projects",
+        query.sort.key_expression()
+    ));
+    query.push_filters(&mut select, email, extent, &tags);
+    if let Some(cursor) = &cursor {
+        let cast = match query.sort {
+            ProjectSort::Title => "::text",
+            ProjectSort::Created | ProjectSort::Modified => "::timestamptz",
+        };
+        let comparison = match order {
+            SortOrder::Asc => ">",
+            SortOrder::Desc => "<",
+        };
+        select.push(format!(" AND ({sort}, id) {comparison} ("));
+        select.push_bind(cursor.key.as_str());
+        select.push(format!("{cast}, "));
+        select.push_bind(cursor.id);
+        select.push(")");
+    }
+    let direction = match order {
+        SortOrder::Asc => "ASC",
+        SortOrder::Desc => "DESC",
+    };
+    select.push(format!(" ORDER BY {sort} {direction}, id {direction}"));
+    if let Some(limit) = limit {
+        // Fetch one more project to know whether there is a next page
+        select.push(" LIMIT ");
+        select.push_bind(limit + 1);
+    }
+
+    let mut rows: Vec<(sqlx::types::Json<Project>, String)> =
+        select.build_query_as().fetch_all(pool).await?;
+
+    let next_cursor = match limit {
+        Some(limit) if rows.len() as i64 > limit => {
+            rows.truncate(limit as usize);
+            rows.last().map(|(project, key)| {
+                Cursor {
+                    key: key.clone(),
+                    id: project.id,
+                }
+                .encode()
+            })
+        }
+        _ => None,
+    };
+    let projects = rows.into_iter().map(|(project, _)| project.0).collect();
+
+    Ok(ProjectPage {
+        projects,
+        total,
+        next_cursor,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cursor_round_trips() {
+        let cursor = Cursor {
+            key: "2024-03-01T12:00:00+00:00".into(),
+            id: Uuid::new_v4(),
+        };
+
+        assert_eq!(
+            Cursor::decode(&cursor.encode(), ProjectSort::Created).unwrap(),
+            cursor
+        );
+        assert!(Cursor::decode("not a cursor", ProjectSort::Created).is_err());
+
+        // Keys not matching the sort are rejected rather than failing in the query
+        let title = Cursor {
+            key: "geology".into(),
+            id: Uuid::new_v4(),
+        };
+        assert_eq!(
+            Cursor::decode(&title.encode(), ProjectSort::Title).unwrap(),
+            title
+        );
+        for sort in [ProjectSort::Created, ProjectSort::Modified] {
+            assert!(matches!(
+                Cursor::decode(&title.encode(), sort),
+                Err(Error::Api(StatusCode::BAD_REQUEST, _))
+            ));
+        }
+    }
+
+    #[test]
+    fn rejects_out_of_range_limit() {
+        for limit in [0, -1, MAX_LIMIT + 1] {
+            let query = ProjectListQuery {
+                limit: Some(limit),
+                ..Default::default()
+            };
+            assert!(query.limit().is_err(), "{limit}");
+        }
+    }
+}
@@ -0,0 +1,303 @@
+use chrono::Utc;
+use sqlx::{PgConnection, PgPool};
+use std::collections::HashSet;
+use uuid::Uuid;
+
+use crate::asset_operations::{set_asset_references, AssetOperation, AssetWorker};
+use crate::handlers::{Member, Project};
+use crate::images::is_managed_image;
+use crate::quotas::{used_storage, Quotas};
+use crate::scanning::apply_scan_statuses;
+use crate::spatial::save_geometry_extents;
+use crate::tags::normalize_tags;
+use crate::{Error, Result};
+
+/// Storage of the projects, shared by the versions of the API.
+///
+/// Keeps the geometry index, the asset references, the quotas and the asset operations
+/// consistent with the saved projects. Authorization is checked against the stored projects.
+#[derive(Clone)]
+pub struct Projects {
+    pool: PgPool,
+    assets: AssetWorker,
+    quotas: Quotas,
+}
+
+impl Projects {
+    pub fn new(pool: PgPool, assets: AssetWorker, quotas: Quotas) -> Self {
+        Self {
+            pool,
+            assets,
+            quotas,
+        }
+    }
+
+    pub async fn fetch(&self, id: Uuid) -> Result<Project> {
+        let project = sqlx::query_scalar!(
+            r#"SELECT project as "project: sqlx::types::Json<Project>" FROM projects WHERE id = $1"#,
+            id
+        )
+        .fetch_optional(&self.pool)
+        .await?
+        .ok_or(Error::NotFound)?;
+        Ok(project.0)
+    }
+
+    /// Create a project, saving its uploaded assets.
+    pub async fn create(&self, mut project: Project) -> Result<Project> {
+        project.tags = normalize_tags(&project.tags)?;
+        let operations: Vec<AssetOperation> = project
+            .assets
+            .iter()
+            .map(|a| AssetOperation::Save { key: a.key.clone() })
+            .collect();
+        self.insert(project, &operations).await
+    }
+
+    /// Insert a new project, index its geometries, reference its assets within the owner's quota
+    /// and record the asset operations it requires.
+    pub async fn insert(
+        &self,
+        mut project: Project,
+        operations: &[AssetOperation],
+    ) -> Result<Project> {
+        let mut tx = self.pool.begin().await?;
+        apply_scan_statuses(&mut tx, &mut project.assets).await?;
+        let (used_before, _) = used_storage(&mut tx, &project.owner.email).await?;
+        sqlx::query!(
+            "INSERT INTO projects (id, project) VALUES ($1, $2)",
+            &project.id,
+            sqlx::types::Json(&project) as _
+        )
+        .execute(&mut *tx)
+        .await?;
+        save_geometry_extents(&mut tx, project.id, &project.geometries).await?;
+        let keys: Vec<String> = project.assets.iter().map(|a| a.key.clone()).collect();
+        set_asset_references(&mut tx, project.id, &keys).await?;
+        self.quotas
+            .check_save(&mut tx, &project.owner.email, used_before)
+            .await?;
+        for operation in operations {
+            operation.enqueue(&mut tx).await?;
+        }
+        AssetOperation::RenderThumbnail {
+            project_id: project.id,
+        }
+        .enqueue(&mut tx)
+        .await?;
+        tx.commit().await?;
+        self.assets.notify();
+
+        Ok(project)
+    }
+
+    /// Replace a project editable by the user with the given email. Only the owner may transfer
+    /// the ownership.
+    ///
+    /// The id, creation date and origin of the stored project are kept.
+    pub async fn update(&self, id: Uuid, email: &str, mut project: Project) -> Result<Project> {
+        let email = email.to_lowercase();
+        let mut tx = self.pool.begin().await?;
+        let saved_project = lock(&mut tx, id).await?;
+        if !saved_project.is_editable_by(&email) {
+            return Err(Error::Forbidden);
+        }
+        if !project
+            .owner
+            .email
+            .eq_ignore_ascii_case(&saved_project.owner.email)
+            && saved_project.owner.email != email
+        {
+            return Err(Error::Forbidden);
+        }
+
+        let saved_project_keys: HashSet<_> =
+            saved_project.assets.iter().map(|a| a.key.clone()).collect();
+        let new_project_keys: Vec<_> = project.assets.iter().map(|a| a.key.clone()).collect();
+
+        apply_scan_statuses(&mut tx, &mut project.assets).await?;
+        let (used_before, _) = used_storage(&mut tx, &project.owner.email).await?;
+
+        // Save the added assets and delete the removed ones once no other project references them
+        let removed_keys = set_asset_references(&mut tx, id, &new_project_keys).await?;
+        for key in removed_keys {
+            AssetOperation::Delete { key }.enqueue(&mut tx).await?;
+        }
+        for key in new_project_keys
+            .into_iter()
+            .filter(|key| !saved_project_keys.contains(key))
+        {
+            AssetOperation::Save { key }.enqueue(&mut tx).await?;
+        }
+
+        if saved_project.geometries != project.geometries {
+            AssetOperation::RenderThumbnail { project_id: id }
+                .enqueue(&mut tx)
+                .await?;
+        }
+        if let Some(image) = saved_project.image.filter(|i| is_managed_image(i)) {
+            if project.image.as_ref() != Some(&image) {
+                AssetOperation::DeleteImage { key: image }
+                    .enqueue(&mut tx)
+                    .await?;
+            }
+        }
+
+        project.id = id;
+        project.created = saved_project.created;
+        project.tags = normalize_tags(&project.tags)?;
+        project.duplicated_from = saved_project.duplicated_from;
+        project.modified = Some(Utc::now());
+        sqlx::query!(
+            "UPDATE projects SET project = project || CAST( $2 as JSONB) WHERE id = $1",
+            id,
+            sqlx::types::Json(&project) as _
+        )
+        .execute(&mut *tx)
+        .await?;
+        save_geometry_extents(&mut tx, id, &project.geometries).await?;
+        self.quotas
+            .check_save(&mut tx, &project.owner.email, used_before)
+            .await?;
+        tx.commit().await?;
+        self.assets.notify();
+
+        Ok(project)
+    }
+
+    /// Delete a project owned by the user with the given email, along with its unshared assets.
+    pub async fn delete(&self, id: Uuid, email: &str) -> Result<()> {
+        let mut tx = self.pool.begin().await?;
+        let saved_project = lock(&mut tx, id).await?;
+        if saved_project.owner.email != email.to_lowercase() {
+            return Err(Error::Forbidden);
+        }
+
+        // Delete assets from bucket, unless they are shared with other projects.
+        // The references of the project are removed along with it.
+        for asset in &saved_project.assets {
+            AssetOperation::Delete {
+                key: asset.key.clone(),
+            }
+            .enqueue(&mut tx)
+            .await?;
+        }
+        if let Some(image) = saved_project.image.filter(|i| is_managed_image(i)) {
+            AssetOperation::DeleteImage { key: image }
+                .enqueue(&mut tx)
+                .await?;
+        }
+        AssetOperation::RenderThumbnail { project_id: id }
+            .enqueue(&mut tx)
+            .await?;
+
+        sqlx::query!("DELETE FROM projects WHERE id = $1", id)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+        self.assets.notify();
+
+        Ok(())
+    }
+
+    /// Copy a project viewable by the new owner, sharing its saved assets.
+    pub async fn duplicate(
+        &self,
+        id: Uuid,
+        owner: Member,
+        title: Option<String>,
+        include_members: bool,
+    ) -> Result<Project> {
+        let source = self.fetch(id).await?;
+        if !source.is_viewable_by(&owner.email) {
+            return Err(Error::Forbidden);
+        }
+
+        let (viewers, editors) = if include_members {
+            let others = |members: Vec<Member>| -> Vec<Member> {
+                members
+                    .into_iter()
+                    .filter(|m| m.email != owner.email)
+                    .collect()
+            };
+            (others(source.viewers), others(source.editors))
+        } else {
+            (Vec::new(), Vec::new())
+        };
+
+        let duplicate = Project {
+            id: Uuid::new_v4(),
+            title: title.unwrap_or(source.title),
+            description: source.description,
+            created: Utc::now(),
+            modified: None,
+            image: source.image,
+            color: source.color,
+            views: source.views,
+            assets: source.assets,
+            owner,
+            viewers,
+            editors,
+            geometries: source.geometries,
+            tags: source.tags,
+            duplicated_from: Some(source.id),
+        };
+
+        // The saved assets are shared with the source project
+        self.insert(duplicate, &[]).await
+    }
+
+    /// Create a project from a template.
+    pub async fn create_from_template(
+        &self,
+        id: Uuid,
+        owner: Member,
+        title: Option<String>,
+    ) -> Result<Project> {
+        let template: Project = sqlx::query_scalar!(
+            r#"
+            SELECT project AS "project!: sqlx::types::Json<Project>"
+            FROM projects
+            JOIN project_templates ON project_templates.project_id = projects.id
+            WHERE projects.id = $1
+            "#,
+            id
+        )
+        .fetch_optional(&self.pool)
+        .await?
+        .ok_or(Error::NotFound)?
+        .0;
+
+        let project = Project {
+            id: Uuid::new_v4(),
+            title: title.unwrap_or(template.title),
+            description: template.description,
+            created: Utc::now(),
+            modified: None,
+            image: template.image,
+            color: template.color,
+            views: template.views,
+            assets: template.assets,
+            owner,
+            viewers: Vec::new(),
+            editors: Vec::new(),
+            geometries: template.geometries,
+            tags: template.tags,
+            duplicated_from: Some(template.id),
+        };
+
+        // The saved assets are shared with the template
+        self.insert(project, &[]).await
+    }
+}
+
+async fn lock(conn: &mut PgConnection, id: Uuid) -> Result<Project> {
+    let project = sqlx::query_scalar!(
+        r#"SELECT project as "project: sqlx::types::Json<Project>" FROM projects WHERE id = $1 FOR UPDATE"#,
+        id
+    )
+    .fetch_optional(conn)
+    .await?
+    .ok_or(Error::NotFound)?;
+    Ok(project.0)
+}
@@ -0,0 +1,194 @@
+use axum::http::StatusCode;
+use serde::{Deserialize, Serialize};
+use sqlx::{Connection, PgConnection};
+use std::time::Duration;
+use utoipa::ToSchema;
+
+use crate::asset_operations::AssetOperation;
+use crate::{Error, Result};
+
+/// Time an uploaded asset not referenced by a project counts in the quota of its uploader,
+/// after which it is deleted
+pub const PENDING_UPLOAD_EXPIRY: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Storage quotas of the project owners.
+///
+/// The storage used by a user is the size of the distinct assets referenced by the projects
+/// they own. Assets shared by several projects of the same owner are only counted once.
+/// Uploaded assets not referenced by a project yet count for their uploader until they expire.
+#[derive(clap::Parser, Clone, Debug)]
+pub struct Quotas {
+    /// Default storage quota of a user in bytes
+    #[clap(long, env, default_value_t = 100 * 1024 * 1024)]
+    pub default_storage_quota: i64,
+}
+
+/// Storage used by a user.
+#[derive(Serialize, Debug, PartialEq, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct StorageUsage {
+    pub used_bytes: i64,
+    pub quota_bytes: i64,
+    pub asset_count: i64,
+}
+
+/// Storage quota of a user set by an administrator.
+#[derive(Serialize, Deserialize, Debug, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct StorageQuota {
+    pub quota_bytes: i64,
+}
+
+impl Quotas {
+    /// Quota of a user, falling back to the default quota.
+    pub async fn quota(&self, conn: &mut PgConnection, email: &str) -> sqlx::Result<i64> {
+        let quota = sqlx::query_scalar!(
+            "SELECT quota FROM storage_quotas WHERE email = $1",
+            email.to_lowercase()
+        )
+        .fetch_optional(conn)
+        .await?;
+        Ok(quota.unwrap_or(self.default_storage_quota))
+    }
+
+    pub async fn usage(&self, conn: &mut PgConnection, email: &str) -> sqlx::Result<StorageUsage> {
+        let (used_bytes, asset_count) = used_storage(&mut *conn, email).await?;
+        Ok(StorageUsage {
+            used_bytes,
+            quota_bytes: self.quota(conn, email).await?,
+            asset_count,
+        })
+    }
+
+    /// Check that uploading `size` bytes keeps the user within their quota.
+    pub async fn check_upload(
+        &self,
+        conn: &mut PgConnection,
+        email: &str,
+        size: i64,
+    ) -> Result<()> {
+        let usage = self.usage(conn, email).await?;
+        if exceeds(usage.used_bytes, usage.used_bytes + size, usage.quota_bytes) {
+            return Err(Error::Api(
+                StatusCode::PAYLOAD_TOO_LARGE,
+                "Storage quota exceeded.",
+            ));
+        }
+        Ok(())
+    }
+
+    /// Check, after a change to the projects of `email`, that the storage they used before
+    /// the change (`used_before`) did not grow beyond their quota.
+    pub async fn check_save(
+        &self,
+        conn: &mut PgConnection,
+        email: &str,
+        used_before: i64,
+    ) -> Result<()> {
+        let usage = self.usage(conn, email).await?;
+        if exceeds(used_before, usage.used_bytes, usage.quota_bytes) {
+            return Err(Error::Api(
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "Storage quota exceeded.",
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Whether a change from `before` to `after` bytes breaches `quota`.
+/// Users above a lowered quota may still remove assets.
+fn exceeds(before: i64, after: i64, quota: i64) -> bool {
+    after > before && after > quota
+}
+
+/// Bytes and number of distinct assets referenced by the projects owned by `email`.
+/// The bytes include the pending uploads of `email` not referenced by these projects.
+/// Assets whose size is not recorded yet are not counted in the bytes.
+pub async fn used_storage(conn: &mut PgConnection, email: &str) -> sqlx::Result<(i64, i64)> {
+    let usage = sqlx::query!(
+        r#"
+        WITH owned AS (
+            SELECT DISTINCT project_assets.key
+            FROM project_assets
+            JOIN projects ON projects.id = project_assets.project_id
+            WHERE LOWER(project->'owner'->>'email') = $1
+        )
+        SELECT
+            (
+                COALESCE((SELECT SUM(size) FROM owned JOIN asset_sizes USING (key)), 0)
+                + COALESCE((
+                    SELECT SUM(size)
+                    FROM pending_uploads
+                    WHERE email = $1
+                        AND uploaded > now() - make_interval(secs => $2)
+                        AND key NOT IN (SELECT key FROM owned)
+                ), 0)
+            )::bigint AS "bytes!",
+            (SELECT COUNT(*) FROM owned) AS "count!"
+        "#,
+        email.to_lowercase(),
+        PENDING_UPLOAD_EXPIRY.as_secs_f64()
+    )
+    .fetch_one(conn)
+    .await?;
+    Ok((usage.bytes, usage.count))
+}
+
+/// Record an asset uploaded by `email`, counted in their quota until a project references it,
+/// and schedule the deletion of its temporary object once it expires.
+pub async fn record_pending_upload(
+    conn: &mut PgConnection,
+    email: &str,
+    key: &str,
+    size: i64,
+) -> sqlx::Result<()> {
+    let mut tx = conn.begin().await?;
+    sqlx::query!(
+        r#"
+        INSERT INTO pending_uploads (email, key, size) VALUES ($1, $2, $3)
+        ON CONFLICT (email, key) DO UPDATE SET size = EXCLUDED.size, uploaded = now()
+        "#,
+        email.to_lowercase(),
+        key,
+        size
+    )
+    .execute(&mut *tx)
+    .await?;
+    AssetOperation::ExpireUpload {
+        key: key.to_owned(),
+    }
+    .enqueue_after(&mut tx, PENDING_UPLOAD_EXPIRY)
+    .await?;
+    tx.commit().await
+}
+
+/// Record the size of a saved asset.
+pub async fn record_asset_size(conn: &mut PgConnection, key: &str, size: i64) -> sqlx::Result<()> {
+    sqlx::query!(
+        r#"
+        INSERT INTO asset_sizes (key, size) VALUES ($1, $2)
+        ON CONFLICT (key) DO UPDATE SET size = EXCLUDED.size
+        "#,
+        key,
+        size
+    )
+    .execute(conn)
+    .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_growth_beyond_the_quota_is_rejected() {
+        assert!(!exceeds(0, 100, 100));
+        assert!(exceeds(0, 101, 100));
+        // Removing assets is allowed above a lowered quota
+        assert!(!exceeds(150, 120, 100));
+        assert!(!exceeds(150, 150, 100));
+        assert!(exceeds(150, 151, 100));
+    }
+}
@@ -0,0 +1,308 @@
+use axum::extract::{ConnectInfo, Request, State};
+use axum::http::{HeaderMap, HeaderName};
+use axum::middleware::Next;
+use axum::response::Response;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::auth::Claims;
+use crate::{Error, Result};
+
+/// Limits of the requests of a client, by group of routes.
+///
+/// Authenticated requests are limited per user, the others per client address.
+#[derive(clap::Parser, Clone, Debug)]
+pub struct RateLimits {
+    /// Requests allowed to the API, as `<requests>/<seconds>`
+    #[clap(long, env, default_value = "600/60")]
+    pub rate_limit_api: RateLimit,
+    /// Requests allowed to the lists of projects, which search and sort them
+    #[clap(long, env, default_value = "120/60")]
+    pub rate_limit_lists: RateLimit,
+    /// Uploads of assets and images allowed
+    #[clap(long, env, default_value = "30/60")]
+    pub rate_limit_uploads: RateLimit,
+    /// Header of the client address set by the trusted reverse proxy, e.g. `x-forwarded-for`.
+    /// The last address of the header is used, as the previous ones are set by the client.
+    /// The address of the connection is used if omitted, or if the header is missing or invalid
+    #[clap(long, env)]
+    pub rate_limit_proxy_header: Option<HeaderName>,
+}
+
+/// Token bucket of `requests` tokens, refilled over `period`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RateLimit {
+    pub requests: u32,
+    pub period: Duration,
+}
+
+impl FromStr for RateLimit {
+    type Err = String;
+
+    fn from_str(limit: &str) -> Result<Self, Self::Err> {
+        let invalid =
+            || format!("{limit} is not a rate limit, e.g. 600/60 for 600 requests a minute");
+        let (requests, seconds) = limit.split_once('/').ok_or_else(invalid)?;
+        let requests: u32 = requests.trim().parse().map_err(|_| invalid())?;
+        let seconds: u64 = seconds.trim().parse().map_err(|_| invalid())?;
+        if requests == 0 || seconds == 0 {
+            return Err(format!(
+                "{limit} must allow at least one request in a period of at least one second"
+            ));
+        }
+        Ok(Self {
+            requests,
+            period: Duration::from_secs(seconds),
+        })
+    }
+}
+
+impl RateLimit {
+    /// Tokens refilled per second.
+    fn rate(&self) -> f64 {
+        f64::from(self.requests) / self.period.as_secs_f64()
+    }
+}
+
+/// The limiters of the groups of routes.
+pub struct Limiters {
+    pub api: Limiter,
+    pub lists: Limiter,
+    pub uploads: Limiter,
+}
+
+impl Limiters {
+    pub fn new(limits: &RateLimits) -> Self {
+        let limiter = |group, limit| Limiter {
+            group,
+            limit,
+            proxy_header: limits.rate_limit_proxy_header.clone(),
+            buckets: Default::default(),
+        };
+        Self {
+            api: limiter("api", limits.rate_limit_api),
+            lists: limiter("lists", limits.rate_limit_lists),
+            uploads: limiter("uploads", limits.rate_limit_uploads),
+        }
+    }
+}
+
+/// Client a bucket of tokens belongs to.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+enum Client {
+    User(String),
+    Address(IpAddr),
+    Unknown,
+}
+
+/// Tokens left to a client.
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+#[derive(Default)]
+struct Buckets {
+    clients: HashMap<Client, Bucket>,
+    pruned: Option<Instant>,
+}
+
+/// Buckets of the clients of a group of routes.
+#[derive(Clone)]
+pub struct Limiter {
+    group: &'static str,
+    limit: RateLimit,
+    proxy_header: Option<HeaderName>,
+    buckets: Arc<Mutex<Buckets>>,
+}
+
+impl Limiter {
+    /// Take a token from the bucket of a client, or return the time until the next token.
+    fn acquire(&self, client: Client, now: Instant) -> Result<(), Duration> {
+        let capacity = f64::from(self.limit.requests);
+        let rate = self.limit.rate();
+        let mut buckets = self.buckets.lock().expect("rate limit lock poisoned");
+
+        // Forget the clients whose bucket is full again, once per period
+        if buckets
+            .pruned
+            .is_none_or(|pruned| now.duration_since(pruned) >= self.limit.period)
+        {
+            let period = self.limit.period;
+            buckets
+                .clients
+                .retain(|_, bucket| now.duration_since(bucket.updated) < period);
+            buckets.pruned = Some(now);
+        }
+
+        let bucket = buckets.clients.entry(client).or_insert(Bucket {
+            tokens: capacity,
+            updated: now,
+        });
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * rate).min(capacity);
+        bucket.updated = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / rate))
+        }
+    }
+
+    /// Address of the client, set by the trusted proxy or of the connection.
+    ///
+    /// Requests without a valid proxy header, e.g. not sent through the proxy, are limited by
+    /// the address of their connection rather than sharing the bucket of the unknown clients.
+    fn address(&self, headers: &HeaderMap, peer: Option<SocketAddr>) -> Option<IpAddr> {
+        self.proxy_header
+            .as_ref()
+            .and_then(|header| {
+                headers
+                    .get_all(header)
+                    .iter()
+                    .filter_map(|value| value.to_str().ok())
+                    .flat_map(|value| value.split(','))
+                    .last()
+                    .and_then(|address| address.trim().parse().ok())
+            })
+            .or_else(|| peer.map(|peer| peer.ip()))
+    }
+}
+
+/// Reject the requests of a client having exhausted its tokens with `429 Too Many Requests`.
+pub async fn limit(
+    State(limiter): State<Limiter>,
+    peer: Option<ConnectInfo<SocketAddr>>,
+    request: Request,
+    next: Next,
+) -> Result<Response> {
+    let (mut parts, body) = request.into_parts();
+    let client = match Claims::authenticate(&mut parts).await {
+        Some(claims) => Client::User(claims.email),
+        None => limiter
+            .address(&parts.headers, peer.map(|ConnectInfo(peer)| peer))
+            .map_or(Client::Unknown, Client::Address),
+    };
+
+    if let Err(retry_after) = limiter.acquire(client, Instant::now()) {
+        crate::metrics::rate_limited(limiter.group);
+        return Err(Error::TooManyRequests(retry_after));
+    }
+    Ok(next.run(Request::from_parts(parts, body)).await)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::Body;
+    use axum::http::{header, StatusCode};
+    use axum::routing::get;
+    use axum::{middleware, Router};
+    use clap::Parser;
+    use tower::ServiceExt;
+
+    fn limits(args: &[&str]) -> Limiters {
+        Limiters::new(&RateLimits::try_parse_from([&["api"], args].concat()).unwrap())
+    }
+
+    fn request(forwarded_for: &str) -> axum::http::Request<Body> {
+        axum::http::Request::builder()
+            .uri("/projects")
+            .header("x-forwarded-for", forwarded_for)
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    #[test]
+    fn rate_limits_are_parsed() {
+        assert_eq!(
+            "30/60".parse(),
+            Ok(RateLimit {
+                requests: 30,
+                period: Duration::from_secs(60)
+            })
+        );
+        for invalid in ["30", "0/60", "30/0", "-1/60", "30/1m"] {
+            assert!(invalid.parse::<RateLimit>().is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn buckets_are_refilled_over_the_period() {
+        let limiter = limits(&["--rate-limit-api=2/10"]).api;
+        let user = || Client::User("user@swissgeol.ch".to_owned());
+        let start = Instant::now();
+
+        assert_eq!(limiter.acquire(user(), start), Ok(()));
+        assert_eq!(limiter.acquire(user(), start), Ok(()));
+        assert_eq!(limiter.acquire(user(), start), Err(Duration::from_secs(5)));
+        assert_eq!(limiter.acquire(Client::Unknown, start), Ok(()));
+
+        let later = start + Duration::from_secs(5);
+        assert_eq!(limiter.acquire(user(), later), Ok(()));
+        assert!(limiter.acquire(user(), later).is_err());
+    }
+
+    #[test]
+    fn full_buckets_are_forgotten() {
+        let limiter = limits(&["--rate-limit-api=2/10"]).api;
+        let start = Instant::now();
+        limiter.acquire(Client::Unknown, start).unwrap();
+
+        let later = start + Duration::from_secs(10);
+        limiter
+            .acquire(Client::User("a@swissgeol.ch".to_owned()), later)
+            .unwrap();
+        let buckets = limiter.buckets.lock().unwrap();
+        assert_eq!(buckets.clients.len(), 1);
+    }
+
+    #[test]
+    fn address_is_taken_from_the_trusted_header() {
+        let peer = Some(SocketAddr::from(([10, 0, 0, 1], 43210)));
+        let mut headers = HeaderMap::new();
+        headers.insert("x-forwarded-for", "1.2.3.4, 5.6.7.8".parse().unwrap());
+
+        let direct = limits(&[]).api;
+        assert_eq!(direct.address(&headers, peer), Some([10, 0, 0, 1].into()));
+
+        let proxied = limits(&["--rate-limit-proxy-header=x-forwarded-for"]).api;
+        assert_eq!(proxied.address(&headers, peer), Some([5, 6, 7, 8].into()));
+        assert_eq!(
+            proxied.address(&HeaderMap::new(), peer),
+            Some([10, 0, 0, 1].into())
+        );
+        headers.insert("x-forwarded-for", "1.2.3.4, unknown".parse().unwrap());
+        assert_eq!(proxied.address(&headers, peer), Some([10, 0, 0, 1].into()));
+        assert_eq!(proxied.address(&HeaderMap::new(), None), None);
+    }
+
+    #[tokio::test]
+    async fn exhausted_clients_get_too_many_requests() {
+        let limiter = limits(&[
+            "--rate-limit-api=2/60",
+            "--rate-limit-proxy-header=x-forwarded-for",
+        ])
+        .api;
+        let app = Router::new()
+            .route("/projects", get(|| async { "[]" }))
+            .route_layer(middleware::from_fn_with_state(limiter, limit));
+
+        for _ in 0..2 {
+            let response = app.clone().oneshot(request("1.2.3.4")).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+        }
+        let response = app.clone().oneshot(request("1.2.3.4")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers()[header::RETRY_AFTER], "30");
+
+        // Other clients keep their tokens
+        let response = app.oneshot(request("5.6.7.8")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+}
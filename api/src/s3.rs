@@ -1,28 +1,54 @@
 use aws_config::{BehaviorVersion, Region};
-use aws_sdk_s3::config::Credentials;
+use aws_sdk_s3::config::interceptors::{
+    BeforeSerializationInterceptorContextRef, FinalizerInterceptorContextRef,
+};
+use aws_sdk_s3::config::{ConfigBag, Credentials, Intercept, RuntimeComponents};
 use aws_sdk_s3::{Client, Config};
+use aws_smithy_runtime_api::box_error::BoxError;
+use aws_smithy_runtime_api::client::orchestrator::Metadata;
+use aws_smithy_types::config_bag::{Storable, StoreReplace};
 use hyper::Uri;
+use std::time::Instant;
+use tracing::Span;
+
+use crate::telemetry::{s3_span, REDACTED};
 
 /// Configuration for AWS S3 Client
-#[derive(clap::Parser)]
+#[derive(clap::Parser, Clone)]
 pub struct S3 {
     /// The S3 bucket name
-    #[clap(env)]
+    #[clap(long, env)]
     pub s3_bucket: String,
+    /// The S3 bucket of the projects and their assets
+    #[clap(long, env)]
+    pub projects_s3_bucket: String,
     /// The S3 AWS access key id
-    #[clap(env, hide_env_values = true, default_value = "")]
+    #[clap(long, env, hide_env_values = true, default_value = "")]
     pub aws_access_key_id: String,
     /// The S3 AWS secret access key
-    #[clap(env, hide_env_values = true, default_value = "")]
+    #[clap(long, env, hide_env_values = true, default_value = "")]
     pub aws_secret_access_key: String,
     /// The S3 AWS region
-    #[clap(env, default_value = "eu-west-1")]
+    #[clap(long, env, default_value = "eu-west-1")]
     pub s3_aws_region: String,
     /// Optional S3 endpoint
-    #[clap(env)]
+    #[clap(long, env)]
     pub s3_endpoint: Option<Uri>,
 }
 
+impl std::fmt::Debug for S3 {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("S3")
+            .field("s3_bucket", &self.s3_bucket)
+            .field("projects_s3_bucket", &self.projects_s3_bucket)
+            .field("aws_access_key_id", &REDACTED)
+            .field("aws_secret_access_key", &REDACTED)
+            .field("s3_aws_region", &self.s3_aws_region)
+            .field("s3_endpoint", &self.s3_endpoint)
+            .finish()
+    }
+}
+
 #[inline(always)]
 fn behavior_version() -> BehaviorVersion {
     BehaviorVersion::latest()
@@ -44,6 +70,7 @@ impl S3 {
                 .region(Region::new(self.s3_aws_region.to_owned()))
                 .endpoint_url(endpoint.to_string())
                 .credentials_provider(creds)
+                .interceptor(Instrumentation)
                 .build();
 
             return Client::from_conf(config);
@@ -53,6 +80,74 @@ impl S3 {
             .region(Region::new(self.s3_aws_region.to_owned()))
             .load()
             .await;
-        Client::new(&aws_config)
+        let config = aws_sdk_s3::config::Builder::from(&aws_config)
+            .interceptor(Instrumentation)
+            .build();
+        Client::from_conf(config)
+    }
+}
+
+/// Interceptor of the S3 client recording every call, retries included, in a span and in the
+/// metrics.
+#[derive(Debug)]
+struct Instrumentation;
+
+/// An S3 call in progress
+#[derive(Debug, Clone)]
+struct Call {
+    operation: String,
+    span: Span,
+    start: Instant,
+}
+
+impl Storable for Call {
+    type Storer = StoreReplace<Self>;
+}
+
+impl Intercept for Instrumentation {
+    fn name(&self) -> &'static str {
+        "Instrumentation"
+    }
+
+    fn read_before_execution(
+        &self,
+        _context: &BeforeSerializationInterceptorContextRef<'_>,
+        cfg: &mut ConfigBag,
+    ) -> Result<(), BoxError> {
+        let operation = cfg
+            .load::<Metadata>()
+            .map(|metadata| metadata.name().to_owned())
+            .unwrap_or_default();
+        cfg.interceptor_state().store_put(Call {
+            span: s3_span(&operation),
+            operation,
+            start: Instant::now(),
+        });
+        Ok(())
+    }
+
+    fn read_after_execution(
+        &self,
+        context: &FinalizerInterceptorContextRef<'_>,
+        _runtime_components: &RuntimeComponents,
+        cfg: &mut ConfigBag,
+    ) -> Result<(), BoxError> {
+        let outcome = match context.output_or_error() {
+            Some(Ok(_)) => "success",
+            Some(Err(error)) if error.is_operation_error() => "service_error",
+            Some(Err(error)) if error.is_timeout_error() => "timeout",
+            Some(Err(error)) if error.is_connector_error() => "connection_error",
+            _ => "error",
+        };
+        if let Some(call) = cfg.load::<Call>() {
+            crate::metrics::s3_call(&call.operation, outcome, call.start.elapsed());
+            if let Some(Err(e)) = context.output_or_error() {
+                call.span.record("otel.status_code", "ERROR");
+                call.span.record("error", tracing::field::display(e));
+            }
+        }
+        // Close the span
+        cfg.interceptor_state().unset::<Call>();
+        Ok(())
     }
 }
@@ -0,0 +1,277 @@
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use anyhow::Context;
+use axum::async_trait;
+use axum::http::StatusCode;
+use serde::{Deserialize, Serialize};
+use sqlx::PgConnection;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::UnixStream;
+use utoipa::ToSchema;
+
+use crate::handlers::Asset;
+use crate::{Error, Result};
+
+/// Size of the chunks streamed to ClamAV
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Configuration of the scanner checking uploaded assets for malware
+#[derive(clap::Parser, Clone, Debug)]
+pub struct Scanning {
+    /// Path of the clamd socket, uploads are not scanned if omitted
+    #[clap(long, env)]
+    pub clamav_socket: Option<PathBuf>,
+}
+
+impl Scanning {
+    pub fn create_scanner(&self) -> Arc<dyn Scanner> {
+        match &self.clamav_socket {
+            Some(socket) => Arc::new(ClamAv {
+                socket: socket.clone(),
+            }),
+            None => Arc::new(NoScanner),
+        }
+    }
+}
+
+/// Outcome of a scan.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ScanResult {
+    Clean,
+    /// Infected, with the name of the detected signature
+    Infected(String),
+    /// The scanner does not check files
+    Skipped,
+}
+
+/// Scan status of an asset, as shown by the viewer.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub enum ScanStatus {
+    Clean,
+    Infected,
+    /// Uploaded without scanner or before scanning was introduced
+    #[default]
+    NotScanned,
+}
+
+impl ScanStatus {
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Clean => "clean",
+            Self::Infected => "infected",
+            Self::NotScanned => "not_scanned",
+        }
+    }
+
+    fn from_str(status: &str) -> Self {
+        match status {
+            "clean" => Self::Clean,
+            "infected" => Self::Infected,
+            _ => Self::NotScanned,
+        }
+    }
+}
+
+impl From<&ScanResult> for ScanStatus {
+    fn from(result: &ScanResult) -> Self {
+        match result {
+            ScanResult::Clean => Self::Clean,
+            ScanResult::Infected(_) => Self::Infected,
+            ScanResult::Skipped => Self::NotScanned,
+        }
+    }
+}
+
+#[async_trait]
+pub trait Scanner: Send + Sync {
+    async fn scan(&self, content: &[u8]) -> anyhow::Result<ScanResult>;
+}
+
+/// Scanner used when no virus scanner is configured.
+pub struct NoScanner;
+
+#[async_trait]
+impl Scanner for NoScanner {
+    async fn scan(&self, _content: &[u8]) -> anyhow::Result<ScanResult> {
+        Ok(ScanResult::Skipped)
+    }
+}
+
+/// ClamAV daemon reached over its local socket, using the `INSTREAM` command.
+pub struct ClamAv {
+    pub socket: PathBuf,
+}
+
+#[async_trait]
+impl Scanner for ClamAv {
+    async fn scan(&self, content: &[u8]) -> anyhow::Result<ScanResult> {
+        let mut stream = UnixStream::connect(&self.socket)
+            .await
+            .with_context(|| format!("Failed to connect to clamd at {:?}", self.socket))?;
+
+        stream.write_all(b"zINSTREAM\0").await?;
+        for chunk in content.chunks(CHUNK_SIZE) {
+            stream
+                .write_all(&(chunk.len() as u32).to_be_bytes())
+                .await?;
+            stream.write_all(chunk).await?;
+        }
+        stream.write_all(&0u32.to_be_bytes()).await?;
+
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).await?;
+        parse_clamd_response(&String::from_utf8_lossy(&response))
+    }
+}
+
+/// Parse a reply such as `stream: OK` or `stream: Eicar-Signature FOUND`.
+fn parse_clamd_response(response: &str) -> anyhow::Result<ScanResult> {
+    let reply = response.trim_end_matches(['\0', '\n']);
+    let result = reply
+        .strip_prefix("stream: ")
+        .with_context(|| format!("Unexpected clamd response `{reply}`"))?;
+    if result == "OK" {
+        Ok(ScanResult::Clean)
+    } else if let Some(signature) = result.strip_suffix(" FOUND") {
+        Ok(ScanResult::Infected(signature.to_owned()))
+    } else {
+        anyhow::bail!("clamd failed to scan: {result}")
+    }
+}
+
+/// Record the result of scanning an uploaded asset.
+pub async fn record_scan(conn: &mut PgConnection, key: &str, result: &ScanResult) -> Result<()> {
+    let signature = match result {
+        ScanResult::Infected(signature) => Some(signature.as_str()),
+        ScanResult::Clean | ScanResult::Skipped => None,
+    };
+    sqlx::query!(
+        r#"
+        INSERT INTO asset_scans (key, status, signature) VALUES ($1, $2, $3)
+        ON CONFLICT (key) DO UPDATE
+        SET status = EXCLUDED.status, signature = EXCLUDED.signature, scanned = now()
+        "#,
+        key,
+        ScanStatus::from(result).as_str(),
+        signature
+    )
+    .execute(conn)
+    .await?;
+    Ok(())
+}
+
+/// Set the recorded scan status of the assets of a project, rejecting quarantined assets.
+pub async fn apply_scan_statuses(conn: &mut PgConnection, assets: &mut [Asset]) -> Result<()> {
+    let keys: Vec<String> = assets.iter().map(|a| a.key.clone()).collect();
+    let scans = sqlx::query!(
+        "SELECT key, status FROM asset_scans WHERE key = ANY($1)",
+        &keys
+    )
+    .fetch_all(conn)
+    .await?;
+
+    for asset in assets {
+        asset.scan_status = scans
+            .iter()
+            .find(|scan| scan.key == asset.key)
+            .map(|scan| ScanStatus::from_str(&scan.status))
+            .unwrap_or_default();
+        if asset.scan_status == ScanStatus::Infected {
+            return Err(Error::Api(
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "Asset rejected by the virus scanner.",
+            ));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::UnixListener;
+
+    /// Scanner flagging content containing a marker.
+    struct MarkerScanner(&'static [u8]);
+
+    #[async_trait]
+    impl Scanner for MarkerScanner {
+        async fn scan(&self, content: &[u8]) -> anyhow::Result<ScanResult> {
+            if content.windows(self.0.len()).any(|w| w == self.0) {
+                Ok(ScanResult::Infected("Marker".into()))
+            } else {
+                Ok(ScanResult::Clean)
+            }
+        }
+    }
+
+    #[test]
+    fn parses_clamd_responses() {
+        assert_eq!(
+            parse_clamd_response("stream: OK\0").unwrap(),
+            ScanResult::Clean
+        );
+        assert_eq!(
+            parse_clamd_response("stream: Win.Test.EICAR_HDB-1 FOUND\0").unwrap(),
+            ScanResult::Infected("Win.Test.EICAR_HDB-1".into())
+        );
+        assert!(parse_clamd_response("INSTREAM size limit exceeded. ERROR\0").is_err());
+        assert!(parse_clamd_response("stream: Can't allocate memory ERROR\0").is_err());
+    }
+
+    #[tokio::test]
+    async fn scanners_map_to_statuses() {
+        let scanner: Arc<dyn Scanner> = Arc::new(MarkerScanner(b"EICAR"));
+        let result = scanner.scan(b"<kml>EICAR</kml>").await.unwrap();
+        assert_eq!(ScanStatus::from(&result), ScanStatus::Infected);
+        let result = scanner.scan(b"<kml/>").await.unwrap();
+        assert_eq!(ScanStatus::from(&result), ScanStatus::Clean);
+
+        let result = NoScanner.scan(b"<kml>EICAR</kml>").await.unwrap();
+        assert_eq!(ScanStatus::from(&result), ScanStatus::NotScanned);
+    }
+
+    #[tokio::test]
+    async fn clamav_streams_content_over_socket() {
+        let socket = std::env::temp_dir().join(format!("clamd-{}.sock", uuid::Uuid::new_v4()));
+        let listener = UnixListener::bind(&socket).unwrap();
+
+        // Minimal clamd answering INSTREAM requests
+        let server = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut command = [0; 10];
+            stream.read_exact(&mut command).await.unwrap();
+            assert_eq!(&command, b"zINSTREAM\0");
+            let mut content = Vec::new();
+            loop {
+                let length = stream.read_u32().await.unwrap() as usize;
+                if length == 0 {
+                    break;
+                }
+                let mut chunk = vec![0; length];
+                stream.read_exact(&mut chunk).await.unwrap();
+                content.extend(chunk);
+            }
+            let response: &[u8] = if content.starts_with(b"X5O!") {
+                b"stream: Eicar-Signature FOUND\0"
+            } else {
+                b"stream: OK\0"
+            };
+            stream.write_all(response).await.unwrap();
+        });
+
+        let scanner = ClamAv {
+            socket: socket.clone(),
+        };
+        let result = scanner.scan(b"X5O!P%@AP[4\\PZX54(P^)7CC)7}").await;
+        server.await.unwrap();
+        std::fs::remove_file(&socket).unwrap();
+
+        assert_eq!(
+            result.unwrap(),
+            ScanResult::Infected("Eicar-Signature".into())
+        );
+    }
+}
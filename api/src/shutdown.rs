@@ -0,0 +1,110 @@
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::task::AbortHandle;
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
+
+/// Background tasks of the API, stopped on shutdown.
+#[derive(Clone, Default)]
+pub struct Workers {
+    token: CancellationToken,
+    tracker: TaskTracker,
+    /// Handles of the tasks, aborted if they do not stop in time
+    tasks: Arc<Mutex<Vec<AbortHandle>>>,
+}
+
+impl Workers {
+    /// Spawn a task, which should return soon after the given token is cancelled.
+    pub fn spawn<F, T>(&self, task: F)
+    where
+        F: FnOnce(CancellationToken) -> T,
+        T: Future<Output = ()> + Send + 'static,
+    {
+        let handle = self.tracker.spawn(task(self.token.clone()));
+        self.tasks
+            .lock()
+            .expect("workers lock poisoned")
+            .push(handle.abort_handle());
+    }
+
+    /// Ask the tasks to stop, without waiting for them.
+    pub fn cancel(&self) {
+        self.token.cancel();
+    }
+
+    /// Stop the tasks, waiting for them to finish their current work up to the given timeout,
+    /// then aborting the remaining ones.
+    ///
+    /// Returns whether every task finished in time.
+    pub async fn stop(&self, timeout: Duration) -> bool {
+        self.token.cancel();
+        self.tracker.close();
+        if tokio::time::timeout(timeout, self.tracker.wait())
+            .await
+            .is_ok()
+        {
+            return true;
+        }
+
+        for task in self.tasks.lock().expect("workers lock poisoned").drain(..) {
+            task.abort();
+        }
+        // Aborted tasks are dropped at their next await point
+        self.tracker.wait().await;
+        false
+    }
+}
+
+/// Wait for `SIGTERM`, sent by Kubernetes to terminate a pod, or `SIGINT`.
+pub async fn signal() {
+    let interrupt = async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("Failed to listen for SIGINT");
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("Failed to listen for SIGTERM")
+            .recv()
+            .await;
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = interrupt => tracing::info!("Received SIGINT, shutting down"),
+        _ = terminate => tracing::info!("Received SIGTERM, shutting down"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn stop_waits_for_the_current_work() {
+        let workers = Workers::default();
+        let (done, finished) = tokio::sync::oneshot::channel();
+        workers.spawn(|token| async move {
+            token.cancelled().await;
+            // Finishing the current work
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            done.send(()).unwrap();
+        });
+
+        assert!(workers.stop(Duration::from_secs(5)).await);
+        assert!(finished.await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn stop_gives_up_after_the_timeout() {
+        let workers = Workers::default();
+        workers.spawn(|_| std::future::pending());
+
+        assert!(!workers.stop(Duration::from_millis(10)).await);
+        assert!(workers.tracker.is_empty());
+    }
+}
@@ -0,0 +1,295 @@
+use axum::http::StatusCode;
+use serde::Deserialize;
+use sqlx::{PgConnection, PgPool};
+use utoipa::IntoParams;
+use uuid::Uuid;
+
+use crate::geometry::{Cartesian3, Geometry};
+use crate::{Error, Result};
+
+/// WGS84 semi-major axis in meters
+const WGS84_A: f64 = 6_378_137.0;
+/// WGS84 flattening
+const WGS84_F: f64 = 1.0 / 298.257_223_563;
+
+/// Swiss LV95 coordinate reference system
+pub const CRS_LV95: u32 = 2056;
+/// WGS84 longitude/latitude coordinate reference system
+pub const CRS_WGS84: u32 = 4326;
+
+/// Axis-aligned bounding box in LV95 coordinates.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Extent {
+    pub min_x: f64,
+    pub min_y: f64,
+    pub max_x: f64,
+    pub max_y: f64,
+}
+
+impl Extent {
+    pub fn from_points(points: impl IntoIterator<Item = (f64, f64)>) -> Option<Self> {
+        points.into_iter().fold(None, |extent, (x, y)| {
+            Some(match extent {
+                None => Self {
+                    min_x: x,
+                    min_y: y,
+                    max_x: x,
+                    max_y: y,
+                },
+                Some(e) => Self {
+                    min_x: e.min_x.min(x),
+                    min_y: e.min_y.min(y),
+                    max_x: e.max_x.max(x),
+                    max_y: e.max_y.max(y),
+                },
+            })
+        })
+    }
+
+    /// Extent of a geometry, projected from ECEF to LV95.
+    pub fn of_geometry(geometry: &Geometry) -> Option<Self> {
+        Self::from_points(geometry.positions().iter().map(ecef_to_lv95))
+    }
+}
+
+/// Query parameters restricting a search to a bounding box.
+#[derive(Deserialize, Debug, Default, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct BboxQuery {
+    /// `minx,miny,maxx,maxy`
+    pub bbox: Option<String>,
+    /// EPSG code of the `bbox` coordinates, defaults to LV95.
+    pub crs: Option<u32>,
+}
+
+impl BboxQuery {
+    /// Parse the requested bounding box into an LV95 extent.
+    pub fn extent(&self) -> Result<Option<Extent>> {
+        let Some(bbox) = &self.bbox else {
+            return Ok(None);
+        };
+        let invalid = || {
+            Error::Api(
+                StatusCode::BAD_REQUEST,
+                "Invalid bbox, expected `minx,miny,maxx,maxy`.",
+            )
+        };
+        let values = bbox
+            .split(',')
+            .map(|v| v.trim().parse::<f64>())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|_| invalid())?;
+        let [min_x, min_y, max_x, max_y] = values[..] else {
+            return Err(invalid());
+        };
+        if values.iter().any(|v| !v.is_finite()) || min_x > max_x || min_y > max_y {
+            return Err(invalid());
+        }
+
+        let corners = [
+            (min_x, min_y),
+            (min_x, max_y),
+            (max_x, min_y),
+            (max_x, max_y),
+        ];
+        let extent = match self.crs.unwrap_or(CRS_LV95) {
+            CRS_LV95 => Extent::from_points(corners),
+            CRS_WGS84 => Extent::from_points(corners.map(|(lon, lat)| wgs84_to_lv95(lon, lat))),
+            _ => {
+                return Err(Error::Api(
+                    StatusCode::BAD_REQUEST,
+                    "Unsupported crs, expected 2056 or 4326.",
+                ))
+            }
+        };
+        Ok(extent)
+    }
+}
+
+/// Convert earth-centered, earth-fixed coordinates to WGS84 longitude and latitude in degrees.
+pub fn ecef_to_wgs84(position: &Cartesian3) -> (f64, f64) {
+    let b = WGS84_A * (1.0 - WGS84_F);
+    let e2 = WGS84_F * (2.0 - WGS84_F);
+    let ep2 = e2 / (1.0 - e2);
+
+    // Bowring's method
+    let p = position.x.hypot(position.y);
+    let theta = (position.z * WGS84_A).atan2(p * b);
+    let lat =
+        (position.z + ep2 * b * theta.sin().powi(3)).atan2(p - e2 * WGS84_A * theta.cos().powi(3));
+    let lon = position.y.atan2(position.x);
+
+    (lon.to_degrees(), lat.to_degrees())
+}
+
+/// Convert WGS84 longitude and latitude in degrees to LV95 easting and northing,
+/// using the approximate formulas published by swisstopo (accurate to about a meter).
+pub fn wgs84_to_lv95(lon: f64, lat: f64) -> (f64, f64) {
+    let phi = (lat * 3600.0 - 169_028.66) / 10_000.0;
+    let lambda = (lon * 3600.0 - 26_782.5) / 10_000.0;
+
+    let east = 2_600_072.37 + 211_455.93 * lambda
+        - 10_938.51 * lambda * phi
+        - 0.36 * lambda * phi.powi(2)
+        - 44.54 * lambda.powi(3);
+    let north = 1_200_147.07 + 308_807.95 * phi + 3_745.25 * lambda.powi(2) + 76.63 * phi.powi(2)
+        - 194.56 * lambda.powi(2) * phi
+        + 119.79 * phi.powi(3);
+
+    (east, north)
+}
+
+pub fn ecef_to_lv95(position: &Cartesian3) -> (f64, f64) {
+    let (lon, lat) = ecef_to_wgs84(position);
+    wgs84_to_lv95(lon, lat)
+}
+
+/// Replace the indexed extents of a project's geometries.
+pub async fn save_geometry_extents(
+    conn: &mut PgConnection,
+    project_id: Uuid,
+    geometries: &[Geometry],
+) -> Result<()> {
+    sqlx::query!(
+        "DELETE FROM geometry_extents WHERE project_id = $1",
+        project_id
+    )
+    .execute(&mut *conn)
+    .await?;
+    sqlx::query!(
+        "DELETE FROM geometry_extents_backfill WHERE project_id = $1",
+        project_id
+    )
+    .execute(&mut *conn)
+    .await?;
+
+    for (index, geometry) in geometries.iter().enumerate() {
+        let Some(extent) = Extent::of_geometry(geometry) else {
+            continue;
+        };
+        sqlx::query!(
+            r#"
+            INSERT INTO geometry_extents (project_id, geometry_index, extent)
+            VALUES ($1, $2, box(point($3, $4), point($5, $6)))
+            "#,
+            project_id,
+            index as i32,
+            extent.min_x,
+            extent.min_y,
+            extent.max_x,
+            extent.max_y
+        )
+        .execute(&mut *conn)
+        .await?;
+    }
+
+    Ok(())
+}
+
+/// Index the geometries of projects saved before their extents were recorded.
+///
+/// The projects are listed once by a migration, and removed from the list once indexed.
+/// Projects whose geometries can't be read are logged and left in the list.
+pub async fn index_geometry_extents(pool: &PgPool) -> Result<()> {
+    let ids = sqlx::query_scalar!("SELECT project_id FROM geometry_extents_backfill")
+        .fetch_all(pool)
+        .await?;
+
+    for id in ids {
+        let mut tx = pool.begin().await?;
+        // Locked, so that a concurrent update can't be overwritten with stale extents
+        let geometries = sqlx::query_scalar!(
+            r#"
+            SELECT COALESCE(project->'geometries', '[]') AS "geometries!"
+            FROM projects
+            WHERE id = $1
+            FOR UPDATE
+            "#,
+            id
+        )
+        .fetch_optional(&mut *tx)
+        .await?;
+        // Deleted projects are removed from the list with their extents
+        let Some(geometries) = geometries else {
+            continue;
+        };
+        match serde_json::from_value::<Vec<Geometry>>(geometries) {
+            Ok(geometries) => {
+                save_geometry_extents(&mut tx, id, &geometries).await?;
+                tx.commit().await?;
+            }
+            Err(e) => tracing::warn!("Failed to index the geometries of project {}: {}", id, e),
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wgs84_to_lv95_matches_swisstopo_example() {
+        // Example from the swisstopo approximate formulas documentation
+        let lat = 46.0 + 2.0 / 60.0 + 38.87 / 3600.0;
+        let lon = 8.0 + 43.0 / 60.0 + 49.79 / 3600.0;
+
+        let (east, north) = wgs84_to_lv95(lon, lat);
+
+        assert!((east - 2_699_999.76).abs() < 1.0, "east: {east}");
+        assert!((north - 1_099_999.97).abs() < 1.0, "north: {north}");
+    }
+
+    #[test]
+    fn ecef_to_lv95_near_bern() {
+        // Zimmerwald observatory
+        let position = Cartesian3 {
+            x: 4_331_283.0,
+            y: 567_549.0,
+            z: 4_633_140.0,
+        };
+
+        let (east, north) = ecef_to_lv95(&position);
+
+        assert!((east - 2_602_030.0).abs() < 50.0, "east: {east}");
+        assert!((north - 1_191_790.0).abs() < 50.0, "north: {north}");
+    }
+
+    #[test]
+    fn parses_bbox_query() {
+        let query = BboxQuery {
+            bbox: Some("2590000,1190000,2610000,1210000".into()),
+            crs: None,
+        };
+        assert_eq!(
+            query.extent().unwrap(),
+            Some(Extent {
+                min_x: 2_590_000.0,
+                min_y: 1_190_000.0,
+                max_x: 2_610_000.0,
+                max_y: 1_210_000.0,
+            })
+        );
+
+        let query = BboxQuery {
+            bbox: Some("7.3,46.9,7.5,47.0".into()),
+            crs: Some(CRS_WGS84),
+        };
+        let extent = query.extent().unwrap().unwrap();
+        assert!(extent.min_x < 2_600_000.0 && extent.max_x > 2_600_000.0);
+
+        for bbox in ["1,2,3", "3,2,1,4", "a,b,c,d"] {
+            let query = BboxQuery {
+                bbox: Some(bbox.into()),
+                crs: None,
+            };
+            assert!(query.extent().is_err(), "{bbox}");
+        }
+        let query = BboxQuery {
+            bbox: Some("1,2,3,4".into()),
+            crs: Some(3857),
+        };
+        assert!(query.extent().is_err());
+    }
+}
@@ -0,0 +1,86 @@
+use axum::http::StatusCode;
+use serde::{Deserialize, Serialize};
+use sqlx::FromRow;
+use utoipa::ToSchema;
+
+use crate::{Error, Result};
+
+/// Maximum number of tags on a project
+const MAX_TAGS: usize = 20;
+/// Maximum length of a tag in characters
+const MAX_TAG_LENGTH: usize = 50;
+
+/// A tag maintained by the administrators.
+#[derive(Serialize, Deserialize, Clone, Debug, FromRow, ToSchema)]
+pub struct CuratedTag {
+    pub name: String,
+    pub description: Option<String>,
+}
+
+/// How often a tag is used by the projects visible to the user.
+#[derive(Serialize, Debug, FromRow, ToSchema)]
+pub struct TagUsage {
+    pub name: String,
+    pub description: Option<String>,
+    pub curated: bool,
+    pub count: i64,
+}
+
+/// Normalize a single tag: trimmed, lowercased and with inner whitespace collapsed.
+pub fn normalize_tag(tag: &str) -> Result<String> {
+    let tag = tag
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+        .to_lowercase();
+    if tag.is_empty() || tag.chars().count() > MAX_TAG_LENGTH || tag.contains(',') {
+        return Err(Error::Api(
+            StatusCode::BAD_REQUEST,
+            "Invalid tag, expected 1 to 50 characters without commas.",
+        ));
+    }
+    Ok(tag)
+}
+
+/// Normalize and deduplicate the tags of a project, keeping their order.
+pub fn normalize_tags(tags: &[String]) -> Result<Vec<String>> {
+    let mut normalized = Vec::with_capacity(tags.len());
+    for tag in tags {
+        let tag = normalize_tag(tag)?;
+        if !normalized.contains(&tag) {
+            normalized.push(tag);
+        }
+    }
+    if normalized.len() > MAX_TAGS {
+        return Err(Error::Api(
+            StatusCode::BAD_REQUEST,
+            "Too many tags, a project may have at most 20 tags.",
+        ));
+    }
+    Ok(normalized)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalizes_and_deduplicates_tags() {
+        let tags = ["  Canton  Bern ", "canton bern", "Geothermie"].map(String::from);
+
+        assert_eq!(
+            normalize_tags(&tags).unwrap(),
+            vec!["canton bern".to_owned(), "geothermie".to_owned()]
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_tags() {
+        assert!(normalize_tag("   ").is_err());
+        assert!(normalize_tag("a,b").is_err());
+        assert!(normalize_tag(&"x".repeat(MAX_TAG_LENGTH + 1)).is_err());
+
+        let tags: Vec<String> = (0..=MAX_TAGS).map(|i| format!("tag {i}")).collect();
+        assert!(normalize_tags(&tags).is_err());
+    }
+}
@@ -0,0 +1,442 @@
+use axum::extract::{FromRequestParts, MatchedPath, RawPathParams, Request};
+use axum::middleware::Next;
+use axum::response::Response;
+use hmac::{Hmac, Mac};
+use once_cell::sync::OnceCell;
+use opentelemetry::trace::{Span as _, SpanKind, Tracer as _, TracerProvider as _};
+use opentelemetry::KeyValue;
+use opentelemetry_otlp::{SpanExporter, WithExportConfig};
+use opentelemetry_sdk::trace::{Tracer, TracerProvider};
+use opentelemetry_sdk::{runtime, Resource};
+use sha2::Sha256;
+use std::time::{Duration, SystemTime};
+use tower_http::request_id::RequestId;
+use tracing::field::{Empty, Field, Visit};
+use tracing::{Event, Span, Subscriber};
+use tracing_opentelemetry::{OtelData, PreSampledTracer};
+use tracing_subscriber::fmt::MakeWriter;
+use tracing_subscriber::layer::{Context, SubscriberExt};
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{EnvFilter, Layer};
+use url::Url;
+
+/// Replaces the secrets of the configuration in the logs
+pub const REDACTED: &str = "<redacted>";
+
+/// Key of the HMAC identifying the users, see [`record_user`]
+static USER_KEY: OnceCell<Vec<u8>> = OnceCell::new();
+
+tokio::task_local! {
+    /// Id of the request being handled, see [`scope_request_id`]
+    static REQUEST_ID: String;
+}
+
+/// Format of the logs
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LogFormat {
+    /// Human readable lines
+    Text,
+    /// One JSON object per line, with the fields of the current request
+    Json,
+}
+
+/// Configuration of the logs and of the export of traces.
+#[derive(clap::Parser, Clone, Debug)]
+pub struct Telemetry {
+    /// Format of the logs
+    #[clap(long, env, value_enum, default_value_t = LogFormat::Text)]
+    pub log_format: LogFormat,
+    /// OTLP/HTTP endpoint of the collector receiving the traces, e.g. `http://otel-collector:4318`.
+    /// Traces are only exported when set
+    #[clap(long, env)]
+    pub otel_exporter_otlp_endpoint: Option<Url>,
+    /// Name of the service in the exported traces
+    #[clap(long, env, default_value = "swissgeol-api")]
+    pub otel_service_name: String,
+    /// Spans and events exported to the collector, with the syntax of `RUST_LOG`
+    #[clap(long, env, default_value = "info,api=debug")]
+    pub otel_traces_filter: String,
+    /// Secret key of the HMAC identifying the users in the logs and traces, rather than their
+    /// email. A random key is used if omitted, changing the identifiers at every start
+    #[clap(long, env, hide_env_values = true)]
+    pub log_user_key: Option<String>,
+}
+
+/// Exporter of the traces, flushed when dropped.
+pub struct TracesGuard(Option<TracerProvider>);
+
+impl Drop for TracesGuard {
+    fn drop(&mut self) {
+        if let Some(provider) = self.0.take() {
+            if let Err(e) = provider.shutdown() {
+                eprintln!("Failed to export the remaining traces: {e}");
+            }
+        }
+    }
+}
+
+impl Telemetry {
+    /// Log to stdout, filtered by `RUST_LOG`, and export the traces when an endpoint is set.
+    ///
+    /// Must be called within the Tokio runtime exporting the traces.
+    pub fn init(&self) -> anyhow::Result<TracesGuard> {
+        if let Some(key) = &self.log_user_key {
+            USER_KEY.set(key.as_bytes().to_vec()).ok();
+        }
+        let logs =
+            log_layer(self.log_format, std::io::stdout).with_filter(EnvFilter::from_default_env());
+
+        let Some(endpoint) = &self.otel_exporter_otlp_endpoint else {
+            tracing_subscriber::registry().with(logs).init();
+            return Ok(TracesGuard(None));
+        };
+
+        let exporter = SpanExporter::builder()
+            .with_http()
+            .with_endpoint(endpoint.join("v1/traces")?)
+            .build()?;
+        let provider = TracerProvider::builder()
+            .with_batch_exporter(exporter, runtime::Tokio)
+            .with_resource(Resource::new([KeyValue::new(
+                "service.name",
+                self.otel_service_name.clone(),
+            )]))
+            .build();
+        let tracer = provider.tracer("api");
+
+        let traces = tracing_opentelemetry::layer()
+            .with_tracer(tracer.clone())
+            .with_filter(EnvFilter::try_new(&self.otel_traces_filter)?);
+        // The same spans, to find the parents of the queries
+        let queries = QuerySpans { tracer }.with_filter(EnvFilter::try_new(format!(
+            "{},sqlx::query=debug",
+            self.otel_traces_filter
+        ))?);
+        tracing_subscriber::registry()
+            .with(logs)
+            .with(traces)
+            .with(queries)
+            .init();
+
+        Ok(TracesGuard(Some(provider)))
+    }
+}
+
+/// Layer formatting the logs, with the fields of the current request in JSON.
+fn log_layer<S, W>(format: LogFormat, writer: W) -> Box<dyn Layer<S> + Send + Sync>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    W: for<'w> MakeWriter<'w> + Send + Sync + 'static,
+{
+    let layer = tracing_subscriber::fmt::layer().with_writer(writer);
+    match format {
+        LogFormat::Text => layer.boxed(),
+        LogFormat::Json => layer
+            .json()
+            .with_current_span(true)
+            .with_span_list(false)
+            .boxed(),
+    }
+}
+
+/// Span of a request, with its id.
+pub fn request_span(request: &Request) -> Span {
+    let request_id = request
+        .extensions()
+        .get::<RequestId>()
+        .and_then(|id| id.header_value().to_str().ok())
+        .unwrap_or_default();
+    tracing::info_span!(
+        "request",
+        otel.name = %request.method(),
+        otel.kind = "server",
+        otel.status_code = Empty,
+        method = %request.method(),
+        uri = %request.uri(),
+        version = ?request.version(),
+        request_id,
+        route = Empty,
+        project_id = Empty,
+        user = Empty,
+        status = Empty,
+        latency_ms = Empty,
+    )
+}
+
+/// Log the status and latency of a response, recording them in the span of its request.
+pub fn log_response(response: &Response, latency: Duration, span: &Span) {
+    let status = response.status().as_u16();
+    let latency_ms = latency.as_millis() as u64;
+    span.record("status", status);
+    span.record("latency_ms", latency_ms);
+    if response.status().is_server_error() {
+        span.record("otel.status_code", "ERROR");
+    }
+    tracing::info!(status, latency_ms, "finished processing request");
+}
+
+/// Span of an S3 call, retries included.
+pub fn s3_span(operation: &str) -> Span {
+    tracing::info_span!(
+        "s3",
+        otel.name = format!("S3.{operation}"),
+        otel.kind = "client",
+        otel.status_code = Empty,
+        rpc.system = "aws-api",
+        rpc.service = "S3",
+        rpc.method = operation,
+        error = Empty,
+    )
+}
+
+/// Record the user of the request, identified by an HMAC of their email.
+pub fn record_user(email: &str) {
+    let key = USER_KEY.get_or_init(|| {
+        [uuid::Uuid::new_v4(), uuid::Uuid::new_v4()]
+            .iter()
+            .flat_map(|random| random.into_bytes())
+            .collect()
+    });
+    Span::current().record("user", user_id(key, email));
+}
+
+/// Identifier of a user, which cannot be traced back to their email without the key.
+fn user_id(key: &[u8], email: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(email.to_lowercase().as_bytes());
+    let hash = format!("{:x}", mac.finalize().into_bytes());
+    hash[..32].to_owned()
+}
+
+/// Name the span of a request after its matched route, and record the project it concerns.
+///
+/// Added as a route layer, where the route and its parameters are known.
+pub async fn record_route(request: Request, next: Next) -> Response {
+    let span = Span::current();
+    if let Some(route) = request.extensions().get::<MatchedPath>() {
+        span.record("route", route.as_str());
+        span.record(
+            "otel.name",
+            format!("{} {}", request.method(), route.as_str()),
+        );
+    }
+
+    // The `id` parameter of the routes is always the id of a project
+    let (mut parts, body) = request.into_parts();
+    if let Ok(params) = RawPathParams::from_request_parts(&mut parts, &()).await {
+        if let Some((_, id)) = params.iter().find(|(name, _)| *name == "id") {
+            span.record("project_id", id);
+        }
+    }
+    next.run(Request::from_parts(parts, body)).await
+}
+
+/// Drop an `X-Request-Id` header that is not a valid id, so that a new id is generated
+/// rather than logging and returning arbitrary content.
+pub async fn check_request_id(mut request: Request, next: Next) -> Response {
+    let headers = request.headers_mut();
+    if !headers
+        .get_all(crate::X_REQUEST_ID)
+        .iter()
+        .all(|id| is_valid_request_id(id.as_bytes()))
+    {
+        headers.remove(crate::X_REQUEST_ID);
+    }
+    next.run(request).await
+}
+
+/// Whether an id is made of at most 128 letters, digits, `.`, `_` or `-`.
+fn is_valid_request_id(id: &[u8]) -> bool {
+    !id.is_empty()
+        && id.len() <= 128
+        && id
+            .iter()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, b'.' | b'_' | b'-'))
+}
+
+/// Make the id of the request available to [`request_id`] while it is handled.
+pub async fn scope_request_id(request: Request, next: Next) -> Response {
+    let request_id = request
+        .extensions()
+        .get::<RequestId>()
+        .and_then(|id| id.header_value().to_str().ok())
+        .unwrap_or_default()
+        .to_owned();
+    REQUEST_ID.scope(request_id, next.run(request)).await
+}
+
+/// Id of the request being handled, from its `X-Request-Id` header or generated.
+pub fn request_id() -> Option<String> {
+    REQUEST_ID
+        .try_with(Clone::clone)
+        .ok()
+        .filter(|id| !id.is_empty())
+}
+
+/// Export the queries logged by sqlx as spans, timed by their reported duration.
+///
+/// sqlx does not open spans around its queries, it only logs them once completed.
+struct QuerySpans {
+    tracer: Tracer,
+}
+
+#[derive(Default)]
+struct Query {
+    summary: String,
+    statement: String,
+    elapsed_secs: f64,
+    rows_affected: u64,
+    rows_returned: u64,
+}
+
+impl Visit for Query {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        match field.name() {
+            "summary" => self.summary = value.to_owned(),
+            "db.statement" => self.statement = value.trim().to_owned(),
+            _ => {}
+        }
+    }
+
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        if field.name() == "elapsed_secs" {
+            self.elapsed_secs = value;
+        }
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        match field.name() {
+            "rows_affected" => self.rows_affected = value,
+            "rows_returned" => self.rows_returned = value,
+            _ => {}
+        }
+    }
+
+    fn record_debug(&mut self, _field: &Field, _value: &dyn std::fmt::Debug) {}
+}
+
+impl<S> Layer<S> for QuerySpans
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        if event.metadata().target() != "sqlx::query" {
+            return;
+        }
+        let mut query = Query::default();
+        event.record(&mut query);
+
+        // The closest span exported to the collector
+        let parent = ctx
+            .event_scope(event)
+            .and_then(|scope| {
+                scope.into_iter().find_map(|span| {
+                    let mut extensions = span.extensions_mut();
+                    extensions
+                        .get_mut::<OtelData>()
+                        .map(|data| self.tracer.sampled_context(data))
+                })
+            })
+            .unwrap_or_default();
+
+        let end = SystemTime::now();
+        let start = end - Duration::from_secs_f64(query.elapsed_secs);
+        let statement = if query.statement.is_empty() {
+            query.summary.clone()
+        } else {
+            query.statement
+        };
+        let mut span = self
+            .tracer
+            .span_builder(query.summary)
+            .with_kind(SpanKind::Client)
+            .with_start_time(start)
+            .with_attributes([
+                KeyValue::new("db.system", "postgresql"),
+                KeyValue::new("db.statement", statement),
+                KeyValue::new("db.rows_affected", query.rows_affected as i64),
+                KeyValue::new("db.rows_returned", query.rows_returned as i64),
+            ])
+            .start_with_context(&self.tracer, &parent);
+        span.end_with_timestamp(end);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::Body;
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Clone, Default)]
+    struct Logs(Arc<Mutex<Vec<u8>>>);
+
+    impl std::io::Write for Logs {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn json_logs_have_the_fields_of_the_request() {
+        let logs = Logs::default();
+        let writer = logs.clone();
+        let subscriber =
+            tracing_subscriber::registry().with(log_layer(LogFormat::Json, move || writer.clone()));
+
+        tracing::subscriber::with_default(subscriber, || {
+            let request = Request::builder()
+                .uri("/api/v2/projects/1")
+                .body(Body::empty())
+                .unwrap();
+            let span = request_span(&request);
+            let _guard = span.enter();
+            span.record("route", "/api/v2/projects/:id");
+            span.record("project_id", "1");
+            record_user("User@Example.com");
+            let response = Response::builder().status(404).body(Body::empty()).unwrap();
+            log_response(&response, Duration::from_millis(12), &span);
+        });
+
+        let logs = String::from_utf8(logs.0.lock().unwrap().clone()).unwrap();
+        let line: serde_json::Value = serde_json::from_str(logs.lines().last().unwrap()).unwrap();
+        let span = &line["span"];
+        assert_eq!(span["route"], "/api/v2/projects/:id");
+        assert_eq!(span["project_id"], "1");
+        assert_eq!(
+            span["user"],
+            user_id(USER_KEY.get().unwrap(), "user@example.com")
+        );
+        assert_eq!(line["fields"]["status"], 404);
+        assert_eq!(line["fields"]["latency_ms"], 12);
+        assert!(!logs.contains("example.com"));
+    }
+
+    #[test]
+    fn request_ids_are_validated() {
+        assert!(is_valid_request_id(b"4f1c2b9e-0d3a.trace_1"));
+        assert!(is_valid_request_id(&[b'a'; 128]));
+        for invalid in [
+            b"".as_slice(),
+            &[b'a'; 129],
+            b"two words",
+            b"<script>",
+            b"line\r\nbreak",
+        ] {
+            assert!(!is_valid_request_id(invalid), "{invalid:?}");
+        }
+    }
+
+    #[test]
+    fn user_ids_depend_on_the_key() {
+        let id = user_id(b"key", "User@Example.com");
+        assert_eq!(id.len(), 32);
+        assert_eq!(id, user_id(b"key", "user@example.com"));
+        assert_ne!(id, user_id(b"other key", "user@example.com"));
+    }
+}
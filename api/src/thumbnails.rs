@@ -0,0 +1,188 @@
+use std::fmt::Write;
+
+use uuid::Uuid;
+
+use crate::geometry::{CesiumColor, Geometry};
+use crate::spatial::{ecef_to_lv95, Extent};
+
+/// Size of the rendered thumbnails in pixels
+const WIDTH: f64 = 320.0;
+const HEIGHT: f64 = 180.0;
+/// Space kept free around the geometries
+const MARGIN: f64 = 12.0;
+/// Radius of the points
+const POINT_RADIUS: f64 = 4.0;
+/// Color of the geometries without color, matching the viewer
+const DEFAULT_COLOR: CesiumColor = CesiumColor {
+    red: 0.0,
+    green: 153.0 / 255.0,
+    blue: 1.0,
+    alpha: 1.0,
+};
+
+/// Bucket key of the cached thumbnail of a project.
+pub fn thumbnail_key(project_id: Uuid) -> String {
+    format!("thumbnails/{}.svg", project_id)
+}
+
+/// Render an SVG overview of geometries, seen from above in LV95 coordinates.
+pub fn render_thumbnail(geometries: &[Geometry]) -> String {
+    let projected: Vec<(&Geometry, Vec<(f64, f64)>)> = geometries
+        .iter()
+        .filter(|g| g.properties().show != Some(false))
+        .map(|g| (g, g.positions().iter().map(ecef_to_lv95).collect()))
+        .collect();
+    let extent = Extent::from_points(projected.iter().flat_map(|(_, p)| p.iter().copied()));
+
+    let mut svg = format!(
+        r##"<svg xmlns="http://www.w3.org/2000/svg" width="{WIDTH}" height="{HEIGHT}" viewBox="0 0 {WIDTH} {HEIGHT}"><rect width="100%" height="100%" fill="#f1f3f5"/>"##
+    );
+    if let Some(extent) = extent {
+        let transform = Transform::fit(&extent);
+        for (geometry, points) in &projected {
+            let color = geometry.properties().color.unwrap_or(DEFAULT_COLOR);
+            let (fill, opacity) = (rgb(&color), color.alpha.clamp(0.0, 1.0));
+            let points = points
+                .iter()
+                .map(|&p| transform.apply(p))
+                .collect::<Vec<_>>();
+            match geometry {
+                Geometry::Point(_) | Geometry::Borehole(_) => {
+                    let (x, y) = points[0];
+                    let _ = write!(
+                        svg,
+                        r#"<circle cx="{x:.1}" cy="{y:.1}" r="{POINT_RADIUS}" fill="{fill}" fill-opacity="{opacity}"/>"#
+                    );
+                }
+                Geometry::Line(_) => {
+                    let _ = write!(
+                        svg,
+                        r#"<polyline points="{}" fill="none" stroke="{fill}" stroke-opacity="{opacity}" stroke-width="2"/>"#,
+                        svg_points(&points)
+                    );
+                }
+                Geometry::Polygon(_) | Geometry::Rectangle(_) => {
+                    let _ = write!(
+                        svg,
+                        r#"<polygon points="{}" fill="{fill}" fill-opacity="{:.2}" stroke="{fill}" stroke-opacity="{opacity}" stroke-width="2"/>"#,
+                        svg_points(&points),
+                        opacity * 0.4
+                    );
+                }
+            }
+        }
+    }
+    svg.push_str("</svg>");
+    svg
+}
+
+fn rgb(color: &CesiumColor) -> String {
+    let channel = |c: f64| (c.clamp(0.0, 1.0) * 255.0).round() as u8;
+    format!(
+        "#{:02x}{:02x}{:02x}",
+        channel(color.red),
+        channel(color.green),
+        channel(color.blue)
+    )
+}
+
+fn svg_points(points: &[(f64, f64)]) -> String {
+    points
+        .iter()
+        .map(|(x, y)| format!("{x:.1},{y:.1}"))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Maps LV95 coordinates onto the thumbnail, keeping the aspect ratio and centering the extent.
+struct Transform {
+    extent: Extent,
+    scale: f64,
+    offset_x: f64,
+    offset_y: f64,
+}
+
+impl Transform {
+    fn fit(extent: &Extent) -> Self {
+        let (width, height) = (extent.max_x - extent.min_x, extent.max_y - extent.min_y);
+        let (available_width, available_height) = (WIDTH - 2.0 * MARGIN, HEIGHT - 2.0 * MARGIN);
+        let scale = match (width > 0.0, height > 0.0) {
+            (true, true) => (available_width / width).min(available_height / height),
+            (true, false) => available_width / width,
+            (false, true) => available_height / height,
+            (false, false) => 1.0,
+        };
+        Self {
+            extent: *extent,
+            scale,
+            offset_x: (WIDTH - width * scale) / 2.0,
+            offset_y: (HEIGHT - height * scale) / 2.0,
+        }
+    }
+
+    /// Northing grows upwards while SVG coordinates grow downwards.
+    fn apply(&self, (x, y): (f64, f64)) -> (f64, f64) {
+        (
+            self.offset_x + (x - self.extent.min_x) * self.scale,
+            HEIGHT - self.offset_y - (y - self.extent.min_y) * self.scale,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn renders_geometries_within_bounds() {
+        let geometries: Vec<Geometry> = serde_json::from_value(json!([
+            {
+                "type": "polygon",
+                "positions": [
+                    {"x": 4_331_283.0, "y": 567_549.0, "z": 4_633_140.0},
+                    {"x": 4_332_283.0, "y": 567_549.0, "z": 4_632_140.0},
+                    {"x": 4_331_783.0, "y": 568_549.0, "z": 4_632_640.0}
+                ],
+                "color": {"red": 1.0, "green": 0.0, "blue": 0.0, "alpha": 1.0}
+            },
+            {
+                "type": "point",
+                "positions": [{"x": 4_331_500.0, "y": 568_000.0, "z": 4_632_800.0}]
+            },
+            {
+                "type": "line",
+                "show": false,
+                "positions": [{"x": 0.0, "y": 0.0, "z": 6_356_752.0}]
+            }
+        ]))
+        .unwrap();
+
+        let svg = render_thumbnail(&geometries);
+
+        assert!(svg.contains(r##"<polygon points=""##));
+        assert!(svg.contains(r##"fill="#ff0000""##));
+        assert!(svg.contains(r##"<circle"##));
+        // Hidden geometries are neither drawn nor part of the extent
+        assert!(!svg.contains("<polyline"));
+        let numbers = svg
+            .split(['"', ' ', ','])
+            .filter_map(|v| v.parse::<f64>().ok());
+        assert!(numbers.clone().all(|v| (0.0..=WIDTH).contains(&v)));
+    }
+
+    #[test]
+    fn fits_single_point_in_the_center() {
+        let extent = Extent::from_points([(2_600_000.0, 1_200_000.0)]).unwrap();
+        let transform = Transform::fit(&extent);
+
+        assert_eq!(
+            transform.apply((2_600_000.0, 1_200_000.0)),
+            (WIDTH / 2.0, HEIGHT / 2.0)
+        );
+        assert_eq!(
+            render_thumbnail(&[]),
+            r##"<svg xmlns="http://www.w3.org/2000/svg" width="320" height="180" viewBox="0 0 320 180"><rect width="100%" height="100%" fill="#f1f3f5"/></svg>"##
+        );
+    }
+}
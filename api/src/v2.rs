@@ -0,0 +1,464 @@
+use std::collections::HashSet;
+
+use axum::{
+    extract::{Extension, Json, Path, Query},
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use crate::auth::Claims;
+use crate::geometry::Geometry;
+use crate::handlers::{
+    self, Asset, CreateProjectFromTemplate, DuplicateProject, Member, View, ViewResource,
+};
+use crate::project_list::{self, ProjectListQuery};
+use crate::projects::Projects;
+use crate::spatial::BboxQuery;
+use crate::views::ViewState;
+use crate::{Error, Result};
+
+/// Role of a member in a project.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, ToSchema)]
+#[serde(rename_all = "lowercase")]
+#[schema(as = v2::Role)]
+pub enum Role {
+    Owner,
+    Editor,
+    Viewer,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, ToSchema)]
+#[schema(as = v2::ProjectMember)]
+pub struct ProjectMember {
+    #[serde(flatten)]
+    pub member: Member,
+    pub role: Role,
+}
+
+/// A project as exchanged by the second version of the API, served under `/api/v2`.
+///
+/// Compared to the first version, members are listed with their role, views come with their
+/// parsed state, the fields managed by the server are not part of the request bodies and created
+/// projects are returned in full.
+#[derive(Serialize, Debug, ToSchema)]
+#[serde(rename_all = "camelCase")]
+#[schema(as = v2::Project)]
+pub struct Project {
+    pub id: Uuid,
+    pub title: String,
+    pub description: Option<String>,
+    pub created: DateTime<Utc>,
+    pub modified: Option<DateTime<Utc>>,
+    pub image: Option<String>,
+    pub color: String,
+    pub tags: Vec<String>,
+    /// The owner first, then the editors and viewers
+    pub members: Vec<ProjectMember>,
+    pub views: Vec<ViewResource>,
+    pub assets: Vec<Asset>,
+    pub geometries: Vec<Geometry>,
+    /// The project this project was copied from
+    pub duplicated_from: Option<Uuid>,
+}
+
+impl From<handlers::Project> for Project {
+    fn from(project: handlers::Project) -> Self {
+        let member = |role| move |member| ProjectMember { member, role };
+        let members = std::iter::once(project.owner)
+            .map(member(Role::Owner))
+            .chain(project.editors.into_iter().map(member(Role::Editor)))
+            .chain(project.viewers.into_iter().map(member(Role::Viewer)))
+            .collect();
+        Self {
+            id: project.id,
+            title: project.title,
+            description: project.description,
+            created: project.created,
+            modified: project.modified,
+            image: project.image,
+            color: project.color,
+            tags: project.tags,
+            members,
+            views: project
+                .views
+                .into_iter()
+                .enumerate()
+                .map(|(position, view)| ViewResource::new(position, view))
+                .collect(),
+            assets: project.assets,
+            geometries: project.geometries,
+            duplicated_from: project.duplicated_from,
+        }
+    }
+}
+
+/// A view of a project, given either as a permalink or as a structured state.
+#[derive(Deserialize, Debug, ToSchema)]
+#[schema(as = v2::ProjectView)]
+pub struct ProjectView {
+    /// Id of an existing view, generated for new views
+    pub id: Option<String>,
+    pub title: String,
+    pub permalink: Option<String>,
+    pub state: Option<ViewState>,
+}
+
+/// Content of a project created or updated by a user.
+#[derive(Deserialize, Debug, ToSchema)]
+#[schema(as = v2::ProjectInput)]
+pub struct ProjectInput {
+    pub title: String,
+    pub description: Option<String>,
+    pub image: Option<String>,
+    pub color: String,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    /// Exactly one owner, and the editors and viewers
+    pub members: Vec<ProjectMember>,
+    #[serde(default)]
+    pub views: Vec<ProjectView>,
+    #[serde(default)]
+    pub assets: Vec<Asset>,
+    #[serde(default)]
+    pub geometries: Vec<Geometry>,
+}
+
+impl ProjectInput {
+    /// Convert into a stored project, keeping the permalinks of the unchanged `saved_views`
+    /// as is, as they may predate the validation of views.
+    fn into_project(self, id: Uuid, saved_views: &[View]) -> Result<handlers::Project> {
+        let mut emails = HashSet::new();
+        if self
+            .members
+            .iter()
+            .any(|m| !emails.insert(m.member.email.clone()))
+        {
+            return Err(Error::Api(StatusCode::BAD_REQUEST, "Duplicate member."));
+        }
+
+        let mut owners = Vec::new();
+        let mut editors = Vec::new();
+        let mut viewers = Vec::new();
+        for ProjectMember { member, role } in self.members {
+            match role {
+                Role::Owner => owners.push(member),
+                Role::Editor => editors.push(member),
+                Role::Viewer => viewers.push(member),
+            }
+        }
+        let owner = match <[Member; 1]>::try_from(owners) {
+            Ok([owner]) => owner,
+            Err(_) => {
+                return Err(Error::Api(
+                    StatusCode::BAD_REQUEST,
+                    "Expected exactly one owner.",
+                ))
+            }
+        };
+
+        let views = self
+            .views
+            .into_iter()
+            .map(|view| {
+                let saved = view
+                    .id
+                    .as_ref()
+                    .and_then(|id| saved_views.iter().find(|saved| saved.id == *id));
+                let permalink = match (saved, &view.permalink) {
+                    (Some(saved), Some(permalink)) if saved.permalink == *permalink => {
+                        permalink.clone()
+                    }
+                    _ => ViewState::normalize(view.permalink.as_deref(), view.state.as_ref())?,
+                };
+                Ok(View {
+                    id: view.id.unwrap_or_else(|| Uuid::new_v4().to_string()),
+                    title: view.title,
+                    permalink,
+                })
+            })
+            .collect::<Result<_>>()?;
+
+        Ok(handlers::Project {
+            id,
+            title: self.title,
+            description: self.description,
+            created: Utc::now(),
+            modified: None,
+            image: self.image,
+            color: self.color,
+            views,
+            assets: self.assets,
+            owner,
+            viewers,
+            editors,
+            geometries: self.geometries,
+            tags: self.tags,
+            duplicated_from: None,
+        })
+    }
+}
+
+/// Response of a created project, with its location.
+fn created(project: handlers::Project) -> Result<(StatusCode, HeaderMap, Json<Project>)> {
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        header::LOCATION,
+        HeaderValue::from_str(&format!("/api/v2/projects/{}", project.id))
+            .map_err(anyhow::Error::from)?,
+    );
+    Ok((StatusCode::CREATED, headers, Json(project.into())))
+}
+
+#[utoipa::path(
+    get, path = "/projects", tag = "projects",
+    params(BboxQuery, ProjectListQuery),
+    responses((
+        status = 200, body = Vec<Project>,
+        headers(
+            ("x-total-count" = i64, description = "Number of projects matching the filters"),
+            ("x-next-cursor" = String, description = "Cursor of the next page, if any"),
+        ),
+    )),
+    security(("bearer" = [])),
+)]
+#[axum_macros::debug_handler]
+pub async fn list_projects(
+    Extension(pool): Extension<PgPool>,
+    claims: Claims,
+    Query(bbox): Query<BboxQuery>,
+    Query(query): Query<ProjectListQuery>,
+) -> Result<(HeaderMap, Json<Vec<Project>>)> {
+    let extent = bbox.extent()?;
+    let page =
+        project_list::list_projects(&pool, &claims.email.to_lowercase(), extent, &query).await?;
+    let headers = page.headers()?;
+
+    Ok((
+        headers,
+        Json(page.projects.into_iter().map(Project::from).collect()),
+    ))
+}
+
+/// Create a project owned by the user.
+#[utoipa::path(
+    post, path = "/projects", tag = "projects",
+    request_body = ProjectInput,
+    responses((status = 201, body = Project, headers(("location" = String)))),
+    security(("bearer" = [])),
+)]
+#[axum_macros::debug_handler]
+pub async fn create_project(
+    Extension(projects): Extension<Projects>,
+    claims: Claims,
+    Json(input): Json<ProjectInput>,
+) -> Result<(StatusCode, HeaderMap, Json<Project>)> {
+    let project = input.into_project(Uuid::new_v4(), &[])?;
+    if project.owner.email != claims.email.to_lowercase() {
+        return Err(Error::Api(
+            StatusCode::BAD_REQUEST,
+            "Project owner does not match token claims.",
+        ));
+    }
+
+    let project = projects.create(project).await?;
+
+    created(project)
+}
+
+#[utoipa::path(
+    get, path = "/projects/{id}", tag = "projects",
+    params(("id" = Uuid, Path, description = "Project id")),
+    responses((status = 200, body = Project)),
+    security(("bearer" = [])),
+)]
+#[axum_macros::debug_handler]
+pub async fn get_project(
+    Path(id): Path<Uuid>,
+    Extension(projects): Extension<Projects>,
+    claims: Claims,
+) -> Result<Json<Project>> {
+    let project = projects.fetch(id).await?;
+    if !project.is_viewable_by(&claims.email.to_lowercase()) {
+        return Err(Error::Forbidden);
+    }
+
+    Ok(Json(project.into()))
+}
+
+/// Replace a project editable by the user. Only the owner may transfer the ownership.
+#[utoipa::path(
+    put, path = "/projects/{id}", tag = "projects",
+    params(("id" = Uuid, Path, description = "Project id")),
+    request_body = ProjectInput,
+    responses((status = 200, body = Project)),
+    security(("bearer" = [])),
+)]
+#[axum_macros::debug_handler]
+pub async fn update_project(
+    Path(id): Path<Uuid>,
+    Extension(projects): Extension<Projects>,
+    claims: Claims,
+    Json(input): Json<ProjectInput>,
+) -> Result<Json<Project>> {
+    let email = claims.email.to_lowercase();
+    let saved = projects.fetch(id).await?;
+    let project = input.into_project(id, &saved.views)?;
+    let project = projects.update(id, &email, project).await?;
+
+    Ok(Json(project.into()))
+}
+
+/// Copy a project viewable by the user, sharing its saved assets.
+#[utoipa::path(
+    post, path = "/projects/{id}/duplicate", tag = "projects",
+    params(("id" = Uuid, Path, description = "Project id")),
+    request_body = DuplicateProject,
+    responses((status = 201, body = Project, headers(("location" = String)))),
+    security(("bearer" = [])),
+)]
+#[axum_macros::debug_handler]
+pub async fn duplicate_project(
+    Path(id): Path<Uuid>,
+    Extension(projects): Extension<Projects>,
+    claims: Claims,
+    Json(request): Json<DuplicateProject>,
+) -> Result<(StatusCode, HeaderMap, Json<Project>)> {
+    if request.owner.email != claims.email.to_lowercase() {
+        return Err(Error::Api(
+            StatusCode::BAD_REQUEST,
+            "Project owner does not match token claims.",
+        ));
+    }
+
+    let project = projects
+        .duplicate(id, request.owner, request.title, request.include_members)
+        .await?;
+
+    created(project)
+}
+
+#[utoipa::path(
+    post, path = "/projects/from-template/{id}", tag = "templates",
+    params(("id" = Uuid, Path, description = "Template id")),
+    request_body = CreateProjectFromTemplate,
+    responses((status = 201, body = Project, headers(("location" = String)))),
+    security(("bearer" = [])),
+)]
+#[axum_macros::debug_handler]
+pub async fn create_project_from_template(
+    Path(id): Path<Uuid>,
+    Extension(projects): Extension<Projects>,
+    claims: Claims,
+    Json(request): Json<CreateProjectFromTemplate>,
+) -> Result<(StatusCode, HeaderMap, Json<Project>)> {
+    if request.owner.email.to_lowercase() != claims.email.to_lowercase() {
+        return Err(Error::Api(
+            StatusCode::BAD_REQUEST,
+            "Project owner does not match token claims.",
+        ));
+    }
+
+    let project = projects
+        .create_from_template(id, request.owner, request.title)
+        .await?;
+
+    created(project)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn member(email: &str, role: Role) -> ProjectMember {
+        ProjectMember {
+            member: Member {
+                email: email.into(),
+                name: "Name".into(),
+                surname: "Surname".into(),
+            },
+            role,
+        }
+    }
+
+    fn input(members: Vec<ProjectMember>, views: Vec<ProjectView>) -> ProjectInput {
+        ProjectInput {
+            title: "Project".into(),
+            description: None,
+            image: None,
+            color: "#ffffff".into(),
+            tags: Vec::new(),
+            members,
+            views,
+            assets: Vec::new(),
+            geometries: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn converts_members_with_roles() {
+        let id = Uuid::new_v4();
+        let project = input(
+            vec![
+                member("viewer@example.com", Role::Viewer),
+                member("owner@example.com", Role::Owner),
+                member("editor@example.com", Role::Editor),
+            ],
+            Vec::new(),
+        )
+        .into_project(id, &[])
+        .unwrap();
+        assert_eq!(project.owner.email, "owner@example.com");
+        assert_eq!(project.editors[0].email, "editor@example.com");
+        assert_eq!(project.viewers[0].email, "viewer@example.com");
+
+        let roles: Vec<Role> = Project::from(project)
+            .members
+            .iter()
+            .map(|m| m.role)
+            .collect();
+        assert_eq!(roles, [Role::Owner, Role::Editor, Role::Viewer]);
+
+        for members in [
+            vec![member("editor@example.com", Role::Editor)],
+            vec![
+                member("owner@example.com", Role::Owner),
+                member("other@example.com", Role::Owner),
+            ],
+            vec![
+                member("owner@example.com", Role::Owner),
+                member("owner@example.com", Role::Viewer),
+            ],
+        ] {
+            assert!(input(members, Vec::new()).into_project(id, &[]).is_err());
+        }
+    }
+
+    #[test]
+    fn keeps_unchanged_legacy_permalinks() {
+        let saved = View {
+            id: "legacy".into(),
+            title: "Legacy".into(),
+            permalink: "?lon=7.45".into(),
+        };
+        let view = |id: Option<&str>| ProjectView {
+            id: id.map(Into::into),
+            title: "View".into(),
+            permalink: Some(saved.permalink.clone()),
+            state: None,
+        };
+        let owner = || vec![member("owner@example.com", Role::Owner)];
+
+        let project = input(owner(), vec![view(Some("legacy"))])
+            .into_project(Uuid::new_v4(), std::slice::from_ref(&saved))
+            .unwrap();
+        assert_eq!(project.views[0].permalink, saved.permalink);
+
+        assert!(input(owner(), vec![view(None)])
+            .into_project(Uuid::new_v4(), &[saved])
+            .is_err());
+    }
+}
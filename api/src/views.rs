@@ -0,0 +1,292 @@
+use std::collections::BTreeMap;
+
+use axum::http::StatusCode;
+use serde::{Deserialize, Serialize};
+use url::form_urlencoded;
+use utoipa::ToSchema;
+
+use crate::{Error, Result};
+
+const LON_PARAM: &str = "lon";
+const LAT_PARAM: &str = "lat";
+const ELEVATION_PARAM: &str = "elevation";
+const HEADING_PARAM: &str = "heading";
+const PITCH_PARAM: &str = "pitch";
+const LAYERS_PARAM: &str = "layers";
+const LAYERS_VISIBILITY_PARAM: &str = "layers_visibility";
+const LAYERS_TRANSPARENCY_PARAM: &str = "layers_transparency";
+const LAYERS_TIMESTAMP_PARAM: &str = "layers_timestamp";
+const EXAGGERATION_PARAM: &str = "zExaggeration";
+
+/// State of the viewer captured by a view, as encoded in its permalink query string.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct ViewState {
+    pub camera: Option<Camera>,
+    #[serde(default)]
+    pub layers: Vec<ViewLayer>,
+    pub exaggeration: Option<f64>,
+    /// Other permalink parameters, kept as is
+    #[serde(default)]
+    pub params: BTreeMap<String, String>,
+}
+
+/// Camera position in WGS84 degrees and meters, orientation in degrees.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, ToSchema)]
+pub struct Camera {
+    pub lon: f64,
+    pub lat: f64,
+    pub elevation: f64,
+    pub heading: Option<f64>,
+    pub pitch: Option<f64>,
+}
+
+/// A layer displayed in a view.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, ToSchema)]
+pub struct ViewLayer {
+    pub layer: String,
+    pub opacity: f64,
+    pub visible: bool,
+    pub timestamp: Option<String>,
+}
+
+fn invalid(message: &'static str) -> Error {
+    Error::Api(StatusCode::BAD_REQUEST, message)
+}
+
+fn parse_number(value: &str) -> Result<f64> {
+    value
+        .trim()
+        .parse::<f64>()
+        .ok()
+        .filter(|v| v.is_finite())
+        .ok_or(invalid(
+            "Invalid permalink, expected numbers for the camera.",
+        ))
+}
+
+fn split(value: Option<String>) -> Vec<String> {
+    match value {
+        Some(value) if !value.is_empty() => value.split(',').map(str::to_owned).collect(),
+        _ => Vec::new(),
+    }
+}
+
+impl ViewState {
+    /// Parse the query string of a permalink, with or without its leading `?` or URL.
+    pub fn parse(permalink: &str) -> Result<Self> {
+        let query = permalink
+            .split_once('?')
+            .map_or(permalink, |(_, query)| query);
+        let mut params: BTreeMap<String, String> = form_urlencoded::parse(query.as_bytes())
+            .into_owned()
+            .collect();
+
+        let position = [LON_PARAM, LAT_PARAM, ELEVATION_PARAM].map(|name| params.remove(name));
+        let orientation = [HEADING_PARAM, PITCH_PARAM].map(|name| params.remove(name));
+        let camera = match position {
+            [Some(lon), Some(lat), Some(elevation)] => {
+                let (heading, pitch) = match orientation {
+                    [Some(heading), Some(pitch)] => {
+                        (Some(parse_number(&heading)?), Some(parse_number(&pitch)?))
+                    }
+                    _ => (None, None),
+                };
+                Some(Camera {
+                    lon: parse_number(&lon)?,
+                    lat: parse_number(&lat)?,
+                    elevation: parse_number(&elevation)?,
+                    heading,
+                    pitch,
+                })
+            }
+            [None, None, None] => None,
+            _ => {
+                return Err(invalid(
+                    "Invalid permalink, expected lon, lat and elevation together.",
+                ))
+            }
+        };
+
+        let names = split(params.remove(LAYERS_PARAM));
+        let visibility = split(params.remove(LAYERS_VISIBILITY_PARAM));
+        let transparency = split(params.remove(LAYERS_TRANSPARENCY_PARAM));
+        let timestamps = split(params.remove(LAYERS_TIMESTAMP_PARAM));
+        let layers = names
+            .into_iter()
+            .enumerate()
+            .map(|(i, layer)| {
+                let transparency = match transparency.get(i) {
+                    Some(t) => t.trim().parse::<f64>().map_err(|_| {
+                        invalid("Invalid permalink, expected numeric transparencies.")
+                    })?,
+                    None => 0.0,
+                };
+                Ok(ViewLayer {
+                    layer,
+                    opacity: 1.0 - transparency,
+                    visible: visibility.get(i).is_some_and(|v| v == "true"),
+                    timestamp: timestamps.get(i).filter(|t| !t.is_empty()).cloned(),
+                })
+            })
+            .collect::<Result<_>>()?;
+
+        let exaggeration = params
+            .remove(EXAGGERATION_PARAM)
+            .map(|e| parse_number(&e))
+            .transpose()?;
+
+        let state = Self {
+            camera,
+            layers,
+            exaggeration,
+            params,
+        };
+        state.validate()?;
+        Ok(state)
+    }
+
+    /// Permalink of a view given either as a permalink or as a structured state.
+    pub fn normalize(permalink: Option<&str>, state: Option<&Self>) -> Result<String> {
+        let state = match (permalink, state) {
+            (Some(permalink), None) => Self::parse(permalink)?,
+            (None, Some(state)) => {
+                state.validate()?;
+                state.clone()
+            }
+            _ => return Err(invalid("Expected either a permalink or a state.")),
+        };
+        Ok(state.to_permalink())
+    }
+
+    /// Check the ranges of the values, as accepted by the viewer.
+    pub fn validate(&self) -> Result<()> {
+        if let Some(camera) = &self.camera {
+            let finite = [camera.lon, camera.lat, camera.elevation]
+                .into_iter()
+                .chain(camera.heading)
+                .chain(camera.pitch)
+                .all(f64::is_finite);
+            if !finite
+                || !(-180.0..=180.0).contains(&camera.lon)
+                || !(-90.0..=90.0).contains(&camera.lat)
+                || camera.pitch.is_some_and(|p| !(-90.0..=90.0).contains(&p))
+                || camera.heading.is_some() != camera.pitch.is_some()
+            {
+                return Err(invalid("Invalid camera position."));
+            }
+        }
+        for layer in &self.layers {
+            if layer.layer.is_empty()
+                || layer.layer.contains(',')
+                || layer.timestamp.as_ref().is_some_and(|t| t.contains(','))
+                || !(0.0..=1.0).contains(&layer.opacity)
+            {
+                return Err(invalid("Invalid layer in view."));
+            }
+        }
+        if self
+            .exaggeration
+            .is_some_and(|e| !(1.0..=20.0).contains(&e))
+        {
+            return Err(invalid(
+                "Invalid exaggeration, expected a value between 1 and 20.",
+            ));
+        }
+        Ok(())
+    }
+
+    /// Render the permalink query string, including its leading `?`.
+    pub fn to_permalink(&self) -> String {
+        let mut query = form_urlencoded::Serializer::new(String::new());
+        for (name, value) in &self.params {
+            query.append_pair(name, value);
+        }
+        if let Some(camera) = &self.camera {
+            query.append_pair(LON_PARAM, &camera.lon.to_string());
+            query.append_pair(LAT_PARAM, &camera.lat.to_string());
+            query.append_pair(ELEVATION_PARAM, &camera.elevation.to_string());
+            if let (Some(heading), Some(pitch)) = (camera.heading, camera.pitch) {
+                query.append_pair(HEADING_PARAM, &heading.to_string());
+                query.append_pair(PITCH_PARAM, &pitch.to_string());
+            }
+        }
+        if !self.layers.is_empty() {
+            let join = |f: &dyn Fn(&ViewLayer) -> String| {
+                self.layers.iter().map(f).collect::<Vec<_>>().join(",")
+            };
+            query.append_pair(LAYERS_PARAM, &join(&|l| l.layer.clone()));
+            query.append_pair(LAYERS_VISIBILITY_PARAM, &join(&|l| l.visible.to_string()));
+            query.append_pair(
+                LAYERS_TRANSPARENCY_PARAM,
+                &join(&|l| format!("{:.2}", 1.0 - l.opacity)),
+            );
+            query.append_pair(
+                LAYERS_TIMESTAMP_PARAM,
+                &join(&|l| l.timestamp.clone().unwrap_or_default()),
+            );
+        }
+        if let Some(exaggeration) = self.exaggeration {
+            query.append_pair(EXAGGERATION_PARAM, &exaggeration.to_string());
+        }
+        format!("?{}", query.finish())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_viewer_permalink() {
+        let state = ViewState::parse(
+            "?lon=7.45&lat=46.95&elevation=12000&heading=10&pitch=-45\
+             &layers=ch.swisstopo.geologie,ch.swisstopo.boreholes\
+             &layers_visibility=true,false&layers_transparency=0.00,0.25\
+             &layers_timestamp=,2020&zExaggeration=2&projectId=abc",
+        )
+        .unwrap();
+
+        assert_eq!(
+            state.camera,
+            Some(Camera {
+                lon: 7.45,
+                lat: 46.95,
+                elevation: 12000.0,
+                heading: Some(10.0),
+                pitch: Some(-45.0),
+            })
+        );
+        assert_eq!(
+            state.layers[1],
+            ViewLayer {
+                layer: "ch.swisstopo.boreholes".into(),
+                opacity: 0.75,
+                visible: false,
+                timestamp: Some("2020".into()),
+            }
+        );
+        assert_eq!(state.exaggeration, Some(2.0));
+        assert_eq!(
+            state.params.get("projectId").map(String::as_str),
+            Some("abc")
+        );
+
+        assert_eq!(ViewState::parse(&state.to_permalink()).unwrap(), state);
+    }
+
+    #[test]
+    fn rejects_invalid_state() {
+        for permalink in [
+            "?lon=7.45&lat=46.95",
+            "?lon=east&lat=46.95&elevation=1",
+            "?lon=200&lat=46.95&elevation=1",
+            "?layers=a&layers_transparency=x",
+            "?layers=a&layers_transparency=2",
+            "?zExaggeration=50",
+        ] {
+            assert!(ViewState::parse(permalink).is_err(), "{permalink}");
+        }
+        assert_eq!(ViewState::parse("").unwrap(), ViewState::default());
+    }
+}
@@ -1,6 +1,5 @@
 use axum::body::Body;
 use axum::Router;
-use clap::Parser;
 use hyper::{Request, StatusCode};
 use tower::ServiceExt;
 use uuid::Uuid; // for `app.oneshot()`
@@ -8,7 +7,7 @@ use uuid::Uuid; // for `app.oneshot()`
 async fn spawn_app() -> Router {
     dotenv::dotenv().ok();
 
-    let config = api::Config::parse();
+    let config = api::Config::load_from(["api"]);
 
     // Create & setup a new database
     let pool = config
@@ -16,7 +15,7 @@ async fn spawn_app() -> Router {
         .setup_with(&Uuid::new_v4().to_string(), true)
         .await;
 
-    api::app(pool).await
+    api::app(&config, pool, &api::Workers::default()).await
 }
 
 #[tokio::test]
@@ -40,3 +39,179 @@ async fn health_check_works() {
     // Assert
     assert_eq!(response.status(), StatusCode::OK);
 }
+
+#[tokio::test]
+async fn openapi_is_served() {
+    let app = spawn_app().await;
+
+    let response = app
+        .oneshot(
+            Request::builder()
+                .uri("/api/openapi.json")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn v1_is_deprecated() {
+    let app = spawn_app().await;
+
+    for (uri, deprecated) in [
+        ("/api/links/unknown", true),
+        ("/api/v2/links/unknown", false),
+        ("/api/health_check", false),
+    ] {
+        let response = app
+            .clone()
+            .oneshot(Request::builder().uri(uri).body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+
+        assert_eq!(
+            response.headers().contains_key("deprecation"),
+            deprecated,
+            "{uri}"
+        );
+    }
+}
+
+#[tokio::test]
+async fn metrics_are_served() {
+    let app = spawn_app().await;
+
+    let response = app
+        .clone()
+        .oneshot(
+            Request::builder()
+                .uri("/api/v2/me/usage")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+    let response = app
+        .oneshot(
+            Request::builder()
+                .uri("/metrics")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    let metrics = String::from_utf8(body.to_vec()).unwrap();
+    assert!(metrics
+        .contains(r#"http_requests_total{route="/api/v2/me/usage",method="GET",status="401"}"#));
+    assert!(metrics.contains(r#"jwt_validation_failures_total{reason="missing_token"}"#));
+    assert!(metrics.contains("db_pool_max_connections 50"));
+    assert!(metrics.contains("projects 0"));
+}
+
+#[tokio::test]
+async fn request_id_is_propagated() {
+    let app = spawn_app().await;
+
+    let response = app
+        .clone()
+        .oneshot(
+            Request::builder()
+                .uri("/api/v2/me/usage")
+                .header("x-request-id", "test-request")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    assert_eq!(response.headers()["x-request-id"], "test-request");
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(body["request_id"], "test-request");
+
+    // Generated when missing
+    let response = app
+        .clone()
+        .oneshot(
+            Request::builder()
+                .uri("/api/health_check")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    let request_id = response.headers()["x-request-id"].to_str().unwrap();
+    assert!(Uuid::parse_str(request_id).is_ok());
+
+    // Replaced when invalid
+    let response = app
+        .clone()
+        .oneshot(
+            Request::builder()
+                .uri("/api/health_check")
+                .header("x-request-id", "<script>alert(1)</script>")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    let request_id = response.headers()["x-request-id"].to_str().unwrap();
+    assert!(Uuid::parse_str(request_id).is_ok());
+}
+
+#[tokio::test]
+async fn health_probes_report_dependencies() {
+    let app = spawn_app().await;
+
+    let response = app
+        .clone()
+        .oneshot(
+            Request::builder()
+                .uri("/api/health/live")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    let live: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(live["status"], "up");
+    assert!(live["commit"].is_string());
+
+    let response = app
+        .oneshot(
+            Request::builder()
+                .uri("/api/health/ready")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    // The keys validating the tokens are not loaded in the tests
+    assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    let ready: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(ready["status"], "down");
+    assert_eq!(ready["checks"]["postgres"]["status"], "up");
+    assert!(ready["checks"]["postgres"]["latency_ms"].is_u64());
+    assert_eq!(ready["checks"]["jwks"]["status"], "down");
+    assert!(ready["checks"]["s3"]["status"].is_string());
+    assert!(ready["checks"]["jwks"].get("message").is_none());
+}
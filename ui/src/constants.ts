@@ -131,12 +131,12 @@ export const SWISSFORAGES_EDITOR_URL = `${SWISSFORAGES_VIEWER_URL}editor/`;
 export const SWISSFORAGES_API_URL = `${SWISSFORAGES_VIEWER_URL}api/v1`;
 
 export const SHORTLINK_URL_BY_PAGE_HOST = {
-  'localhost:8000': '/abbr',
-  'review-viewer.swissgeol.ch': 'https://link.dev-viewer.swissgeol.ch',
-  'dev-viewer.swissgeol.ch': 'https://link.dev-viewer.swissgeol.ch',
-  'int-viewer.swissgeol.ch': 'https://link.int-viewer.swissgeol.ch',
-  'swissgeol.ch': 'https://link.swissgeol.ch',
-  'viewer.swissgeol.ch': 'https://link.swissgeol.ch',
+  'localhost:8000': '/api/links',
+  'review-viewer.swissgeol.ch': 'https://api.dev-viewer.swissgeol.ch/api/links',
+  'dev-viewer.swissgeol.ch': 'https://api.dev-viewer.swissgeol.ch/api/links',
+  'int-viewer.swissgeol.ch': 'https://api.int-viewer.swissgeol.ch/api/links',
+  'swissgeol.ch': 'https://api.swissgeol.ch/api/links',
+  'viewer.swissgeol.ch': 'https://api.swissgeol.ch/api/links',
 };
 
 export const API_BY_PAGE_HOST = {
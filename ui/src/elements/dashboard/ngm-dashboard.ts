@@ -52,7 +52,8 @@ export interface View {
 export interface Asset {
   name: string,
   key: string,
-  clampToGround?: boolean
+  clampToGround?: boolean,
+  scanStatus?: 'clean' | 'infected' | 'notScanned'
 }
 
 export interface Member {
@@ -90,6 +90,11 @@ export default defineConfig({
         changeOrigin: true,
         secure: false,
       },
+      '/l/': {
+        target: 'http://api:3000',
+        changeOrigin: true,
+        secure: false,
+      },
       '/abbr': {
         target: 'http://abbreviator:8080',
         rewrite: (path) => path.replace(/^\/abbr/, ''),